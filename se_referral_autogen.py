@@ -1,15 +1,23 @@
 #!/usr/bin/env python
 # -*- encoding: utf-8
 """
-This script auto-generates the ``fix_se_referral`` lines used in ``urls.rs``.
+This script auto-generates the ``stack_exchange`` referrals config for
+a safari.rs rules file.
 
 It takes somebody's Stack Exchange account URL, of the form
 
     https://stackexchange.com/users/:user_id/:user_name
 
-and spits out a list of ``fix_se_referral`` calls.
+and spits out a rules file that maps each site's hostname to your user ID
+on that site, e.g.
+
+    python se_referral_autogen.py https://stackexchange.com/users/1234/me \
+        > ~/.config/safari.rs/rules.json
+
+If you already have a rules file, copy the ``stack_exchange`` key into it.
 """
 
+import json
 import sys
 from urllib.parse import urlparse
 
@@ -28,5 +36,4 @@ for account in soup.find_all('div', attrs={'class': 'account-container'}):
     user_id = components.path.split('/')[2]
     accounts.append((components.netloc, user_id))
 
-for netloc, user_id in sorted(accounts):
-    print(f'fix_se_referral(&mut parsed_url, "{netloc}", "{user_id}");')
+print(json.dumps({"stack_exchange": {"referrals": dict(sorted(accounts))}}, indent=2))
@@ -1,29 +1,165 @@
+//! Run AppleScript and JavaScript for Automation (JXA) scripts with
+//! `osascript`, through a trait that can be swapped out in tests.
+
+#[cfg(test)]
+use std::cell::RefCell;
+#[cfg(test)]
+use std::collections::VecDeque;
+use std::os::unix::process::ExitStatusExt;
 use std::process::{Command, ExitStatus};
 
 /// The output of a finished process.
 ///
 /// This varies from Output in std::process in that stdout/stderr are
-/// both strings rather than Vec<u8>.
+/// both strings rather than `Vec<u8>`.
+#[derive(Clone, Debug)]
 pub struct Output {
     pub status: ExitStatus,
     pub stdout: String,
     pub stderr: String,
 }
 
+/// Something that can run an AppleScript.
+///
+/// The Safari functions take one of these rather than calling `osascript`
+/// directly, so they can be tested on machines without Safari.
+pub trait ScriptRunner {
+    /// Run an AppleScript.
+    fn run(&self, script: &str) -> Output;
+
+    /// Run a JavaScript for Automation (JXA) script.
+    fn run_javascript(&self, script: &str) -> Output;
+}
+
+/// Runs AppleScript with `osascript`.
+pub struct Osascript;
+
+impl ScriptRunner for Osascript {
+    fn run(&self, script: &str) -> Output {
+        run(script)
+    }
+
+    fn run_javascript(&self, script: &str) -> Output {
+        run_javascript(script)
+    }
+}
+
 /// Run an AppleScript.
 ///
+/// If we can't start `osascript` at all, we return a failed Output with
+/// exit status 127 (the shell's "command not found"), rather than panicking.
+///
 /// * `script`: The AppleScript code to run.
 ///
 pub fn run(script: &str) -> Output {
-    let cmd_result = Command::new("osascript")
-        .arg("-e")
-        .arg(script)
-        .output()
-        .expect("failed to execute AppleScript");
-
-    Output {
-        status: cmd_result.status,
-        stdout: String::from_utf8(cmd_result.stdout).unwrap(),
-        stderr: String::from_utf8(cmd_result.stderr).unwrap(),
+    osascript(&["-e", script])
+}
+
+/// Run a JavaScript for Automation (JXA) script.
+///
+/// * `script`: The JavaScript code to run.
+///
+pub fn run_javascript(script: &str) -> Output {
+    osascript(&["-l", "JavaScript", "-e", script])
+}
+
+fn osascript(args: &[&str]) -> Output {
+    match Command::new("osascript").args(args).output() {
+        Ok(cmd_result) => Output {
+            status: cmd_result.status,
+            stdout: String::from_utf8_lossy(&cmd_result.stdout).into_owned(),
+            stderr: String::from_utf8_lossy(&cmd_result.stderr).into_owned(),
+        },
+        Err(e) => Output {
+            status: ExitStatus::from_raw(127 << 8),
+            stdout: String::new(),
+            stderr: format!("failed to execute AppleScript: {}", e),
+        },
+    }
+}
+
+/// A ScriptRunner for tests.  It records every script it's asked to run,
+/// and returns canned outputs in the order they were given.
+#[cfg(test)]
+pub struct FakeRunner {
+    outputs: RefCell<VecDeque<Output>>,
+    scripts: RefCell<Vec<String>>,
+}
+
+#[cfg(test)]
+impl FakeRunner {
+    pub fn new(outputs: Vec<Output>) -> FakeRunner {
+        FakeRunner {
+            outputs: RefCell::new(outputs.into_iter().collect()),
+            scripts: RefCell::new(vec![]),
+        }
+    }
+
+    /// Every script we've been asked to run, in order.
+    pub fn scripts(&self) -> Vec<String> {
+        self.scripts.borrow().clone()
+    }
+
+    /// A successful run that prints `stdout`, like osascript (which adds
+    /// a trailing newline).
+    pub fn ok(stdout: &str) -> Output {
+        Output {
+            status: ExitStatus::from_raw(0),
+            stdout: format!("{}\n", stdout),
+            stderr: String::new(),
+        }
+    }
+
+    /// A failed run that prints `stderr`.
+    pub fn err(stderr: &str) -> Output {
+        Output {
+            status: ExitStatus::from_raw(1 << 8),
+            stdout: String::new(),
+            stderr: format!("{}\n", stderr),
+        }
+    }
+}
+
+#[cfg(test)]
+impl ScriptRunner for FakeRunner {
+    fn run(&self, script: &str) -> Output {
+        self.scripts.borrow_mut().push(script.to_owned());
+        match self.outputs.borrow_mut().pop_front() {
+            Some(output) => output,
+            None => panic!("FakeRunner has no output left for script: {}", script),
+        }
+    }
+
+    fn run_javascript(&self, script: &str) -> Output {
+        self.run(script)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use applescript::{run, FakeRunner, ScriptRunner};
+
+    #[test]
+    fn test_fake_runner_records_scripts() {
+        let runner = FakeRunner::new(vec![FakeRunner::ok("1"), FakeRunner::err("oops")]);
+
+        let first = runner.run("first script");
+        assert!(first.status.success());
+        assert_eq!(first.stdout, "1\n");
+
+        let second = runner.run("second script");
+        assert!(!second.status.success());
+        assert_eq!(second.stderr, "oops\n");
+
+        assert_eq!(runner.scripts(), vec!["first script", "second script"]);
+    }
+
+    #[test]
+    fn test_run_never_panics() {
+        // On machines without osascript, this is a failed Output rather
+        // than a panic; on a Mac, it's a syntax error.
+        let output = run("this is not valid AppleScript (");
+        assert!(!output.status.success());
+        assert!(!output.stderr.is_empty());
     }
 }
@@ -0,0 +1,518 @@
+//! Read Safari's bookmarks from Bookmarks.plist, and export them.
+//!
+//! Bookmarks.plist is a tree of folders and bookmarks.  The top level has
+//! a folder for the Favourites bar (`BookmarksBar`), one for the Bookmarks
+//! menu (`BookmarksMenu`), any other folders you've created, and Reading
+//! List (`com.apple.ReadingList`), which we leave out -- see
+//! `safari::get_reading_list` instead.
+//!
+//! Each entry in the tree looks something like this:
+//!
+//! ```text
+//! <dict>
+//!   <key>WebBookmarkType</key><string>WebBookmarkTypeList</string>
+//!   <key>WebBookmarkUUID</key><string>[[ UUID ]]</string>
+//!   <key>Title</key><string>[[ Folder title ]]</string>
+//!   <key>Children</key>
+//!   <array>
+//!     <dict>
+//!       <key>WebBookmarkType</key><string>WebBookmarkTypeLeaf</string>
+//!       <key>WebBookmarkUUID</key><string>[[ UUID ]]</string>
+//!       <key>URLString</key><string>[[ URL ]]</string>
+//!       <key>URIDictionary</key>
+//!       <dict>
+//!         <key>title</key><string>[[ Bookmark title ]]</string>
+//!       </dict>
+//!     </dict>
+//!     ... other bookmarks and folders ...
+//!   </array>
+//! </dict>
+//! ```
+
+use std::path::Path;
+
+use serde_json;
+
+use error::Error;
+use output::{html_escape, markdown_link, Item};
+use paths::SafariPaths;
+use plists::{self, Node};
+
+/// A folder of bookmarks.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Folder {
+    pub title: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uuid: Option<String>,
+    pub children: Vec<Entry>,
+}
+
+/// A single bookmark.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Bookmark {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    pub url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uuid: Option<String>,
+}
+
+/// Something in a bookmarks folder.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Entry {
+    Folder(Folder),
+    Bookmark(Bookmark),
+}
+
+/// The title of the Reading List folder, which isn't really bookmarks.
+const READING_LIST: &str = "com.apple.ReadingList";
+
+/// Read every bookmark from Bookmarks.plist.
+pub fn get_bookmarks(paths: &SafariPaths) -> Result<Folder, Error> {
+    read_bookmarks(&paths.bookmarks)
+}
+
+/// Read every bookmark from a Bookmarks.plist file.  The root folder has
+/// an empty title.
+pub fn read_bookmarks(path: &Path) -> Result<Folder, Error> {
+    let plist = plists::read(path)?;
+    parse_folder(&Node::root(&plist, path))
+}
+
+fn parse_folder(node: &Node) -> Result<Folder, Error> {
+    // An empty folder doesn't have a Children key.
+    let children = match node.get("Children")? {
+        Some(children) => children.items()?,
+        None => vec![],
+    };
+
+    let mut entries = vec![];
+    for child in children {
+        match parse_entry(&child)? {
+            Some(Entry::Folder(ref f)) if f.title == READING_LIST => (),
+            Some(entry) => entries.push(entry),
+            None => (),
+        }
+    }
+
+    Ok(Folder {
+        title: node.optional_string("Title").unwrap_or_default(),
+        uuid: node.optional_string("WebBookmarkUUID"),
+        children: entries,
+    })
+}
+
+/// Parse a folder or bookmark.  Returns None for entries that aren't
+/// either, e.g. the proxy entry for History.
+fn parse_entry(node: &Node) -> Result<Option<Entry>, Error> {
+    node.as_dictionary()?;
+    let entry = match node.optional_string("WebBookmarkType").as_deref() {
+        Some("WebBookmarkTypeList") => Some(Entry::Folder(parse_folder(node)?)),
+        Some("WebBookmarkTypeLeaf") => Some(Entry::Bookmark(parse_bookmark(node)?)),
+        Some(_) => None,
+
+        // If there isn't a type, guess from the keys.
+        None => {
+            if node.get("URLString")?.is_some() {
+                Some(Entry::Bookmark(parse_bookmark(node)?))
+            } else if node.get("Children")?.is_some() {
+                Some(Entry::Folder(parse_folder(node)?))
+            } else {
+                None
+            }
+        }
+    };
+    Ok(entry)
+}
+
+fn parse_bookmark(node: &Node) -> Result<Bookmark, Error> {
+    Ok(Bookmark {
+        title: node
+            .get("URIDictionary")?
+            .and_then(|d| d.optional_string("title")),
+        url: node.key("URLString")?.as_str()?.to_owned(),
+        uuid: node.optional_string("WebBookmarkUUID"),
+    })
+}
+
+impl Folder {
+    /// Find a folder inside this one by its path, e.g. `BookmarksBar/Rust`.
+    pub fn find(&self, path: &str) -> Option<&Folder> {
+        let mut folder = self;
+        for title in path.split('/').filter(|t| !t.is_empty()) {
+            folder = folder.children.iter().find_map(|entry| match *entry {
+                Entry::Folder(ref f) if f.title == title => Some(f),
+                _ => None,
+            })?;
+        }
+        Some(folder)
+    }
+
+    /// Every bookmark in this folder and its subfolders, with the path of
+    /// the folder it's in (relative to this one).
+    pub fn bookmarks(&self) -> Vec<(String, &Bookmark)> {
+        let mut result = vec![];
+        self.collect_bookmarks("", &mut result);
+        result
+    }
+
+    fn collect_bookmarks<'a>(&'a self, path: &str, result: &mut Vec<(String, &'a Bookmark)>) {
+        for entry in &self.children {
+            match *entry {
+                Entry::Bookmark(ref b) => result.push((path.to_owned(), b)),
+                Entry::Folder(ref f) => {
+                    let child_path = if path.is_empty() {
+                        f.title.clone()
+                    } else {
+                        format!("{}/{}", path, f.title)
+                    };
+                    f.collect_bookmarks(&child_path, result);
+                }
+            }
+        }
+    }
+
+    /// Every bookmark in this folder and its subfolders, as items for the
+    /// `output` module.
+    pub fn items(&self) -> Vec<Item> {
+        self.bookmarks()
+            .into_iter()
+            .map(|(folder, b)| Item {
+                url: b.url.clone(),
+                title: b.title.clone(),
+                folder: if folder.is_empty() {
+                    None
+                } else {
+                    Some(folder)
+                },
+                ..Item::default()
+            })
+            .collect()
+    }
+}
+
+impl Bookmark {
+    fn item(&self) -> Item {
+        Item {
+            url: self.url.clone(),
+            title: self.title.clone(),
+            ..Item::default()
+        }
+    }
+}
+
+/// Print a folder as an indented tree, with a trailing `/` on folder names.
+pub fn to_tree(folder: &Folder) -> String {
+    let mut out = String::new();
+    write_tree(&folder.children, 0, &mut out);
+    out
+}
+
+fn write_tree(entries: &[Entry], depth: usize, out: &mut String) {
+    let indent = "  ".repeat(depth);
+    for entry in entries {
+        match *entry {
+            Entry::Folder(ref f) => {
+                out.push_str(&format!("{}{}/\n", indent, f.title));
+                write_tree(&f.children, depth + 1, out);
+            }
+            Entry::Bookmark(ref b) => match b.title {
+                Some(ref title) => out.push_str(&format!("{}{} <{}>\n", indent, title, b.url)),
+                None => out.push_str(&format!("{}<{}>\n", indent, b.url)),
+            },
+        }
+    }
+}
+
+/// Export a folder as JSON, keeping the folder structure.
+pub fn to_json(folder: &Folder) -> String {
+    format!("{}\n", serde_json::to_string_pretty(folder).unwrap())
+}
+
+/// Export a folder as a nested Markdown list.
+pub fn to_markdown(folder: &Folder) -> String {
+    let mut out = String::new();
+    write_markdown(&folder.children, 0, &mut out);
+    out
+}
+
+fn write_markdown(entries: &[Entry], depth: usize, out: &mut String) {
+    let indent = "  ".repeat(depth);
+    for entry in entries {
+        match *entry {
+            Entry::Folder(ref f) => {
+                out.push_str(&format!("{}- {}\n", indent, f.title));
+                write_markdown(&f.children, depth + 1, out);
+            }
+            Entry::Bookmark(ref b) => {
+                out.push_str(&indent);
+                out.push_str(&markdown_link(&b.item()));
+            }
+        }
+    }
+}
+
+/// Export a folder in the Netscape bookmark file format, which most
+/// browsers (including Safari) can import.
+pub fn to_netscape_html(folder: &Folder) -> String {
+    let mut out = String::from(concat!(
+        "<!DOCTYPE NETSCAPE-Bookmark-file-1>\n",
+        "<META HTTP-EQUIV=\"Content-Type\" CONTENT=\"text/html; charset=UTF-8\">\n",
+        "<TITLE>Bookmarks</TITLE>\n",
+        "<H1>Bookmarks</H1>\n",
+    ));
+    write_netscape(&folder.children, 0, &mut out);
+    out
+}
+
+fn write_netscape(entries: &[Entry], depth: usize, out: &mut String) {
+    let indent = "    ".repeat(depth);
+    out.push_str(&format!("{}<DL><p>\n", indent));
+    for entry in entries {
+        match *entry {
+            Entry::Folder(ref f) => {
+                out.push_str(&format!(
+                    "{}    <DT><H3>{}</H3>\n",
+                    indent,
+                    html_escape(&f.title)
+                ));
+                write_netscape(&f.children, depth + 1, out);
+            }
+            Entry::Bookmark(ref b) => out.push_str(&format!(
+                "{}    <DT><A HREF=\"{}\">{}</A>\n",
+                indent,
+                html_escape(&b.url),
+                html_escape(b.title.as_ref().unwrap_or(&b.url))
+            )),
+        }
+    }
+    out.push_str(&format!("{}</DL><p>\n", indent));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use bookmarks::{
+        read_bookmarks, to_json, to_markdown, to_netscape_html, to_tree, Bookmark, Entry, Folder,
+    };
+    use error::Error;
+
+    fn fixture(name: &str) -> PathBuf {
+        PathBuf::from(format!(
+            "{}/tests/fixtures/plist/{}",
+            env!("CARGO_MANIFEST_DIR"),
+            name
+        ))
+    }
+
+    fn bookmarks() -> Folder {
+        read_bookmarks(&fixture("bookmarks.plist")).unwrap()
+    }
+
+    #[test]
+    fn test_reads_the_whole_tree() {
+        let root = bookmarks();
+        let titles: Vec<&str> = root
+            .children
+            .iter()
+            .map(|e| match *e {
+                Entry::Folder(ref f) => f.title.as_str(),
+                Entry::Bookmark(_) => panic!("Unexpected bookmark at the top level"),
+            })
+            .collect();
+
+        // History is a proxy, and Reading List isn't bookmarks.
+        assert_eq!(titles, vec!["BookmarksBar", "BookmarksMenu"]);
+
+        let rust = root.find("BookmarksBar/Rust").unwrap();
+        assert_eq!(
+            rust.uuid,
+            Some("A1B2C3D4-0000-0000-0000-000000000004".to_owned())
+        );
+        assert_eq!(
+            rust.children[1],
+            Entry::Bookmark(Bookmark {
+                title: None,
+                url: "https://docs.rs/plist".to_owned(),
+                uuid: Some("A1B2C3D4-0000-0000-0000-000000000006".to_owned()),
+            })
+        );
+        assert_eq!(
+            rust.children[2],
+            Entry::Folder(Folder {
+                title: "Empty".to_owned(),
+                uuid: Some("A1B2C3D4-0000-0000-0000-000000000007".to_owned()),
+                children: vec![],
+            })
+        );
+    }
+
+    #[test]
+    fn test_find_folder() {
+        let root = bookmarks();
+        assert_eq!(root.find("").unwrap().title, "");
+        assert_eq!(root.find("/BookmarksMenu/").unwrap().title, "BookmarksMenu");
+        assert!(root.find("BookmarksBar/Python").is_none());
+        assert!(root.find("BookmarksBar/GitHub").is_none());
+    }
+
+    #[test]
+    fn test_bookmarks_have_folder_paths() {
+        let root = bookmarks();
+        let paths: Vec<(String, &str)> = root
+            .bookmarks()
+            .into_iter()
+            .map(|(path, b)| (path, b.url.as_str()))
+            .collect();
+        assert_eq!(
+            paths,
+            vec![
+                ("BookmarksBar".to_owned(), "https://github.com/"),
+                (
+                    "BookmarksBar/Rust".to_owned(),
+                    "https://doc.rust-lang.org/book/"
+                ),
+                ("BookmarksBar/Rust".to_owned(), "https://docs.rs/plist"),
+                ("BookmarksMenu".to_owned(), "https://example.org/?q=a&b=<c>"),
+            ]
+        );
+
+        let items = root.find("BookmarksBar").unwrap().items();
+        assert_eq!(items[0].folder, None);
+        assert_eq!(items[1].folder, Some("Rust".to_owned()));
+    }
+
+    #[test]
+    fn test_tree() {
+        assert_eq!(
+            to_tree(bookmarks().find("BookmarksBar").unwrap()),
+            concat!(
+                "GitHub <https://github.com/>\n",
+                "Rust/\n",
+                "  The Rust Programming Language <https://doc.rust-lang.org/book/>\n",
+                "  <https://docs.rs/plist>\n",
+                "  Empty/\n",
+            )
+        );
+    }
+
+    #[test]
+    fn test_json() {
+        let json = to_json(bookmarks().find("BookmarksMenu").unwrap());
+        assert_eq!(
+            json,
+            concat!(
+                "{\n",
+                "  \"title\": \"BookmarksMenu\",\n",
+                "  \"uuid\": \"A1B2C3D4-0000-0000-0000-000000000008\",\n",
+                "  \"children\": [\n",
+                "    {\n",
+                "      \"type\": \"bookmark\",\n",
+                "      \"title\": \"Example \\\"Domain\\\" & friends\",\n",
+                "      \"url\": \"https://example.org/?q=a&b=<c>\",\n",
+                "      \"uuid\": \"A1B2C3D4-0000-0000-0000-000000000009\"\n",
+                "    }\n",
+                "  ]\n",
+                "}\n",
+            )
+        );
+    }
+
+    #[test]
+    fn test_markdown() {
+        assert_eq!(
+            to_markdown(&bookmarks()),
+            concat!(
+                "- BookmarksBar\n",
+                "  - [GitHub](https://github.com/)\n",
+                "  - Rust\n",
+                "    - [The Rust Programming Language](https://doc.rust-lang.org/book/)\n",
+                "    - <https://docs.rs/plist>\n",
+                "    - Empty\n",
+                "- BookmarksMenu\n",
+                "  - [Example \"Domain\" & friends](https://example.org/?q=a&b=<c>)\n",
+            )
+        );
+    }
+
+    #[test]
+    fn test_netscape_html() {
+        let html = to_netscape_html(bookmarks().find("BookmarksBar").unwrap());
+        assert_eq!(
+            html,
+            concat!(
+                "<!DOCTYPE NETSCAPE-Bookmark-file-1>\n",
+                "<META HTTP-EQUIV=\"Content-Type\" CONTENT=\"text/html; charset=UTF-8\">\n",
+                "<TITLE>Bookmarks</TITLE>\n",
+                "<H1>Bookmarks</H1>\n",
+                "<DL><p>\n",
+                "    <DT><A HREF=\"https://github.com/\">GitHub</A>\n",
+                "    <DT><H3>Rust</H3>\n",
+                "    <DL><p>\n",
+                "        <DT><A HREF=\"https://doc.rust-lang.org/book/\">",
+                "The Rust Programming Language</A>\n",
+                "        <DT><A HREF=\"https://docs.rs/plist\">https://docs.rs/plist</A>\n",
+                "        <DT><H3>Empty</H3>\n",
+                "        <DL><p>\n",
+                "        </DL><p>\n",
+                "    </DL><p>\n",
+                "</DL><p>\n",
+            )
+        );
+    }
+
+    #[test]
+    fn test_netscape_html_is_escaped() {
+        let html = to_netscape_html(bookmarks().find("BookmarksMenu").unwrap());
+        assert!(html.contains(concat!(
+            "<DT><A HREF=\"https://example.org/?q=a&amp;b=&lt;c&gt;\">",
+            "Example &quot;Domain&quot; &amp; friends</A>",
+        )));
+    }
+
+    #[test]
+    fn test_missing_file_is_error() {
+        match read_bookmarks(&fixture("does-not-exist.plist")) {
+            Err(Error::PlistMissing { .. }) => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_binary_plist_matches_xml() {
+        assert_eq!(
+            read_bookmarks(&fixture("bookmarks.bplist")),
+            Ok(bookmarks())
+        );
+    }
+
+    #[test]
+    fn test_root_without_children_is_empty() {
+        let root = read_bookmarks(&fixture("missing-children.plist")).unwrap();
+        assert_eq!(root.children, vec![]);
+    }
+
+    #[test]
+    fn test_malformed_bookmarks_are_errors() {
+        let cases = vec![
+            ("bookmarks-children-not-array.plist", "Children[1].Children"),
+            (
+                "bookmarks-non-string-url.bplist",
+                "Children[1].Children[0].URLString",
+            ),
+            ("array-root.bplist", "(root)"),
+            ("not-a-plist.plist", "(root)"),
+            ("truncated.bplist", "(root)"),
+        ];
+        for (name, expected_key_path) in cases {
+            match read_bookmarks(&fixture(name)) {
+                Err(Error::PlistMalformed { key_path, .. }) => {
+                    assert_eq!(key_path, expected_key_path, "{}", name)
+                }
+                other => panic!("Unexpected result for {}: {:?}", name, other),
+            }
+        }
+    }
+}
@@ -0,0 +1,399 @@
+//! An on-disk cache of URLs we've already resolved, so resolving the same
+//! short link twice doesn't need the network.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use reqwest::Client;
+use serde_json;
+
+use error::Error;
+use files::write_atomically;
+use urls::{self, Hop, Resolution, ResolveError, ResolveOptions};
+
+/// Where we keep the cache of resolved URLs, if the platform has a cache
+/// directory, e.g. `~/Library/Caches/safari.rs/resolve.json` on macOS.
+pub fn default_cache_path() -> Option<PathBuf> {
+    dirs::cache_dir().map(|mut path| {
+        path.push("safari.rs");
+        path.push("resolve.json");
+        path
+    })
+}
+
+/// A resolved URL, as we remember it.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+struct CacheEntry {
+    /// The final URL, before any tidying.
+    url: String,
+    chain: Vec<Hop>,
+    /// When we resolved this URL, in seconds since the Unix epoch.
+    resolved_at: u64,
+    /// Whether we followed HTML redirects, which can change where we end up.
+    #[serde(default)]
+    follow_meta_refresh: bool,
+    #[serde(default)]
+    prefer_canonical: bool,
+}
+
+impl CacheEntry {
+    /// Would we have got this result with these options?  Every hop except
+    /// the last is a redirect, so a long chain is only usable if we're
+    /// allowed to follow that many redirects.
+    fn matches(&self, options: &ResolveOptions) -> bool {
+        self.follow_meta_refresh == options.follow_meta_refresh
+            && self.prefer_canonical == options.prefer_canonical
+            && self.chain.len() <= options.max_redirects + 1
+    }
+}
+
+/// A persistent cache of resolved URLs, keyed by the URL we were given.
+///
+/// Short links almost never change where they point, so we can skip the
+/// network for any URL we've resolved recently.  We only cache successful
+/// resolutions, and entries older than the TTL are ignored and dropped
+/// when we save.
+#[derive(Debug)]
+pub struct ResolveCache {
+    path: PathBuf,
+    ttl: Duration,
+    entries: BTreeMap<String, CacheEntry>,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+impl ResolveCache {
+    /// Open the cache at `path`.  A missing or unreadable cache file is
+    /// treated as an empty cache, because we can always rebuild it.
+    pub fn open(path: PathBuf, ttl: Duration) -> ResolveCache {
+        let mut json = String::new();
+        let entries = match File::open(&path).and_then(|mut f| f.read_to_string(&mut json)) {
+            Ok(_) => serde_json::from_str(&json).unwrap_or_default(),
+            Err(_) => BTreeMap::new(),
+        };
+        ResolveCache { path, ttl, entries }
+    }
+
+    /// Look up a URL, if we resolved it within the TTL with the same options.
+    pub fn get(&self, url: &str, options: &ResolveOptions) -> Option<Resolution> {
+        self.get_at(url, options, now())
+    }
+
+    fn get_at(&self, url: &str, options: &ResolveOptions, now: u64) -> Option<Resolution> {
+        let entry = self.entries.get(url)?;
+        if !entry.matches(options) || self.is_expired(entry, now) {
+            return None;
+        }
+
+        // We store the URL before tidying, so changes to the rules apply to
+        // cached URLs as well.
+        let url = match options.tidy {
+            Some(ref rules) => rules.tidy(&entry.url),
+            None => entry.url.clone(),
+        };
+        Some(Resolution {
+            url,
+            chain: entry.chain.clone(),
+        })
+    }
+
+    /// Remember how we resolved a URL.
+    pub fn insert(&mut self, url: &str, resolution: &Resolution, options: &ResolveOptions) {
+        self.insert_at(url, resolution, options, now())
+    }
+
+    fn insert_at(
+        &mut self,
+        url: &str,
+        resolution: &Resolution,
+        options: &ResolveOptions,
+        now: u64,
+    ) {
+        let final_url = match resolution.chain.last() {
+            Some(hop) => hop.url.clone(),
+            None => resolution.url.clone(),
+        };
+        self.entries.insert(
+            url.to_owned(),
+            CacheEntry {
+                url: final_url,
+                chain: resolution.chain.clone(),
+                resolved_at: now,
+                follow_meta_refresh: options.follow_meta_refresh,
+                prefer_canonical: options.prefer_canonical,
+            },
+        );
+    }
+
+    fn is_expired(&self, entry: &CacheEntry, now: u64) -> bool {
+        now.saturating_sub(entry.resolved_at) > self.ttl.as_secs()
+    }
+
+    /// Write the cache back to disk, dropping any expired entries.
+    pub fn save(&mut self) -> Result<(), Error> {
+        let now = now();
+        let ttl = self.ttl.as_secs();
+        self.entries
+            .retain(|_, entry| now.saturating_sub(entry.resolved_at) <= ttl);
+
+        let json = match serde_json::to_string(&self.entries) {
+            Ok(json) => json,
+            Err(e) => {
+                return Err(Error::Io {
+                    path: self.path.clone(),
+                    reason: format!("Unable to serialise the resolve cache: {}", e),
+                })
+            }
+        };
+
+        write_atomically(&self.path, json.as_bytes())
+    }
+
+    /// Resolve several URLs like `urls::resolve_all`, but only go to the
+    /// network for URLs that aren't already in the cache.
+    pub fn resolve_all(
+        &mut self,
+        client: &Client,
+        to_resolve: &[String],
+        options: &ResolveOptions,
+        jobs: usize,
+    ) -> Vec<Result<Resolution, ResolveError>> {
+        let mut results: Vec<Option<Result<Resolution, ResolveError>>> = to_resolve
+            .iter()
+            .map(|url| self.get(url, options).map(Ok))
+            .collect();
+
+        let misses: Vec<usize> = (0..to_resolve.len())
+            .filter(|&i| results[i].is_none())
+            .collect();
+        let miss_urls: Vec<String> = misses.iter().map(|&i| to_resolve[i].clone()).collect();
+
+        let resolved = urls::resolve_all(client, &miss_urls, options, jobs);
+        for (i, result) in misses.into_iter().zip(resolved) {
+            if let Ok(ref resolution) = result {
+                self.insert(&to_resolve[i], resolution, options);
+            }
+            results[i] = Some(result);
+        }
+
+        results.into_iter().map(|r| r.unwrap()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    use super::*;
+    use rules::RuleSet;
+    use test_support::temp_path;
+    use urls::resolve_tests::serve;
+    use urls::{build_client, ResolveError};
+
+    fn cache_path(name: &str) -> PathBuf {
+        temp_path(&format!("cache-{}", name)).join("resolve.json")
+    }
+
+    fn resolution(url: &str, final_url: &str) -> Resolution {
+        Resolution {
+            url: final_url.to_owned(),
+            chain: vec![
+                Hop {
+                    url: url.to_owned(),
+                    method: "HEAD".to_owned(),
+                    status: 301,
+                    html_redirect: None,
+                },
+                Hop {
+                    url: final_url.to_owned(),
+                    method: "HEAD".to_owned(),
+                    status: 200,
+                    html_redirect: None,
+                },
+            ],
+        }
+    }
+
+    const DAY: u64 = 24 * 60 * 60;
+
+    #[test]
+    fn test_missing_file_is_empty_cache() {
+        let cache = ResolveCache::open(cache_path("missing"), Duration::from_secs(DAY));
+        assert!(cache.entries.is_empty());
+    }
+
+    #[test]
+    fn test_corrupt_file_is_empty_cache() {
+        let path = cache_path("corrupt");
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, "this is not JSON").unwrap();
+
+        let cache = ResolveCache::open(path.clone(), Duration::from_secs(DAY));
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+        assert!(cache.entries.is_empty());
+    }
+
+    #[test]
+    fn test_save_and_reopen() {
+        let path = cache_path("roundtrip");
+        let options = ResolveOptions::default();
+        let r = resolution("https://t.co/abc", "https://example.org/");
+
+        let mut cache = ResolveCache::open(path.clone(), Duration::from_secs(DAY));
+        cache.insert("https://t.co/abc", &r, &options);
+        cache.save().unwrap();
+
+        let reopened = ResolveCache::open(path.clone(), Duration::from_secs(DAY));
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+        assert_eq!(reopened.get("https://t.co/abc", &options), Some(r));
+        assert_eq!(reopened.get("https://t.co/xyz", &options), None);
+    }
+
+    #[test]
+    fn test_expired_entries_are_ignored() {
+        let options = ResolveOptions::default();
+        let r = resolution("https://t.co/abc", "https://example.org/");
+
+        let mut cache = ResolveCache::open(cache_path("ttl"), Duration::from_secs(DAY));
+        cache.insert_at("https://t.co/abc", &r, &options, 1000);
+
+        assert_eq!(
+            cache.get_at("https://t.co/abc", &options, 1000 + DAY),
+            Some(r)
+        );
+        assert_eq!(
+            cache.get_at("https://t.co/abc", &options, 1000 + DAY + 1),
+            None
+        );
+    }
+
+    #[test]
+    fn test_expired_entries_are_dropped_on_save() {
+        let path = cache_path("prune");
+        let options = ResolveOptions::default();
+
+        let mut cache = ResolveCache::open(path.clone(), Duration::from_secs(DAY));
+        cache.insert_at(
+            "https://t.co/old",
+            &resolution("https://t.co/old", "https://example.org/old"),
+            &options,
+            1000,
+        );
+        cache.insert(
+            "https://t.co/new",
+            &resolution("https://t.co/new", "https://example.org/new"),
+            &options,
+        );
+        cache.save().unwrap();
+
+        let reopened = ResolveCache::open(path.clone(), Duration::from_secs(DAY));
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+        let keys: Vec<&String> = reopened.entries.keys().collect();
+        assert_eq!(keys, vec!["https://t.co/new"]);
+    }
+
+    #[test]
+    fn test_entries_depend_on_html_options() {
+        let plain = ResolveOptions::default();
+        let html = ResolveOptions {
+            follow_meta_refresh: true,
+            ..ResolveOptions::default()
+        };
+        let r = resolution("https://t.co/abc", "https://example.org/");
+
+        let mut cache = ResolveCache::open(cache_path("options"), Duration::from_secs(DAY));
+        cache.insert("https://t.co/abc", &r, &plain);
+
+        assert!(cache.get("https://t.co/abc", &plain).is_some());
+        assert!(cache.get("https://t.co/abc", &html).is_none());
+    }
+
+    #[test]
+    fn test_entries_respect_max_redirects() {
+        let options = ResolveOptions::default();
+        let r = resolution("https://t.co/abc", "https://example.org/");
+
+        let mut cache = ResolveCache::open(cache_path("redirects"), Duration::from_secs(DAY));
+        cache.insert("https://t.co/abc", &r, &options);
+
+        let one = ResolveOptions {
+            max_redirects: 1,
+            ..ResolveOptions::default()
+        };
+        let none = ResolveOptions {
+            max_redirects: 0,
+            ..ResolveOptions::default()
+        };
+        assert!(cache.get("https://t.co/abc", &one).is_some());
+        assert!(cache.get("https://t.co/abc", &none).is_none());
+    }
+
+    #[test]
+    fn test_cached_urls_are_tidied_with_current_rules() {
+        let options = ResolveOptions {
+            follow_meta_refresh: true,
+            tidy: Some(RuleSet::builtin()),
+            ..ResolveOptions::default()
+        };
+        let r = resolution(
+            "https://t.co/abc",
+            "https://example.org/?utm_source=newsletter",
+        );
+
+        let mut cache = ResolveCache::open(cache_path("tidy"), Duration::from_secs(DAY));
+        cache.insert("https://t.co/abc", &r, &options);
+
+        let cached = cache.get("https://t.co/abc", &options).unwrap();
+        assert_eq!(cached.url, "https://example.org/");
+        assert_eq!(cached.chain, r.chain);
+    }
+
+    #[test]
+    fn test_resolve_all_uses_cache_and_only_caches_successes() {
+        let options = ResolveOptions::default();
+        let client = build_client(&options).unwrap();
+        let r = resolution("https://t.co/abc", "https://example.org/");
+
+        let mut cache = ResolveCache::open(cache_path("resolve-all"), Duration::from_secs(DAY));
+        cache.insert("https://t.co/abc", &r, &options);
+
+        // Neither of these touch the network: one is cached, and the other
+        // isn't a valid URL.
+        let to_resolve = vec!["https://t.co/abc".to_owned(), "not a url".to_owned()];
+        let results = cache.resolve_all(&client, &to_resolve, &options, 2);
+
+        assert_eq!(results[0], Ok(r));
+        match results[1] {
+            Err(ResolveError::InvalidUrl { .. }) => (),
+            ref other => panic!("Unexpected result: {:?}", other),
+        }
+        assert!(!cache.entries.contains_key("not a url"));
+    }
+
+    #[test]
+    fn test_resolve_all_caches_new_urls() {
+        let base = serve(vec![("/short", 301, Some("/long"))], false);
+        let options = ResolveOptions::default();
+        let client = build_client(&options).unwrap();
+
+        let mut cache = ResolveCache::open(cache_path("miss"), Duration::from_secs(DAY));
+        let to_resolve = vec![format!("{}/short", base)];
+        let results = cache.resolve_all(&client, &to_resolve, &options, 1);
+
+        assert_eq!(results[0].as_ref().unwrap().url, format!("{}/long", base));
+        assert_eq!(
+            cache.get(&to_resolve[0], &options),
+            Some(results[0].clone().unwrap())
+        );
+    }
+}
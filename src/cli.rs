@@ -11,15 +11,21 @@ macro_rules! println_stderr(
 );
 
 const USAGE: &str = "
-Usage: <NAME> url [--window=<WINDOW> [--tab=<TAB>]]
-       <NAME> title [--window=<WINDOW> [--tab=<TAB>]]
-       <NAME> tidy-url <url>
-       <NAME> resolve <url>
-       <NAME> list-tabs
-       <NAME> urls-all
+Usage: <NAME> url [--window=<WINDOW> [--tab=<TAB>]] [--template=<TEMPLATE>] [--rules=<RULES>]
+       <NAME> title [--window=<WINDOW> [--tab=<TAB>]] [--template=<TEMPLATE>]
+       <NAME> tidy-url [--explain] <url> [--rules=<RULES>]
+       <NAME> resolve [--verbose] [--max-redirects=<N>] [--timeout=<SECONDS>] [--meta-refresh] [--canonical] [--jobs=<JOBS>] [--no-cache | --cache-ttl=<DAYS>] [--rules=<RULES>] [<urls>...]
+       <NAME> list-tabs [--format=<FORMAT> | --template=<TEMPLATE>] [--rules=<RULES>]
+       <NAME> urls-all [--format=<FORMAT> | --template=<TEMPLATE>] [--rules=<RULES>]
        <NAME> close-tabs <urls-to-close>
-       <NAME> reading-list
-       <NAME> icloud-tabs [--list-devices | --device=<DEVICE>]
+       <NAME> reading-list [--unread | --read] [--sort=<ORDER>] [--since=<DATE>] [--format=<FORMAT> | --template=<TEMPLATE>] [--rules=<RULES>] [--safari-dir=<DIR>]
+       <NAME> icloud-tabs [--list-devices [--verbose] | --device=<DEVICE>] [--format=<FORMAT> | --template=<TEMPLATE>] [--rules=<RULES>] [--safari-dir=<DIR>]
+       <NAME> history [--since=<DATE>] [--until=<DATE>] [--domain=<DOMAIN>] [--search=<TEXT>] [--dedupe] [--format=<FORMAT> | --template=<TEMPLATE>] [--rules=<RULES>] [--safari-dir=<DIR>]
+       <NAME> bookmarks [--folder=<FOLDER>] [--format=<FORMAT> | --template=<TEMPLATE>] [--safari-dir=<DIR>]
+       <NAME> session save <session-name>
+       <NAME> session restore <session-name>
+       <NAME> session list
+       <NAME> session show <session-name> [--format=<FORMAT> | --template=<TEMPLATE>]
        <NAME> (-h | --help)
        <NAME> --version
 
@@ -31,12 +37,63 @@ Options:
     --tab=<TAB>         Which tab to choose a URL from.  Use 1 for the leftmost
                         tab, 2 for second-from-left, and so on.
     --list-devices      Get a list of all the devices known to iCloud Tabs.
-    --device=<DEVICE>   Only get iCloud URLs for this device.
+    --device=<DEVICE>   Only get iCloud URLs for this device.  Either the
+                        device name or its UUID.
+    --unread            Only print Reading List items you haven't opened.
+    --read              Only print Reading List items you have opened.
+    --sort=<ORDER>      Sort Reading List items by the date they were added:
+                        newest or oldest.
+    --since=<DATE>      Only print Reading List items added, or history
+                        visits made, on or after this date, e.g. 2019-01-02
+                        or 2019-01-02T03:04:05Z.
+    --until=<DATE>      Only count history visits before this date.
+    --domain=<DOMAIN>   Only print history for this domain and its
+                        subdomains, e.g. rust-lang.org.
+    --search=<TEXT>     Only print history whose URL or title contains this
+                        text, ignoring case.
+    --dedupe            Combine history items which have the same URL after
+                        tidying.
+    --folder=<FOLDER>   Only print bookmarks in this folder, e.g.
+                        BookmarksBar/Rust.
+    --explain           Print each rule that changed the URL, with the URL
+                        before and after.
+    --verbose           Print every URL in the redirect chain, with the HTTP
+                        method and status code of each request.  When
+                        listing iCloud devices, print each device's UUID
+                        and when it last synced.
+    --max-redirects=<N>  Give up after following this many redirects.
+                        [default: 10]
+    --timeout=<SECONDS>  How long to wait for each request, in seconds.  Use 0
+                        to wait forever.  [default: 30]
+    --meta-refresh      Follow meta-refresh redirects in HTML pages, then
+                        tidy the final URL.
+    --canonical         Follow canonical links in HTML pages, then tidy the
+                        final URL.
+    --jobs=<JOBS>       How many URLs to resolve at once.  [default: 8]
+    --no-cache          Don't read or write the cache of resolved URLs.
+    --cache-ttl=<DAYS>  How long to trust a cached URL, in days.  [default: 30]
+    --format=<FORMAT>   How to print tabs and Reading List items: plain, json,
+                        jsonl, csv, tsv, markdown or html.  [default: plain]
+    --template=<TEMPLATE>  Print each item with a tera template, which can use
+                        the url, title, folder, device, window, tab,
+                        date_added, date_last_viewed, preview_text,
+                        image_url, visit_count and last_visited variables.
+                        Either a path to a template file, or the template.
+    --safari-dir=<DIR>  Read Safari's files from this directory instead of
+                        your Library folder, e.g. a backup.  This can be a
+                        Library folder, or a directory with Bookmarks.plist,
+                        History.db, and CloudTabs.db or
+                        com.apple.Safari.plist.  Defaults to
+                        $SAFARI_RS_SAFARI_DIR.
+    --rules=<RULES>     Path to a JSON file of extra rules for tidying URLs.
+                        Defaults to $SAFARI_RS_RULES, or rules.json in the
+                        safari.rs config directory if it exists.
 
 Commands:
     url           Print a URL from an open Safari tab.
     title         Print the title of an open Safari tab.
-    resolve       Follow redirects and print the final location of a URL.
+    resolve       Follow redirects and print the final location of a URL.  If
+                  you don't pass any URLs, read them from stdin, one per line.
     tidy-url      Remove tracking junk, mobile, links, etc. from a URL.
     list-tabs     Prints a list of URLs from every open Safari tab.
     urls-all      Same as urls-all.  Deprecated.
@@ -44,6 +101,13 @@ Commands:
     reading-list  Print a list of URLs from Reading List.
     icloud-tabs   Get a list of URLs from iCloud Tabs.  Default is to list URLs
                   from every device, or you can filter with the --device flag.
+    history       Print the URLs you've visited, most recent first, with how
+                  many times you visited each one.
+    bookmarks     Print your bookmarks as a tree, or export them with --format.
+    session       Save the tabs open in every Safari window as a named
+                  session, then reopen them later with `session restore`.
+                  `session list` prints the saved sessions, and
+                  `session show` prints the tabs in one of them.
 ";
 
 #[derive(Debug, Deserialize)]
@@ -57,13 +121,44 @@ pub struct Args {
     pub cmd_close_tabs: bool,
     pub cmd_icloud_tabs: bool,
     pub cmd_reading_list: bool,
+    pub cmd_bookmarks: bool,
+    pub cmd_history: bool,
+    pub cmd_session: bool,
+    pub cmd_save: bool,
+    pub cmd_restore: bool,
+    pub cmd_list: bool,
+    pub cmd_show: bool,
     pub flag_window: Option<u32>,
     pub flag_tab: Option<u32>,
     pub flag_version: bool,
     pub flag_list_devices: bool,
     pub flag_device: Option<String>,
+    pub flag_unread: bool,
+    pub flag_read: bool,
+    pub flag_sort: Option<String>,
+    pub flag_since: Option<String>,
+    pub flag_until: Option<String>,
+    pub flag_domain: Option<String>,
+    pub flag_search: Option<String>,
+    pub flag_dedupe: bool,
+    pub flag_folder: Option<String>,
+    pub flag_rules: Option<String>,
+    pub flag_safari_dir: Option<String>,
+    pub flag_format: String,
+    pub flag_template: Option<String>,
+    pub flag_explain: bool,
+    pub flag_verbose: bool,
+    pub flag_max_redirects: usize,
+    pub flag_timeout: u64,
+    pub flag_meta_refresh: bool,
+    pub flag_canonical: bool,
+    pub flag_jobs: usize,
+    pub flag_no_cache: bool,
+    pub flag_cache_ttl: u64,
     pub arg_url: String,
+    pub arg_urls: Vec<String>,
     pub arg_urls_to_close: String,
+    pub arg_session_name: String,
 }
 
 pub fn parse_args(name: &str) -> Args {
@@ -74,22 +169,12 @@ pub fn parse_args(name: &str) -> Args {
     // 0 is the default value for the --window and --tab flags, so if we get
     // this value then replace it with None.
     if args.cmd_url {
-        match args.flag_window {
-            Some(v) => {
-                if v == 0 {
-                    args.flag_window = None;
-                };
-            }
-            None => {}
-        };
-        match args.flag_tab {
-            Some(v) => {
-                if v == 0 {
-                    args.flag_tab = None;
-                };
-            }
-            None => {}
-        };
+        if args.flag_window == Some(0) {
+            args.flag_window = None;
+        }
+        if args.flag_tab == Some(0) {
+            args.flag_tab = None;
+        }
 
         if args.flag_tab.is_some() && args.flag_window.is_none() {
             Error::Usage("Cannot use --tab without --window.".to_string()).exit();
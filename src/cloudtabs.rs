@@ -0,0 +1,227 @@
+//! Read iCloud Tabs from CloudTabs.db.
+//!
+//! Newer versions of Safari keep iCloud Tabs in a SQLite database rather
+//! than com.apple.Safari.plist.  There's a row in `cloud_tab_devices` for
+//! each device, and a row in `cloud_tabs` for each tab, which points to its
+//! device with `device_uuid`.
+
+use std::path::Path;
+
+use rusqlite::{self, Connection};
+
+use dates::from_mac_timestamp;
+use error::Error;
+use rules::RuleSet;
+use safari::{CloudDevice, CloudTab};
+use sqlite::open_read_only;
+
+/// Read every device and tab in a CloudTabs.db file.
+pub fn read(path: &Path, rules: &RuleSet) -> Result<Vec<CloudDevice>, Error> {
+    open_read_only(path, |conn| parse(conn, rules))
+}
+
+fn parse(conn: &Connection, rules: &RuleSet) -> Result<Vec<CloudDevice>, rusqlite::Error> {
+    // Not every version of Safari records when a device last synced.
+    let last_modified = if has_column(conn, "cloud_tab_devices", "last_modified")? {
+        "last_modified"
+    } else {
+        "NULL"
+    };
+    let mut stmt = conn.prepare(&format!(
+        "SELECT device_uuid, device_name, {} FROM cloud_tab_devices ORDER BY device_uuid",
+        last_modified
+    ))?;
+    let mut devices = stmt
+        .query_map([], |row| {
+            Ok(CloudDevice {
+                uuid: row.get(0)?,
+                name: row.get(1)?,
+                last_modified: row.get::<_, Option<f64>>(2)?.and_then(from_mac_timestamp),
+                tabs: vec![],
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // We don't understand the `position` column, which is an opaque blob,
+    // so we return tabs in the order Safari inserted them.
+    let mut stmt =
+        conn.prepare("SELECT url, title FROM cloud_tabs WHERE device_uuid = ?1 ORDER BY rowid")?;
+    for device in &mut devices {
+        let rows = stmt.query_map([&device.uuid], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, Option<String>>(1)?))
+        })?;
+        for (i, row) in rows.enumerate() {
+            let (url, title) = row?;
+            device.tabs.push(CloudTab {
+                index: i as u32 + 1,
+                url: rules.tidy(&url),
+                title,
+            });
+        }
+    }
+    Ok(devices)
+}
+
+fn has_column(conn: &Connection, table: &str, column: &str) -> Result<bool, rusqlite::Error> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let names = stmt
+        .query_map([], |row| row.get::<_, String>(1))?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(names.iter().any(|name| name == column))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use rusqlite::Connection;
+
+    use cloudtabs::{parse, read};
+    use dates::parse_date;
+    use error::Error;
+    use rules::RuleSet;
+    use safari::CloudTab;
+    use test_support::temp_path;
+
+    const SCHEMA: &str = "
+        CREATE TABLE cloud_tab_devices (
+            device_uuid TEXT PRIMARY KEY,
+            system_fields BLOB,
+            device_name TEXT,
+            has_duplicate_device_name BOOLEAN,
+            is_ephemeral_device BOOLEAN,
+            last_modified REAL
+        );
+        CREATE TABLE cloud_tabs (
+            tab_uuid TEXT PRIMARY KEY,
+            system_fields BLOB,
+            device_uuid TEXT,
+            position BLOB,
+            title TEXT,
+            url TEXT,
+            is_showing_reader BOOLEAN,
+            is_pinned BOOLEAN
+        );
+    ";
+
+    // 2019-01-05T12:00:00Z, in seconds since 1 January 2001.
+    const LAST_MODIFIED: f64 = 568_382_400.0;
+
+    fn fixture() -> Connection {
+        populate(Connection::open_in_memory().unwrap())
+    }
+
+    fn populate(conn: Connection) -> Connection {
+        conn.execute_batch(SCHEMA).unwrap();
+        conn.execute_batch(&format!(
+            "
+            INSERT INTO cloud_tab_devices (device_uuid, device_name, last_modified) VALUES
+                ('9F1D2E3C-4B5A-6978-8A9B-0C1D2E3F4A5B', 'Alex''s iPad', NULL),
+                ('0C5A8C53-2A3B-4C1C-9A4E-3B1B2F1E7D10', 'Alex''s iPhone', {});
+            INSERT INTO cloud_tabs (tab_uuid, device_uuid, title, url) VALUES
+                ('T1', '0C5A8C53-2A3B-4C1C-9A4E-3B1B2F1E7D10', 'Example Domain',
+                 'https://example.org/?utm_source=twitter'),
+                ('T2', '0C5A8C53-2A3B-4C1C-9A4E-3B1B2F1E7D10', NULL,
+                 'https://docs.rs/plist');
+            ",
+            LAST_MODIFIED
+        ))
+        .unwrap();
+        conn
+    }
+
+    #[test]
+    fn test_devices_and_tabs() {
+        let devices = parse(&fixture(), &RuleSet::builtin()).unwrap();
+        assert_eq!(devices.len(), 2);
+
+        let iphone = &devices[0];
+        assert_eq!(iphone.uuid, "0C5A8C53-2A3B-4C1C-9A4E-3B1B2F1E7D10");
+        assert_eq!(iphone.name, "Alex's iPhone");
+        assert_eq!(
+            iphone.last_modified,
+            Some(parse_date("2019-01-05T12:00:00Z").unwrap())
+        );
+        assert_eq!(
+            iphone.tabs,
+            vec![
+                CloudTab {
+                    index: 1,
+                    url: "https://example.org/".to_owned(),
+                    title: Some("Example Domain".to_owned()),
+                },
+                CloudTab {
+                    index: 2,
+                    url: "https://docs.rs/plist".to_owned(),
+                    title: None,
+                },
+            ]
+        );
+
+        let ipad = &devices[1];
+        assert_eq!(ipad.name, "Alex's iPad");
+        assert_eq!(ipad.last_modified, None);
+        assert_eq!(ipad.tabs, vec![]);
+    }
+
+    #[test]
+    fn test_schema_without_last_modified() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "
+            CREATE TABLE cloud_tab_devices (device_uuid TEXT PRIMARY KEY, device_name TEXT);
+            CREATE TABLE cloud_tabs (tab_uuid TEXT PRIMARY KEY, device_uuid TEXT, title TEXT, url TEXT);
+            INSERT INTO cloud_tab_devices VALUES ('A', 'Alex''s Mac');
+            INSERT INTO cloud_tabs VALUES ('T1', 'A', NULL, 'https://example.org/');
+            ",
+        )
+        .unwrap();
+        let devices = parse(&conn, &RuleSet::builtin()).unwrap();
+        assert_eq!(devices[0].last_modified, None);
+        assert_eq!(devices[0].tabs[0].url, "https://example.org/");
+    }
+
+    #[test]
+    fn test_tab_without_url_is_error() {
+        let conn = fixture();
+        conn.execute("UPDATE cloud_tabs SET url = NULL WHERE tab_uuid = 'T2'", [])
+            .unwrap();
+        assert!(parse(&conn, &RuleSet::builtin()).is_err());
+    }
+
+    #[test]
+    fn test_read_from_file() {
+        let path = temp_path("cloudtabs.db");
+        let _ = fs::remove_file(&path);
+        populate(Connection::open(&path).unwrap());
+
+        let devices = read(&path, &RuleSet::builtin()).unwrap();
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[0].tabs.len(), 2);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_missing_tables_are_an_error() {
+        let path = temp_path("cloudtabs-empty.db");
+        let _ = fs::remove_file(&path);
+        Connection::open(&path)
+            .unwrap()
+            .execute_batch("CREATE TABLE other (id INTEGER);")
+            .unwrap();
+
+        match read(&path, &RuleSet::builtin()) {
+            Err(Error::Database { reason, .. }) => assert!(reason.contains("cloud_tab_devices")),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_missing_file_is_an_error() {
+        match read(&temp_path("does-not-exist.db"), &RuleSet::builtin()) {
+            Err(Error::Database { .. }) => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,131 @@
+//! Parse and print the dates in Safari's plist files.
+
+use chrono::{DateTime, NaiveDate, NaiveDateTime, UTC};
+
+use error::Error;
+
+/// Seconds between the Unix epoch and 1 January 2001, which is when the
+/// timestamps in Apple's databases start.
+const MAC_EPOCH_OFFSET: f64 = 978_307_200.0;
+
+/// Convert a timestamp in seconds since 1 January 2001 to a date.
+pub fn from_mac_timestamp(seconds: f64) -> Option<DateTime<UTC>> {
+    let unix = seconds + MAC_EPOCH_OFFSET;
+    NaiveDateTime::from_timestamp_opt(unix.trunc() as i64, (unix.fract() * 1e9) as u32)
+        .map(|naive| DateTime::from_utc(naive, UTC))
+}
+
+/// Convert a date to a timestamp in seconds since 1 January 2001.
+pub fn to_mac_timestamp(date: &DateTime<UTC>) -> f64 {
+    date.timestamp() as f64 - MAC_EPOCH_OFFSET + f64::from(date.timestamp_subsec_nanos()) / 1e9
+}
+
+/// Print a date as an RFC 3339 timestamp in UTC, e.g. `2019-01-02T03:04:05Z`.
+pub fn format_date(date: &DateTime<UTC>) -> String {
+    date.format("%Y-%m-%dT%H:%M:%SZ").to_string()
+}
+
+/// Parse a date from the command line.  This can be a day (`2019-01-02`),
+/// which means midnight UTC, or a full RFC 3339 timestamp.
+pub fn parse_date(s: &str) -> Result<DateTime<UTC>, Error> {
+    if let Ok(date) = NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        return Ok(DateTime::from_utc(date.and_hms(0, 0, 0), UTC));
+    }
+    match DateTime::parse_from_rfc3339(s) {
+        Ok(date) => Ok(date.with_timezone(&UTC)),
+        Err(_) => Err(Error::Usage(format!(
+            "Unrecognised date {:?}; expected YYYY-MM-DD or an RFC 3339 timestamp",
+            s
+        ))),
+    }
+}
+
+/// Describe how long ago something happened, e.g. "3 days ago".
+pub fn describe_age(date: &DateTime<UTC>, now: &DateTime<UTC>) -> String {
+    let seconds = now.signed_duration_since(*date).num_seconds();
+    let (count, unit) = if seconds < 60 {
+        return "just now".to_owned();
+    } else if seconds < 60 * 60 {
+        (seconds / 60, "minute")
+    } else if seconds < 24 * 60 * 60 {
+        (seconds / (60 * 60), "hour")
+    } else {
+        (seconds / (24 * 60 * 60), "day")
+    };
+    if count == 1 {
+        format!("1 {} ago", unit)
+    } else {
+        format!("{} {}s ago", count, unit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, TimeZone, UTC};
+
+    use dates::{describe_age, format_date, from_mac_timestamp, parse_date, to_mac_timestamp};
+
+    fn date(s: &str) -> DateTime<UTC> {
+        parse_date(s).unwrap()
+    }
+
+    #[test]
+    fn test_parse_date() {
+        assert_eq!(
+            parse_date("2019-01-02"),
+            Ok(UTC.ymd(2019, 1, 2).and_hms(0, 0, 0))
+        );
+        assert_eq!(
+            parse_date("2019-01-02T03:04:05+01:00"),
+            Ok(UTC.ymd(2019, 1, 2).and_hms(2, 4, 5))
+        );
+        assert!(parse_date("last tuesday").is_err());
+        assert!(parse_date("2019-13-01").is_err());
+    }
+
+    #[test]
+    fn test_format_date() {
+        assert_eq!(
+            format_date(&date("2019-01-02T03:04:05.678Z")),
+            "2019-01-02T03:04:05Z"
+        );
+    }
+
+    #[test]
+    fn test_mac_timestamps() {
+        let date = date("2019-01-05T12:00:00Z");
+        assert_eq!(to_mac_timestamp(&date), 568_382_400.0);
+        assert_eq!(from_mac_timestamp(568_382_400.0), Some(date));
+        assert_eq!(
+            from_mac_timestamp(0.5),
+            Some(UTC.ymd(2001, 1, 1).and_hms_milli(0, 0, 0, 500))
+        );
+    }
+
+    #[test]
+    fn test_describe_age() {
+        let now = date("2019-01-10T12:00:00Z");
+        assert_eq!(
+            describe_age(&date("2019-01-10T11:59:30Z"), &now),
+            "just now"
+        );
+        assert_eq!(
+            describe_age(&date("2019-01-10T11:59:00Z"), &now),
+            "1 minute ago"
+        );
+        assert_eq!(
+            describe_age(&date("2019-01-10T09:00:00Z"), &now),
+            "3 hours ago"
+        );
+        assert_eq!(
+            describe_age(&date("2019-01-03T12:00:00Z"), &now),
+            "7 days ago"
+        );
+
+        // Clocks on different devices don't always agree.
+        assert_eq!(
+            describe_age(&date("2019-01-10T12:05:00Z"), &now),
+            "just now"
+        );
+    }
+}
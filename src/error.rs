@@ -0,0 +1,264 @@
+//! The errors that safari.rs can return.
+//!
+//! Each kind of error has its own process exit code, so shell scripts that
+//! call `safari` can tell (say) "Safari isn't running" apart from "that tab
+//! doesn't exist".
+
+use std::error;
+use std::fmt;
+use std::path::PathBuf;
+
+use urls::ResolveError;
+
+/// Something that went wrong in safari.rs.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Error {
+    /// A command-line option or template didn't make sense, e.g. an
+    /// unknown `--format`.
+    Usage(String),
+    /// Safari isn't running, so we can't ask it about windows or tabs.
+    SafariNotRunning,
+    /// There isn't a window or tab at the index we were asked for.  If
+    /// `tab` is None, the window itself doesn't exist.
+    NoSuchTab { window: u32, tab: Option<u32> },
+    /// The window exists, but it doesn't have a current tab.
+    NoCurrentTab { window: u32 },
+    /// Safari doesn't have a URL or title for a tab, e.g. a blank new tab.
+    TabUnreadable {
+        window: u32,
+        tab: u32,
+        reason: String,
+    },
+    /// An AppleScript or JXA script failed, or printed something we didn't
+    /// understand.
+    Script(String),
+    /// We couldn't open or read one of Safari's plist files.
+    PlistMissing { path: PathBuf, reason: String },
+    /// A plist file didn't have the shape we expected.  `key_path` says
+    /// where we were looking, e.g. `Children[3].URLString`.
+    PlistMalformed {
+        path: PathBuf,
+        key_path: String,
+        reason: String,
+    },
+    /// We couldn't follow a URL's redirects.
+    Network(ResolveError),
+    /// The user's rules file (or a ClearURLs catalogue it uses) is missing
+    /// or broken.  `path` is None if the rules didn't come from a file.
+    Rules {
+        path: Option<PathBuf>,
+        reason: String,
+    },
+    /// Reading or writing a file went wrong.
+    Io { path: PathBuf, reason: String },
+    /// We couldn't open or query one of Safari's SQLite databases, or it
+    /// didn't have the tables we expected.
+    Database { path: PathBuf, reason: String },
+    /// A saved session isn't a session file we understand, e.g. because it
+    /// was saved by a newer version of safari.rs.  `path` is None if the
+    /// session didn't come from a file.
+    SessionMalformed {
+        path: Option<PathBuf>,
+        reason: String,
+    },
+}
+
+impl Error {
+    /// The process exit code for this error.
+    ///
+    /// ```text
+    /// 1   reading or writing a file failed
+    /// 2   bad command-line option or template
+    /// 3   Safari isn't running
+    /// 4   no such window or tab
+    /// 5   tab doesn't have a URL or title
+    /// 6   an AppleScript or JXA script failed
+    /// 7   plist file is missing or unreadable
+    /// 8   plist file is malformed
+    /// 9   network error while resolving a URL
+    /// 10  rules file is missing or broken
+    /// 11  SQLite database is missing, unreadable or malformed
+    /// 12  saved session file is malformed
+    /// ```
+    ///
+    pub fn exit_code(&self) -> i32 {
+        match *self {
+            Error::Io { .. } => 1,
+            Error::Usage(_) => 2,
+            Error::SafariNotRunning => 3,
+            Error::NoSuchTab { .. } | Error::NoCurrentTab { .. } => 4,
+            Error::TabUnreadable { .. } => 5,
+            Error::Script(_) => 6,
+            Error::PlistMissing { .. } => 7,
+            Error::PlistMalformed { .. } => 8,
+            Error::Network(_) => 9,
+            Error::Rules { .. } => 10,
+            Error::Database { .. } => 11,
+            Error::SessionMalformed { .. } => 12,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Usage(ref message) => write!(f, "{}", message),
+            Error::SafariNotRunning => write!(f, "Safari is not running."),
+            Error::NoSuchTab { window, tab } => match tab {
+                Some(tab) => write!(f, "Invalid index: no tab {} in window {}.", tab, window),
+                None => write!(f, "Invalid index: no window {}.", window),
+            },
+            Error::NoCurrentTab { window } => {
+                write!(f, "Window {} doesn't have a current tab.", window)
+            }
+            Error::TabUnreadable {
+                window,
+                tab,
+                ref reason,
+            } => write!(
+                f,
+                "Unable to read tab {} of window {}: {}",
+                tab, window, reason
+            ),
+            Error::Script(ref message) => write!(f, "{}", message),
+            Error::PlistMissing {
+                ref path,
+                ref reason,
+            } => write!(f, "Unable to read {}: {}", path.display(), reason),
+            Error::PlistMalformed {
+                ref path,
+                ref key_path,
+                ref reason,
+            } => write!(
+                f,
+                "Unexpected data in {} at {}: {}",
+                path.display(),
+                key_path,
+                reason
+            ),
+            Error::Network(ref e) => write!(f, "{}", e),
+            Error::Rules {
+                path: Some(ref path),
+                ref reason,
+            } => write!(f, "{}: {}", path.display(), reason),
+            Error::Rules {
+                path: None,
+                ref reason,
+            } => write!(f, "{}", reason),
+            Error::Io {
+                ref path,
+                ref reason,
+            } => write!(f, "{}: {}", path.display(), reason),
+            Error::Database {
+                ref path,
+                ref reason,
+            } => write!(f, "Unable to read {}: {}", path.display(), reason),
+            Error::SessionMalformed {
+                path: Some(ref path),
+                ref reason,
+            } => write!(f, "Unable to read session {}: {}", path.display(), reason),
+            Error::SessionMalformed {
+                path: None,
+                ref reason,
+            } => write!(f, "Unable to read session: {}", reason),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+impl From<ResolveError> for Error {
+    fn from(e: ResolveError) -> Error {
+        Error::Network(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use error::Error;
+    use urls::ResolveError;
+
+    #[test]
+    fn test_exit_codes_are_distinct() {
+        let errors = vec![
+            Error::Io {
+                path: PathBuf::from("cache.json"),
+                reason: "Permission denied".to_owned(),
+            },
+            Error::Usage("Unrecognised format".to_owned()),
+            Error::SafariNotRunning,
+            Error::NoSuchTab {
+                window: 1,
+                tab: Some(5),
+            },
+            Error::TabUnreadable {
+                window: 1,
+                tab: 1,
+                reason: "no URL".to_owned(),
+            },
+            Error::Script("execution error".to_owned()),
+            Error::PlistMissing {
+                path: PathBuf::from("Bookmarks.plist"),
+                reason: "No such file or directory".to_owned(),
+            },
+            Error::PlistMalformed {
+                path: PathBuf::from("Bookmarks.plist"),
+                key_path: "Children".to_owned(),
+                reason: "expected an array".to_owned(),
+            },
+            Error::Network(ResolveError::Client("TLS".to_owned())),
+            Error::Rules {
+                path: None,
+                reason: "Unable to parse rules".to_owned(),
+            },
+            Error::Database {
+                path: PathBuf::from("CloudTabs.db"),
+                reason: "no such table: cloud_tabs".to_owned(),
+            },
+            Error::SessionMalformed {
+                path: Some(PathBuf::from("work.json")),
+                reason: "missing a format version".to_owned(),
+            },
+        ];
+        let mut codes: Vec<i32> = errors.iter().map(|e| e.exit_code()).collect();
+        assert!(codes.iter().all(|&c| c != 0));
+        codes.sort();
+        codes.dedup();
+        assert_eq!(codes.len(), errors.len());
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(
+            Error::NoSuchTab {
+                window: 2,
+                tab: Some(5)
+            }
+            .to_string(),
+            "Invalid index: no tab 5 in window 2."
+        );
+        assert_eq!(
+            Error::NoCurrentTab { window: 2 }.to_string(),
+            "Window 2 doesn't have a current tab."
+        );
+        assert_eq!(
+            Error::PlistMalformed {
+                path: PathBuf::from("Bookmarks.plist"),
+                key_path: "Children[3].URLString".to_owned(),
+                reason: "expected a string".to_owned(),
+            }
+            .to_string(),
+            "Unexpected data in Bookmarks.plist at Children[3].URLString: expected a string"
+        );
+        assert_eq!(
+            Error::Rules {
+                path: Some(PathBuf::from("rules.json")),
+                reason: "Unable to parse rules: EOF".to_owned(),
+            }
+            .to_string(),
+            "rules.json: Unable to parse rules: EOF"
+        );
+    }
+}
@@ -0,0 +1,66 @@
+//! Helpers for the files that safari.rs writes itself, like the resolve
+//! cache and saved sessions.
+
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+
+use error::Error;
+
+/// Write a file, creating its directory if necessary.
+///
+/// We write to a temporary file and rename it, so a crash halfway through
+/// doesn't leave a truncated file.
+pub fn write_atomically(path: &Path, bytes: &[u8]) -> Result<(), Error> {
+    if let Some(parent) = path.parent() {
+        if let Err(e) = fs::create_dir_all(parent) {
+            return Err(Error::Io {
+                path: parent.to_owned(),
+                reason: format!("Unable to create directory: {}", e),
+            });
+        }
+    }
+
+    let mut tmp_name = path
+        .file_name()
+        .map_or_else(OsString::new, |n| n.to_owned());
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+
+    File::create(&tmp_path)
+        .and_then(|mut f| f.write_all(bytes))
+        .and_then(|_| fs::rename(&tmp_path, path))
+        .map_err(|e| Error::Io {
+            path: path.to_owned(),
+            reason: format!("Unable to write file: {}", e),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use error::Error;
+    use files::write_atomically;
+    use test_support::temp_path;
+
+    #[test]
+    fn test_write_atomically() {
+        let dir = temp_path("files");
+        let _ = fs::remove_dir_all(&dir);
+        let path = dir.join("nested").join("file.json");
+
+        write_atomically(&path, b"first").unwrap();
+        write_atomically(&path, b"second").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "second");
+        assert!(!dir.join("nested").join("file.json.tmp").exists());
+
+        // We can't create a directory inside a file.
+        match write_atomically(&path.join("child.json"), b"") {
+            Err(Error::Io { .. }) => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
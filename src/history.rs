@@ -0,0 +1,339 @@
+//! Read your browsing history from Safari's History.db.
+//!
+//! History.db is a SQLite database.  There's a row in `history_items` for
+//! each URL you've visited, and a row in `history_visits` for each time you
+//! visited it, which points to its URL with `history_item`.  Each visit has
+//! the page title at the time, and a timestamp in seconds since 2001.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use chrono::{DateTime, UTC};
+use rusqlite::{self, Connection};
+use urlparse::urlparse;
+
+use dates::{from_mac_timestamp, to_mac_timestamp};
+use error::Error;
+use paths::SafariPaths;
+use rules::RuleSet;
+use sqlite::open_read_only;
+
+/// A URL from your browsing history.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HistoryItem {
+    pub url: String,
+    /// The title of the page the last time you visited it.
+    pub title: Option<String>,
+    /// How many times you visited this URL in the dates you asked for.
+    pub visit_count: u32,
+    pub last_visited: Option<DateTime<UTC>>,
+}
+
+/// Which history items to return.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct HistoryQuery {
+    /// If set, only count visits on or after this date.
+    pub since: Option<DateTime<UTC>>,
+    /// If set, only count visits before this date.
+    pub until: Option<DateTime<UTC>>,
+    /// If set, only URLs on this domain or one of its subdomains.
+    pub domain: Option<String>,
+    /// If set, only URLs or titles containing this text, ignoring case.
+    pub search: Option<String>,
+    /// If true, combine items which are the same URL after tidying.
+    pub dedupe: bool,
+}
+
+impl HistoryQuery {
+    fn matches(&self, item: &HistoryItem) -> bool {
+        if let Some(ref domain) = self.domain {
+            if !is_on_domain(&item.url, domain) {
+                return false;
+            }
+        }
+        if let Some(ref search) = self.search {
+            let search = search.to_lowercase();
+            let in_title = item
+                .title
+                .as_ref()
+                .is_some_and(|t| t.to_lowercase().contains(&search));
+            if !item.url.to_lowercase().contains(&search) && !in_title {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Is this URL on the given domain, or one of its subdomains?
+fn is_on_domain(url: &str, domain: &str) -> bool {
+    let hostname = match urlparse(url).hostname {
+        Some(hostname) => hostname.to_lowercase(),
+        None => return false,
+    };
+    let domain = domain.trim_start_matches('.').to_lowercase();
+    hostname == domain || hostname.ends_with(&format!(".{}", domain))
+}
+
+/// Return the URLs in your Safari history, most recently visited first.
+pub fn get_history(
+    paths: &SafariPaths,
+    rules: &RuleSet,
+    query: &HistoryQuery,
+) -> Result<Vec<HistoryItem>, Error> {
+    read_history(&paths.history, rules, query)
+}
+
+/// Return the URLs in a History.db file, most recently visited first.
+pub fn read_history(
+    path: &Path,
+    rules: &RuleSet,
+    query: &HistoryQuery,
+) -> Result<Vec<HistoryItem>, Error> {
+    open_read_only(path, |conn| parse_history(conn, rules, query))
+}
+
+fn parse_history(
+    conn: &Connection,
+    rules: &RuleSet,
+    query: &HistoryQuery,
+) -> Result<Vec<HistoryItem>, rusqlite::Error> {
+    // When a query uses MAX(), SQLite takes the other bare columns from the
+    // row with the maximum value, so `title` is from the most recent visit.
+    let mut stmt = conn.prepare(
+        "SELECT history_items.url, history_visits.title, COUNT(*), MAX(history_visits.visit_time)
+         FROM history_items
+         JOIN history_visits ON history_visits.history_item = history_items.id
+         WHERE history_visits.visit_time >= ?1 AND history_visits.visit_time < ?2
+         GROUP BY history_items.id
+         ORDER BY MAX(history_visits.visit_time) DESC, history_items.id",
+    )?;
+    let since = query.since.as_ref().map_or(f64::MIN, to_mac_timestamp);
+    let until = query.until.as_ref().map_or(f64::MAX, to_mac_timestamp);
+
+    let mut items: Vec<HistoryItem> = vec![];
+
+    // Where each URL is in `items`, so deduping doesn't have to search it.
+    let mut positions: HashMap<String, usize> = HashMap::new();
+    let rows = stmt.query_map([since, until], |row| {
+        Ok(HistoryItem {
+            url: rules.tidy(&row.get::<_, String>(0)?),
+            title: row.get::<_, Option<String>>(1)?.filter(|t| !t.is_empty()),
+            visit_count: row.get(2)?,
+            last_visited: row.get::<_, Option<f64>>(3)?.and_then(from_mac_timestamp),
+        })
+    })?;
+    for row in rows {
+        let item = row?;
+        if !query.matches(&item) {
+            continue;
+        }
+        if query.dedupe {
+            // Items are sorted newest first, so an earlier copy of the same
+            // URL already has the most recent title and visit time.
+            if let Some(&idx) = positions.get(&item.url) {
+                let existing = &mut items[idx];
+                existing.visit_count += item.visit_count;
+                if existing.title.is_none() {
+                    existing.title = item.title;
+                }
+                continue;
+            }
+            positions.insert(item.url.clone(), items.len());
+        }
+        items.push(item);
+    }
+    Ok(items)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use rusqlite::Connection;
+
+    use dates::parse_date;
+    use error::Error;
+    use history::{is_on_domain, parse_history, read_history, HistoryItem, HistoryQuery};
+    use rules::RuleSet;
+    use test_support::temp_path;
+
+    /// A small History.db, with the columns we use from the real schema.
+    /// Visit times are seconds since 1 January 2001; 568382400 is
+    /// 2019-01-05T12:00:00Z.
+    const FIXTURE: &str = "
+        CREATE TABLE history_items (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            url TEXT NOT NULL UNIQUE,
+            domain_expansion TEXT NULL,
+            visit_count INTEGER NOT NULL
+        );
+        CREATE TABLE history_visits (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            history_item INTEGER NOT NULL REFERENCES history_items(id),
+            visit_time REAL NOT NULL,
+            title TEXT NULL,
+            load_successful BOOLEAN NOT NULL DEFAULT 1
+        );
+        INSERT INTO history_items (id, url, domain_expansion, visit_count) VALUES
+            (1, 'https://example.org/', 'example', 3),
+            (2, 'https://www.rust-lang.org/', 'rust-lang', 1),
+            (3, 'https://blog.rust-lang.org/?utm_source=twitter', 'blog.rust-lang', 1),
+            (4, 'https://blog.rust-lang.org/', 'blog.rust-lang', 1),
+            (5, 'https://notexample.org/', 'notexample', 1);
+        INSERT INTO history_visits (history_item, visit_time, title) VALUES
+            (1, 568296000, 'Old title'),
+            (1, 568382400, 'Example Domain'),
+            (1, 568386000, 'Example Domain'),
+            (2, 568300000, 'Rust Programming Language'),
+            (3, 568390000, 'Rust Blog'),
+            (4, 568380000, ''),
+            (5, 568200000, 'Not Example');
+    ";
+
+    fn fixture() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(FIXTURE).unwrap();
+        conn
+    }
+
+    fn history(query: &HistoryQuery) -> Vec<HistoryItem> {
+        parse_history(&fixture(), &RuleSet::builtin(), query).unwrap()
+    }
+
+    fn urls(items: &[HistoryItem]) -> Vec<&str> {
+        items.iter().map(|item| item.url.as_str()).collect()
+    }
+
+    #[test]
+    fn test_history_is_newest_first() {
+        let items = history(&HistoryQuery::default());
+        assert_eq!(
+            urls(&items),
+            vec![
+                "https://blog.rust-lang.org/",
+                "https://example.org/",
+                "https://blog.rust-lang.org/",
+                "https://www.rust-lang.org/",
+                "https://notexample.org/",
+            ]
+        );
+        assert_eq!(
+            items[1],
+            HistoryItem {
+                url: "https://example.org/".to_owned(),
+                title: Some("Example Domain".to_owned()),
+                visit_count: 3,
+                last_visited: Some(parse_date("2019-01-05T13:00:00Z").unwrap()),
+            }
+        );
+
+        // Safari records an empty title for pages that didn't have one.
+        assert_eq!(items[2].title, None);
+    }
+
+    #[test]
+    fn test_date_range_counts_visits_in_range() {
+        let items = history(&HistoryQuery {
+            since: Some(parse_date("2019-01-05").unwrap()),
+            until: Some(parse_date("2019-01-05T13:00:00Z").unwrap()),
+            ..HistoryQuery::default()
+        });
+        assert_eq!(
+            urls(&items),
+            vec!["https://example.org/", "https://blog.rust-lang.org/"]
+        );
+        assert_eq!(items[0].visit_count, 1);
+        assert_eq!(
+            items[0].last_visited,
+            Some(parse_date("2019-01-05T12:00:00Z").unwrap())
+        );
+    }
+
+    #[test]
+    fn test_domain_filter() {
+        let items = history(&HistoryQuery {
+            domain: Some("rust-lang.org".to_owned()),
+            ..HistoryQuery::default()
+        });
+        assert_eq!(
+            urls(&items),
+            vec![
+                "https://blog.rust-lang.org/",
+                "https://blog.rust-lang.org/",
+                "https://www.rust-lang.org/",
+            ]
+        );
+
+        let items = history(&HistoryQuery {
+            domain: Some("example.org".to_owned()),
+            ..HistoryQuery::default()
+        });
+        assert_eq!(urls(&items), vec!["https://example.org/"]);
+    }
+
+    #[test]
+    fn test_is_on_domain() {
+        assert!(is_on_domain("https://example.org/", "example.org"));
+        assert!(is_on_domain("https://www.Example.org:8080/", "example.org"));
+        assert!(is_on_domain("https://www.example.org/", ".example.org"));
+        assert!(!is_on_domain("https://notexample.org/", "example.org"));
+        assert!(!is_on_domain("not a url", "example.org"));
+    }
+
+    #[test]
+    fn test_search_matches_url_or_title() {
+        let items = history(&HistoryQuery {
+            search: Some("programming".to_owned()),
+            ..HistoryQuery::default()
+        });
+        assert_eq!(urls(&items), vec!["https://www.rust-lang.org/"]);
+
+        let items = history(&HistoryQuery {
+            search: Some("BLOG".to_owned()),
+            ..HistoryQuery::default()
+        });
+        assert_eq!(items.len(), 2);
+    }
+
+    #[test]
+    fn test_dedupe_combines_tidied_urls() {
+        let items = history(&HistoryQuery {
+            dedupe: true,
+            ..HistoryQuery::default()
+        });
+        assert_eq!(items.len(), 4);
+        assert_eq!(
+            items[0],
+            HistoryItem {
+                url: "https://blog.rust-lang.org/".to_owned(),
+                title: Some("Rust Blog".to_owned()),
+                visit_count: 2,
+                last_visited: Some(parse_date("2019-01-05T14:06:40Z").unwrap()),
+            }
+        );
+    }
+
+    #[test]
+    fn test_read_history_from_file() {
+        let path = temp_path("history.db");
+        let _ = fs::remove_file(&path);
+        Connection::open(&path)
+            .unwrap()
+            .execute_batch(FIXTURE)
+            .unwrap();
+
+        let items = read_history(&path, &RuleSet::builtin(), &HistoryQuery::default()).unwrap();
+        assert_eq!(items.len(), 5);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_missing_database_is_error() {
+        let path = temp_path("does-not-exist.db");
+        match read_history(&path, &RuleSet::builtin(), &HistoryQuery::default()) {
+            Err(Error::Database { .. }) => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+}
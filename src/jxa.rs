@@ -0,0 +1,159 @@
+//! Read every Safari tab with a single JavaScript for Automation script.
+//!
+//! Asking AppleScript for each tab's URL spawns one `osascript` process
+//! per tab, which gets slow when you have hundreds of tabs open.  Instead,
+//! we run one JXA script that returns all the windows and tabs as JSON.
+
+use serde_json;
+
+use applescript::ScriptRunner;
+use error::Error;
+
+const ALL_TABS_SCRIPT: &str = include_str!("scripts/all-tabs.js");
+
+/// A Safari window, as reported by the JXA script.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct Window {
+    /// Window index.  1 is frontmost.
+    pub index: u32,
+    /// Safari's ID for this window, which doesn't change when windows are
+    /// reordered or closed.
+    #[serde(default)]
+    pub id: Option<u32>,
+    pub visible: bool,
+    #[serde(default)]
+    pub miniaturized: bool,
+    /// Index of the tab that's showing in this window, if any.
+    pub current_tab: Option<u32>,
+    pub tabs: Vec<Tab>,
+}
+
+/// A tab in a Safari window, as reported by the JXA script.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct Tab {
+    /// Tab index.  1 is leftmost.
+    pub index: u32,
+    /// Safari doesn't have a URL for some tabs, e.g. a blank new tab.
+    pub url: Option<String>,
+    pub title: Option<String>,
+    /// True if this is the current tab of its window.
+    #[serde(default)]
+    pub visible: bool,
+}
+
+/// The JXA script that prints every window and tab as JSON.
+pub fn all_tabs_script() -> &'static str {
+    ALL_TABS_SCRIPT
+}
+
+/// Parse the JSON printed by `all_tabs_script()`.
+pub fn parse_windows(json: &str) -> Result<Vec<Window>, Error> {
+    serde_json::from_str(json.trim())
+        .map_err(|e| Error::Script(format!("Unable to parse tabs from JXA script: {}", e)))
+}
+
+/// Get every window and tab in Safari, with a single script.
+pub fn get_all_windows(runner: &dyn ScriptRunner) -> Result<Vec<Window>, Error> {
+    let output = runner.run_javascript(all_tabs_script());
+    if !output.status.success() {
+        return Err(Error::Script(format!(
+            "Unexpected error from osascript: {:?}",
+            output.stderr
+        )));
+    }
+    parse_windows(&output.stdout)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use applescript::FakeRunner;
+    use error::Error;
+    use jxa::{all_tabs_script, get_all_windows, parse_windows, Tab};
+
+    fn fixture(name: &str) -> String {
+        let path = format!("{}/tests/fixtures/jxa/{}", env!("CARGO_MANIFEST_DIR"), name);
+        fs::read_to_string(path).unwrap()
+    }
+
+    #[test]
+    fn test_script_is_javascript() {
+        let script = all_tabs_script();
+        assert!(script.contains("function run()"));
+        assert!(script.contains("Application(\"Safari\")"));
+        assert!(script.contains("JSON.stringify"));
+    }
+
+    #[test]
+    fn test_parse_two_windows() {
+        let windows = parse_windows(&fixture("two-windows.json")).unwrap();
+        assert_eq!(windows.len(), 2);
+
+        assert_eq!(windows[0].index, 1);
+        assert_eq!(windows[0].id, Some(4721));
+        assert_eq!(windows[0].current_tab, Some(2));
+        assert!(!windows[0].miniaturized);
+        assert_eq!(windows[0].tabs.len(), 3);
+        assert_eq!(
+            windows[0].tabs[1],
+            Tab {
+                index: 2,
+                url: Some("https://mobile.twitter.com/alexwlchan".to_owned()),
+                title: Some("Alex Chan (@alexwlchan) / Twitter".to_owned()),
+                visible: true,
+            }
+        );
+
+        assert_eq!(windows[1].index, 2);
+        assert!(windows[1].miniaturized);
+        let urls: Vec<&str> = windows[1]
+            .tabs
+            .iter()
+            .map(|t| t.url.as_deref().unwrap())
+            .collect();
+        assert_eq!(
+            urls,
+            vec!["https://crates.io/crates/urlparse", "https://docs.rs/plist"]
+        );
+    }
+
+    #[test]
+    fn test_parse_unreadable_tabs() {
+        let windows = parse_windows(&fixture("unreadable-tabs.json")).unwrap();
+        assert_eq!(windows[0].tabs[0].url, None);
+        assert_eq!(windows[0].tabs[2].title, None);
+        assert_eq!(windows[1].current_tab, None);
+        assert!(windows[1].tabs.is_empty());
+    }
+
+    #[test]
+    fn test_parse_no_windows() {
+        assert_eq!(parse_windows(&fixture("no-windows.json")), Ok(vec![]));
+    }
+
+    #[test]
+    fn test_parse_bad_json_is_error() {
+        assert!(parse_windows("not json").is_err());
+        assert!(parse_windows("[{\"index\": \"one\"}]").is_err());
+    }
+
+    #[test]
+    fn test_get_all_windows_runs_one_script() {
+        let runner = FakeRunner::new(vec![FakeRunner::ok(&fixture("two-windows.json"))]);
+        let windows = get_all_windows(&runner).unwrap();
+        assert_eq!(windows.len(), 2);
+        assert_eq!(runner.scripts(), vec![all_tabs_script()]);
+    }
+
+    #[test]
+    fn test_get_all_windows_reports_errors() {
+        let runner = FakeRunner::new(vec![FakeRunner::err(
+            "execution error: Error: Error: Application isn't running. (-600)",
+        )]);
+        match get_all_windows(&runner) {
+            Err(Error::Script(e)) => assert!(e.contains("Application isn't running"), "{}", e),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,70 @@
+//! Read and tidy URLs from Safari.
+//!
+//! This is the library behind the `safari` command-line tool.  It has:
+//!
+//!   * [`rules`]: a URL tidier, which removes tracking junk from URLs
+//!   * [`urls`]: a resolver, which follows redirects to find where a URL
+//!     ends up, and [`cache`], which remembers the results
+//!   * [`safari`]: a controller for Safari, which reads and closes open
+//!     tabs, and readers for Reading List and iCloud Tabs
+//!   * [`session`]: save the tabs you have open, and reopen them later
+//!   * [`history`]: a reader for your browsing history in History.db
+//!   * [`bookmarks`]: a reader for the bookmarks in Bookmarks.plist, and
+//!     exporters for JSON, Markdown and Netscape bookmark files
+//!   * [`output`]: printers for lists of tabs, e.g. as JSON or Markdown
+//!   * [`paths`]: where to find Safari's data files, which you can point
+//!     at a backup or a copy of another user's files
+//!   * [`dates`]: helpers for the dates in Safari's plist files
+//!
+//! For example, to tidy a URL with the built-in rules:
+//!
+//! ```
+//! use safari::rules::RuleSet;
+//!
+//! let rules = RuleSet::builtin();
+//! assert_eq!(
+//!     rules.tidy("https://example.org/?utm_source=newsletter&id=1"),
+//!     "https://example.org/?id=1"
+//! );
+//! ```
+//!
+//! Reading tabs from Safari only works on macOS, but the functions that
+//! talk to Safari take an [`applescript::ScriptRunner`], so you can pass
+//! something else in tests.
+
+#![deny(warnings)]
+
+extern crate chrono;
+extern crate dirs;
+extern crate plist;
+extern crate regex;
+extern crate reqwest;
+extern crate rusqlite;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+extern crate tera;
+extern crate urlencoding;
+extern crate urlparse;
+
+pub mod applescript;
+pub mod bookmarks;
+pub mod cache;
+mod cloudtabs;
+pub mod dates;
+pub mod error;
+mod files;
+pub mod history;
+mod jxa;
+pub mod output;
+pub mod paths;
+mod plists;
+pub mod rules;
+pub mod safari;
+pub mod session;
+mod sqlite;
+#[cfg(test)]
+mod test_support;
+pub mod urls;
+
+pub use urls::tidy_url;
@@ -1,131 +1,569 @@
 #![deny(warnings)]
 
-extern crate dirs;
+extern crate chrono;
 extern crate docopt;
-extern crate plist;
-extern crate reqwest;
+extern crate safari as safari_rs;
 #[macro_use]
 extern crate serde_derive;
-extern crate tera;
-extern crate urlencoding;
-extern crate urlparse;
 
-use std::io::Write;
+use std::io::{self, BufRead, Write};
+use std::path::PathBuf;
 use std::process;
+use std::time::Duration;
+
+use chrono::{DateTime, UTC};
 
-mod applescript;
 mod cli;
-mod safari;
-mod urls;
+
+use safari_rs::error::Error;
+use safari_rs::{
+    applescript, bookmarks, cache, dates, history, output, paths, rules, safari, session, urls,
+};
 
 const NAME: &str = env!("CARGO_PKG_NAME");
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
-// http://stackoverflow.com/a/27590832/1558022
-macro_rules! error(
-  ($($arg:tt)*) => {
-    {
-      let r = writeln!(&mut ::std::io::stderr(), $($arg)*);
-      r.expect("failed printing to stderr");
-      process::exit(1);
-    }
-  }
-);
+/// Prints an error and exits, with the exit code for that kind of error.
+fn exit_with(e: Error) -> ! {
+    let r = writeln!(&mut ::std::io::stderr(), "{}", e);
+    r.expect("failed printing to stderr");
+    process::exit(e.exit_code());
+}
+
+/// Prints an error, but carries on.
+fn warn<E: std::fmt::Display>(e: E) {
+    let r = writeln!(&mut ::std::io::stderr(), "{}", e);
+    r.expect("failed printing to stderr");
+}
 
 /// Exits the program if Safari isn't running.
 fn assert_safari_is_running() {
-    if !safari::is_safari_running() {
-        error!("Safari is not running.");
+    if let Err(e) = safari::ensure_safari_is_running() {
+        exit_with(e);
+    }
+}
+
+/// Loads the URL tidying rules, or exits if the user's rules are broken.
+fn load_rules(args: &cli::Args) -> rules::RuleSet {
+    match rules::load(args.flag_rules.as_deref()) {
+        Ok(rules) => rules,
+        Err(e) => exit_with(e),
+    }
+}
+
+/// Parses the --format flag, or exits if it isn't a format we know.
+fn parse_format(args: &cli::Args) -> output::Format {
+    match args.flag_format.parse() {
+        Ok(format) => format,
+        Err(e) => exit_with(e),
+    }
+}
+
+/// Works out where to read Safari's files from, or exits if --safari-dir
+/// doesn't exist.
+fn load_paths(args: &cli::Args) -> paths::SafariPaths {
+    match paths::load(args.flag_safari_dir.as_deref()) {
+        Ok(paths) => paths,
+        Err(e) => exit_with(e),
+    }
+}
+
+/// Parses a date flag like --since, or exits if it isn't a date.
+fn parse_date_flag(flag: &Option<String>) -> Option<DateTime<UTC>> {
+    flag.as_ref().map(|s| match dates::parse_date(s) {
+        Ok(date) => date,
+        Err(e) => exit_with(e),
+    })
+}
+
+/// Builds the Reading List filters from --unread, --read, --sort and
+/// --since, or exits if they don't make sense.
+fn load_reading_list_query(args: &cli::Args) -> safari::ReadingListQuery {
+    let read = if args.flag_unread {
+        Some(false)
+    } else if args.flag_read {
+        Some(true)
+    } else {
+        None
+    };
+    let since = parse_date_flag(&args.flag_since);
+    let sort = args.flag_sort.as_ref().map(|s| match s.parse() {
+        Ok(order) => order,
+        Err(e) => exit_with(e),
+    });
+    safari::ReadingListQuery { read, since, sort }
+}
+
+/// Loads the --template flag, if set, or exits if the template is broken.
+fn load_template(args: &cli::Args) -> Option<output::Template> {
+    args.flag_template.as_ref().map(|value| {
+        match output::read_template(value).and_then(|source| output::Template::new(&source)) {
+            Ok(template) => template,
+            Err(e) => exit_with(e),
+        }
+    })
+}
+
+/// How to print the items from a listing command.
+enum Printer {
+    Format(output::Format),
+    Template(Box<output::Template>),
+}
+
+/// Works out how to print items from the --format and --template flags.
+/// We do this before talking to Safari, so mistakes are reported straight
+/// away.
+fn load_printer(args: &cli::Args) -> Printer {
+    match load_template(args) {
+        Some(template) => Printer::Template(Box::new(template)),
+        None => Printer::Format(parse_format(args)),
+    }
+}
+
+/// Prints a list of items, with the user's template if they passed one,
+/// or in the format chosen with --format.
+fn print_items(printer: &Printer, items: &[output::Item]) {
+    match *printer {
+        Printer::Format(format) => print!("{}", output::render(items, format)),
+        Printer::Template(ref template) => match template.render_all(items) {
+            Ok(rendered) => print!("{}", rendered),
+            Err(e) => exit_with(e),
+        },
+    }
+}
+
+/// Prints a single tab with the user's template.  Like `url` and `title`,
+/// there's no trailing newline.
+fn print_tab_with_template(
+    runner: &dyn applescript::ScriptRunner,
+    args: &cli::Args,
+    template: &output::Template,
+) {
+    let rules = load_rules(args);
+    let tab = match safari::get_tab(runner, args.flag_window, args.flag_tab) {
+        Ok(tab) => tab,
+        Err(e) => exit_with(e),
+    };
+    let item = match tab.url {
+        Ok(url) => output::Item {
+            url: rules.tidy(&url),
+            title: tab.title,
+            window: Some(tab.window),
+            tab: Some(tab.index),
+            ..output::Item::default()
+        },
+        Err(e) => exit_with(e),
+    };
+    match template.render(&item) {
+        Ok(rendered) => print!("{}", rendered),
+        Err(e) => exit_with(e),
+    }
+}
+
+/// Prints every hop in a redirect chain, one per line.
+fn print_chain(chain: &[urls::Hop]) {
+    for hop in chain {
+        match hop.html_redirect {
+            Some(kind) => println!("{} {} {} ({})", hop.status, hop.method, hop.url, kind),
+            None => println!("{} {} {}", hop.status, hop.method, hop.url),
+        }
+    }
+}
+
+/// Reads newline-delimited URLs from stdin, skipping blank lines.
+fn read_urls_from_stdin() -> Result<Vec<String>, Error> {
+    let stdin = io::stdin();
+    let mut urls = vec![];
+    for line in stdin.lock().lines() {
+        match line {
+            Ok(line) => {
+                if !line.trim().is_empty() {
+                    urls.push(line.trim().to_owned());
+                }
+            }
+            Err(e) => {
+                return Err(Error::Io {
+                    path: PathBuf::from("<stdin>"),
+                    reason: format!("Unable to read URLs: {}", e),
+                })
+            }
+        }
     }
+    Ok(urls)
 }
 
 fn main() {
     let args = cli::parse_args(NAME);
+    let runner = applescript::Osascript;
 
     if args.flag_version {
         println!("{}.rs v{}", NAME, VERSION);
     }
 
+    if args.cmd_url || args.cmd_title {
+        if let Some(template) = load_template(&args) {
+            assert_safari_is_running();
+            print_tab_with_template(&runner, &args, &template);
+            return;
+        }
+    }
+
     if args.cmd_url {
         assert_safari_is_running();
-        match safari::get_url(args.flag_window, args.flag_tab) {
+        let rules = load_rules(&args);
+        match safari::get_url(&runner, args.flag_window, args.flag_tab, &rules) {
             Ok(url) => print!("{}", url),
-            Err(e) => error!("{}", e),
+            Err(e) => exit_with(e),
         };
     }
 
     if args.cmd_title {
         assert_safari_is_running();
-        match safari::get_title(args.flag_window, args.flag_tab) {
+        match safari::get_title(&runner, args.flag_window, args.flag_tab) {
             Ok(url) => print!("{}", url),
-            Err(e) => error!("{}", e),
+            Err(e) => exit_with(e),
         };
     }
 
     if args.cmd_resolve {
-        print!("{}", urls::resolve(&args.arg_url));
+        // Pages that redirect with HTML tend to be newsletters and link
+        // shorteners, which add tracking junk, so tidy wherever they go.
+        let reads_html = args.flag_meta_refresh || args.flag_canonical;
+        let options = urls::ResolveOptions {
+            max_redirects: args.flag_max_redirects,
+            timeout: match args.flag_timeout {
+                0 => None,
+                seconds => Some(Duration::from_secs(seconds)),
+            },
+            follow_meta_refresh: args.flag_meta_refresh,
+            prefer_canonical: args.flag_canonical,
+            tidy: if reads_html {
+                Some(load_rules(&args))
+            } else {
+                None
+            },
+            ..urls::ResolveOptions::default()
+        };
+        let client = match urls::build_client(&options) {
+            Ok(client) => client,
+            Err(e) => exit_with(e.into()),
+        };
+
+        let mut cache = if args.flag_no_cache {
+            None
+        } else {
+            let ttl = match args.flag_cache_ttl.checked_mul(24 * 60 * 60) {
+                Some(seconds) => Duration::from_secs(seconds),
+                None => exit_with(Error::Usage(format!(
+                    "--cache-ttl is too large: {} days",
+                    args.flag_cache_ttl
+                ))),
+            };
+            cache::default_cache_path().map(|path| cache::ResolveCache::open(path, ttl))
+        };
+
+        let to_resolve = if args.arg_urls.is_empty() {
+            match read_urls_from_stdin() {
+                Ok(urls) => urls,
+                Err(e) => exit_with(e),
+            }
+        } else {
+            args.arg_urls.clone()
+        };
+
+        let results = match cache {
+            Some(ref mut cache) => {
+                cache.resolve_all(&client, &to_resolve, &options, args.flag_jobs)
+            }
+            None => urls::resolve_all(&client, &to_resolve, &options, args.flag_jobs),
+        };
+
+        // The cache is only there to save time, so if we can't write it,
+        // warn but carry on.
+        if let Some(ref mut cache) = cache {
+            if let Err(e) = cache.save() {
+                warn(e);
+            }
+        }
+
+        // A single URL on the command line gets printed without a trailing
+        // newline, the same as `url` and `tidy-url`.
+        if args.arg_urls.len() == 1 {
+            match results.into_iter().next().unwrap() {
+                Ok(resolution) => {
+                    if args.flag_verbose {
+                        print_chain(&resolution.chain);
+                    } else {
+                        print!("{}", resolution.url);
+                    }
+                }
+                Err(e) => exit_with(e.into()),
+            };
+        } else {
+            // Print one line per URL, so the output lines up with the input.
+            // If we can't resolve a URL, we print it unchanged and report
+            // the error on stderr.
+            let mut failure = None;
+            for (url, result) in to_resolve.iter().zip(results) {
+                match result {
+                    Ok(resolution) => {
+                        if args.flag_verbose {
+                            print_chain(&resolution.chain);
+                            println!();
+                        } else {
+                            println!("{}", resolution.url);
+                        }
+                    }
+                    Err(e) => {
+                        warn(&e);
+                        failure = Some(Error::from(e));
+                        if !args.flag_verbose {
+                            println!("{}", url);
+                        }
+                    }
+                }
+            }
+
+            if let Some(e) = failure {
+                process::exit(e.exit_code());
+            }
+        }
     }
 
     if args.cmd_list_tabs {
         assert_safari_is_running();
-        for url in safari::get_all_urls() {
-            println!("{}", url);
+        let rules = load_rules(&args);
+        let printer = load_printer(&args);
+        let tabs = match safari::get_all_tabs(&runner) {
+            Ok(tabs) => tabs,
+            Err(e) => exit_with(e),
+        };
+        let mut items = vec![];
+        for tab in tabs {
+            match tab.url {
+                Ok(url) => items.push(output::Item {
+                    url: rules.tidy(&url),
+                    title: tab.title,
+                    window: Some(tab.window),
+                    tab: Some(tab.index),
+                    ..output::Item::default()
+                }),
+                Err(e) => warn(e),
+            }
         }
+        print_items(&printer, &items);
     }
 
     if args.cmd_close_tabs {
         assert_safari_is_running();
         let patterns = args.arg_urls_to_close.split(",").collect();
-        safari::close_tabs(patterns);
+        if let Err(e) = safari::close_tabs(&runner, patterns) {
+            exit_with(e);
+        }
     }
 
     if args.cmd_reading_list {
-        match safari::get_reading_list_urls() {
-            Ok(urls) => {
-                for url in urls {
-                    println!("{}", url);
-                }
-            }
-            Err(e) => error!("{}", e),
+        let paths = load_paths(&args);
+        let rules = load_rules(&args);
+        let query = load_reading_list_query(&args);
+        let printer = load_printer(&args);
+        let items: Vec<output::Item> = match safari::get_reading_list(&paths, &rules) {
+            Ok(reading_list) => query
+                .apply(reading_list)
+                .into_iter()
+                .map(|item| output::Item {
+                    url: item.url,
+                    title: item.title,
+                    date_added: item.date_added.as_ref().map(dates::format_date),
+                    date_last_viewed: item.date_last_viewed.as_ref().map(dates::format_date),
+                    preview_text: item.preview_text,
+                    image_url: item.image_url,
+                    ..output::Item::default()
+                })
+                .collect(),
+            Err(e) => exit_with(e),
         };
+        print_items(&printer, &items);
     }
 
     if args.cmd_icloud_tabs {
+        let paths = load_paths(&args);
+        let rules = load_rules(&args);
+        let devices = match safari::get_icloud_devices(&paths, &rules) {
+            Ok(devices) => devices,
+            Err(e) => exit_with(e),
+        };
         if args.flag_list_devices {
-            match safari::list_icloud_tabs_devices() {
-                Ok(devices) => {
-                    for device in devices {
-                        println!("{}", device);
-                    }
+            let now = UTC::now();
+            for device in devices {
+                if !args.flag_verbose {
+                    println!("{}", device.name);
+                    continue;
                 }
-                Err(e) => error!("{}", e),
-            };
+                match device.last_modified {
+                    Some(date) => println!(
+                        "{} ({}), updated {}",
+                        device.name,
+                        device.uuid,
+                        dates::describe_age(&date, &now)
+                    ),
+                    None => println!("{} ({})", device.name, device.uuid),
+                }
+            }
         } else {
-            let tab_data = match safari::get_icloud_tabs_urls() {
-                Ok(tab_data) => tab_data,
-                Err(e) => error!("{}", e),
+            let printer = load_printer(&args);
+            let items: Vec<output::Item> = devices
+                .into_iter()
+                .filter(|device| match args.flag_device {
+                    Some(ref d) => &device.name == d || &device.uuid == d,
+                    None => true,
+                })
+                .flat_map(|device| {
+                    let name = device.name;
+                    device.tabs.into_iter().map(move |tab| output::Item {
+                        url: tab.url,
+                        title: tab.title,
+                        device: Some(name.clone()),
+                        tab: Some(tab.index),
+                        ..output::Item::default()
+                    })
+                })
+                .collect();
+            print_items(&printer, &items);
+        }
+    }
+
+    if args.cmd_history {
+        let paths = load_paths(&args);
+        let rules = load_rules(&args);
+        let query = history::HistoryQuery {
+            since: parse_date_flag(&args.flag_since),
+            until: parse_date_flag(&args.flag_until),
+            domain: args.flag_domain.clone(),
+            search: args.flag_search.clone(),
+            dedupe: args.flag_dedupe,
+        };
+        let printer = load_printer(&args);
+        let items: Vec<output::Item> = match history::get_history(&paths, &rules, &query) {
+            Ok(items) => items
+                .into_iter()
+                .map(|item| output::Item {
+                    url: item.url,
+                    title: item.title,
+                    visit_count: Some(item.visit_count),
+                    last_visited: item.last_visited.as_ref().map(dates::format_date),
+                    ..output::Item::default()
+                })
+                .collect(),
+            Err(e) => exit_with(e),
+        };
+        print_items(&printer, &items);
+    }
+
+    if args.cmd_bookmarks {
+        let paths = load_paths(&args);
+        let printer = load_printer(&args);
+        let root = match bookmarks::get_bookmarks(&paths) {
+            Ok(root) => root,
+            Err(e) => exit_with(e),
+        };
+        let folder = match args.flag_folder {
+            Some(ref path) => match root.find(path) {
+                Some(folder) => folder,
+                None => exit_with(Error::Usage(format!("No bookmarks folder {}", path))),
+            },
+            None => &root,
+        };
+
+        // The tree-shaped formats keep the folder structure; the others get
+        // a flat list of bookmarks, with the folder each one is in.
+        match printer {
+            Printer::Format(output::Format::Plain) => print!("{}", bookmarks::to_tree(folder)),
+            Printer::Format(output::Format::Json) => print!("{}", bookmarks::to_json(folder)),
+            Printer::Format(output::Format::Markdown) => {
+                print!("{}", bookmarks::to_markdown(folder))
+            }
+            Printer::Format(output::Format::Html) => {
+                print!("{}", bookmarks::to_netscape_html(folder))
+            }
+            _ => print_items(&printer, &folder.items()),
+        }
+    }
+
+    if args.cmd_session {
+        let store = match session::SessionStore::open_default() {
+            Ok(store) => store,
+            Err(e) => exit_with(e),
+        };
+
+        if args.cmd_save {
+            assert_safari_is_running();
+            let session = match session::capture(&runner, &args.arg_session_name, &UTC::now()) {
+                Ok(session) => session,
+                Err(e) => exit_with(e),
             };
-            match args.flag_device {
-                Some(d) => match tab_data.get(&d) {
-                    Some(urls) => {
-                        for url in urls {
-                            println!("{}", url);
-                        }
-                    }
-                    None => (),
-                },
-                None => {
-                    for urls in tab_data.values() {
-                        for url in urls {
-                            println!("{}", url);
-                        }
+            match store.save(&session) {
+                Ok(path) => println!(
+                    "Saved {} windows and {} tabs to {}",
+                    session.windows.len(),
+                    session.tab_count(),
+                    path.display()
+                ),
+                Err(e) => exit_with(e),
+            }
+        }
+
+        if args.cmd_restore {
+            let session = match store.load(&args.arg_session_name) {
+                Ok(session) => session,
+                Err(e) => exit_with(e),
+            };
+            if let Err(e) = session::restore(&runner, &session) {
+                exit_with(e);
+            }
+        }
+
+        if args.cmd_list {
+            let names = match store.list() {
+                Ok(names) => names,
+                Err(e) => exit_with(e),
+            };
+            let now = UTC::now();
+            for name in names {
+                // One broken file shouldn't hide every other session.
+                match store.load(&name) {
+                    Ok(session) => {
+                        let age = dates::parse_date(&session.saved_at)
+                            .map(|date| format!(", saved {}", dates::describe_age(&date, &now)))
+                            .unwrap_or_default();
+                        println!(
+                            "{} ({} windows, {} tabs{})",
+                            name,
+                            session.windows.len(),
+                            session.tab_count(),
+                            age
+                        );
                     }
+                    Err(e) => println!("{} ({})", name, e),
                 }
             }
         }
+
+        if args.cmd_show {
+            let printer = load_printer(&args);
+            match store.load(&args.arg_session_name) {
+                Ok(session) => print_items(&printer, &session.items()),
+                Err(e) => exit_with(e),
+            }
+        }
     }
 
     if args.cmd_tidy_url {
-        print!("{}", urls::tidy_url(&args.arg_url));
+        let rules = load_rules(&args);
+        if args.flag_explain {
+            println!("{}", rules.explain(&args.arg_url));
+        } else {
+            print!("{}", rules.tidy(&args.arg_url));
+        }
     }
 }
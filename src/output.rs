@@ -0,0 +1,510 @@
+//! Print lists of tabs and Reading List items in different formats, or
+//! with a user-defined tera template.
+
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use serde_json;
+use tera::{Context, Tera};
+
+use error::Error;
+
+/// Something to print from one of the listing commands, e.g. a tab or a
+/// Reading List item.  Fields are None if the source doesn't have them.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Item {
+    pub url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub folder: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub window: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tab: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub date_added: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub date_last_viewed: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preview_text: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub image_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub visit_count: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_visited: Option<String>,
+}
+
+/// The fields of an Item, in the order we print them as columns.
+const FIELDS: [&str; 12] = [
+    "url",
+    "title",
+    "folder",
+    "device",
+    "window",
+    "tab",
+    "date_added",
+    "date_last_viewed",
+    "preview_text",
+    "image_url",
+    "visit_count",
+    "last_visited",
+];
+
+impl Item {
+    fn field(&self, name: &str) -> Option<String> {
+        match name {
+            "url" => Some(self.url.clone()),
+            "title" => self.title.clone(),
+            "folder" => self.folder.clone(),
+            "device" => self.device.clone(),
+            "window" => self.window.map(|w| w.to_string()),
+            "tab" => self.tab.map(|t| t.to_string()),
+            "date_added" => self.date_added.clone(),
+            "date_last_viewed" => self.date_last_viewed.clone(),
+            "preview_text" => self.preview_text.clone(),
+            "image_url" => self.image_url.clone(),
+            "visit_count" => self.visit_count.map(|c| c.to_string()),
+            "last_visited" => self.last_visited.clone(),
+            _ => None,
+        }
+    }
+}
+
+/// How to print a list of items.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    /// One URL per line
+    Plain,
+    Json,
+    /// One JSON object per line
+    JsonLines,
+    Csv,
+    Tsv,
+    /// A Markdown list of links
+    Markdown,
+    /// An HTML list of links
+    Html,
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Format, Error> {
+        match s.to_lowercase().as_str() {
+            "plain" => Ok(Format::Plain),
+            "json" => Ok(Format::Json),
+            "jsonl" | "json-lines" => Ok(Format::JsonLines),
+            "csv" => Ok(Format::Csv),
+            "tsv" => Ok(Format::Tsv),
+            "markdown" | "md" => Ok(Format::Markdown),
+            "html" => Ok(Format::Html),
+            _ => Err(Error::Usage(format!(
+                "Unrecognised format {:?}; expected one of \
+                 plain, json, jsonl, csv, tsv, markdown, html",
+                s
+            ))),
+        }
+    }
+}
+
+/// Render a list of items in the given format.
+pub fn render(items: &[Item], format: Format) -> String {
+    match format {
+        Format::Plain => items.iter().map(|i| format!("{}\n", i.url)).collect(),
+        Format::Json => {
+            if items.is_empty() {
+                "[]\n".to_owned()
+            } else {
+                format!("{}\n", serde_json::to_string_pretty(items).unwrap())
+            }
+        }
+        Format::JsonLines => items
+            .iter()
+            .map(|i| format!("{}\n", serde_json::to_string(i).unwrap()))
+            .collect(),
+        Format::Csv => render_table(items, ",", csv_escape),
+        Format::Tsv => render_table(items, "\t", tsv_escape),
+        Format::Markdown => items.iter().map(markdown_link).collect(),
+        Format::Html => render_html(items),
+    }
+}
+
+/// Render items as a table with a header row.  We only include columns
+/// that at least one item has a value for.
+fn render_table(items: &[Item], separator: &str, escape: fn(&str) -> String) -> String {
+    let columns: Vec<&str> = FIELDS
+        .iter()
+        .cloned()
+        .filter(|f| *f == "url" || items.iter().any(|i| i.field(f).is_some()))
+        .collect();
+
+    let mut out = format!("{}\n", columns.join(separator));
+    for item in items {
+        let row: Vec<String> = columns
+            .iter()
+            .map(|c| escape(&item.field(c).unwrap_or_default()))
+            .collect();
+        out.push_str(&row.join(separator));
+        out.push('\n');
+    }
+    out
+}
+
+/// Quote a CSV field if it contains anything special, as in RFC 4180.
+fn csv_escape(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_owned()
+    }
+}
+
+/// TSV doesn't have any quoting, so replace tabs and newlines with spaces.
+fn tsv_escape(value: &str) -> String {
+    value.replace(['\t', '\n', '\r'], " ")
+}
+
+pub(crate) fn markdown_link(item: &Item) -> String {
+    // Markdown doesn't allow spaces in link destinations, and an unbalanced
+    // parenthesis would end an inline link early.
+    let url = item.url.replace(' ', "%20");
+    match item.title {
+        Some(ref title) if !title.trim().is_empty() => {
+            let title = title
+                .trim()
+                .replace('\\', "\\\\")
+                .replace('[', "\\[")
+                .replace(']', "\\]");
+            let url = url.replace('(', "%28").replace(')', "%29");
+            format!("- [{}]({})\n", title, url)
+        }
+        _ => format!("- <{}>\n", url),
+    }
+}
+
+pub(crate) fn html_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn render_html(items: &[Item]) -> String {
+    let mut out = "<ul>\n".to_owned();
+    for item in items {
+        let text = match item.title {
+            Some(ref title) if !title.trim().is_empty() => title.trim(),
+            _ => &item.url,
+        };
+        out.push_str(&format!(
+            "  <li><a href=\"{}\">{}</a></li>\n",
+            html_escape(&item.url),
+            html_escape(text)
+        ));
+    }
+    out.push_str("</ul>\n");
+    out
+}
+
+/// A user-defined template for printing items, rendered with tera.
+///
+/// Templates can use the `url`, `title`, `folder`, `device`, `window`,
+/// `tab`, `date_added`, `date_last_viewed`, `preview_text`, `image_url`,
+/// `visit_count` and `last_visited` variables.  Any that don't apply to an
+/// item are null, so you can test them with `{% if title %}`.
+pub struct Template {
+    tera: Tera,
+}
+
+impl Template {
+    /// Compile a template, so we find syntax errors before printing
+    /// anything.
+    pub fn new(source: &str) -> Result<Template, Error> {
+        let mut tera = Tera::default();
+        match tera.add_raw_template("item", source) {
+            Ok(()) => Ok(Template { tera }),
+            Err(e) => Err(Error::Usage(format!(
+                "Unable to parse template: {}",
+                tera_error(&e)
+            ))),
+        }
+    }
+
+    /// Render the template for a single item.
+    pub fn render(&self, item: &Item) -> Result<String, Error> {
+        let mut context = Context::new();
+        context.add("url", &item.url);
+        context.add("title", &item.title);
+        context.add("folder", &item.folder);
+        context.add("device", &item.device);
+        context.add("window", &item.window);
+        context.add("tab", &item.tab);
+        context.add("date_added", &item.date_added);
+        context.add("date_last_viewed", &item.date_last_viewed);
+        context.add("preview_text", &item.preview_text);
+        context.add("image_url", &item.image_url);
+        context.add("visit_count", &item.visit_count);
+        context.add("last_visited", &item.last_visited);
+
+        self.tera
+            .render("item", &context)
+            .map_err(|e| Error::Usage(format!("Unable to render template: {}", tera_error(&e))))
+    }
+
+    /// Render the template for every item, one per line.
+    pub fn render_all(&self, items: &[Item]) -> Result<String, Error> {
+        let mut out = String::new();
+        for item in items {
+            out.push_str(&self.render(item)?);
+            out.push('\n');
+        }
+        Ok(out)
+    }
+}
+
+/// tera wraps the useful part of an error (e.g. which variable was
+/// missing) inside a generic "Failed to render" error, so print them all.
+fn tera_error(e: &::tera::Error) -> String {
+    e.iter()
+        .map(|cause| cause.to_string())
+        .collect::<Vec<String>>()
+        .join(": ")
+}
+
+/// Get the source of a template from the --template flag, which is either
+/// the path to a template file or the template itself.
+pub fn read_template(value: &str) -> Result<String, Error> {
+    if Path::new(value).is_file() {
+        fs::read_to_string(value).map_err(|e| Error::Io {
+            path: value.into(),
+            reason: format!("Unable to read template file: {}", e),
+        })
+    } else {
+        Ok(value.to_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use error::Error;
+    use output::{read_template, render, Format, Item, Template};
+    use test_support::temp_path;
+
+    fn items() -> Vec<Item> {
+        vec![
+            Item {
+                url: "https://example.org/".to_owned(),
+                title: Some("Example Domain".to_owned()),
+                window: Some(1),
+                tab: Some(1),
+                ..Item::default()
+            },
+            Item {
+                url: "https://example.org/search?q=a,b".to_owned(),
+                title: Some("Results for \"a, b\" [page 1]".to_owned()),
+                window: Some(1),
+                tab: Some(2),
+                ..Item::default()
+            },
+            Item {
+                url: "https://example.org/wiki/Rust_(language)".to_owned(),
+                window: Some(2),
+                tab: Some(1),
+                ..Item::default()
+            },
+        ]
+    }
+
+    #[test]
+    fn test_parse_format() {
+        assert_eq!("json".parse(), Ok(Format::Json));
+        assert_eq!("JSONL".parse(), Ok(Format::JsonLines));
+        assert_eq!("md".parse(), Ok(Format::Markdown));
+        assert!("yaml".parse::<Format>().is_err());
+    }
+
+    #[test]
+    fn test_plain() {
+        assert_eq!(
+            render(&items(), Format::Plain),
+            concat!(
+                "https://example.org/\n",
+                "https://example.org/search?q=a,b\n",
+                "https://example.org/wiki/Rust_(language)\n",
+            )
+        );
+        assert_eq!(render(&[], Format::Plain), "");
+    }
+
+    #[test]
+    fn test_json() {
+        let json = render(&items()[..1], Format::Json);
+        assert_eq!(
+            json,
+            concat!(
+                "[\n",
+                "  {\n",
+                "    \"url\": \"https://example.org/\",\n",
+                "    \"title\": \"Example Domain\",\n",
+                "    \"window\": 1,\n",
+                "    \"tab\": 1\n",
+                "  }\n",
+                "]\n",
+            )
+        );
+        assert_eq!(render(&[], Format::Json), "[]\n");
+    }
+
+    #[test]
+    fn test_json_lines() {
+        let jsonl = render(&items(), Format::JsonLines);
+        let lines: Vec<&str> = jsonl.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(
+            lines[2],
+            r#"{"url":"https://example.org/wiki/Rust_(language)","window":2,"tab":1}"#
+        );
+    }
+
+    #[test]
+    fn test_csv() {
+        assert_eq!(
+            render(&items(), Format::Csv),
+            concat!(
+                "url,title,window,tab\n",
+                "https://example.org/,Example Domain,1,1\n",
+                "\"https://example.org/search?q=a,b\",\"Results for \"\"a, b\"\" [page 1]\",1,2\n",
+                "https://example.org/wiki/Rust_(language),,2,1\n",
+            )
+        );
+    }
+
+    #[test]
+    fn test_csv_only_has_url_column_for_bare_urls() {
+        let items = vec![Item {
+            url: "https://example.org/".to_owned(),
+            ..Item::default()
+        }];
+        assert_eq!(render(&items, Format::Csv), "url\nhttps://example.org/\n");
+    }
+
+    #[test]
+    fn test_tsv() {
+        let items = vec![Item {
+            url: "https://example.org/".to_owned(),
+            title: Some("Tabs\tand\nnewlines".to_owned()),
+            device: Some("Alex's iPhone".to_owned()),
+            ..Item::default()
+        }];
+        assert_eq!(
+            render(&items, Format::Tsv),
+            "url\ttitle\tdevice\nhttps://example.org/\tTabs and newlines\tAlex's iPhone\n"
+        );
+    }
+
+    #[test]
+    fn test_markdown() {
+        assert_eq!(
+            render(&items(), Format::Markdown),
+            concat!(
+                "- [Example Domain](https://example.org/)\n",
+                "- [Results for \"a, b\" \\[page 1\\]](https://example.org/search?q=a,b)\n",
+                "- <https://example.org/wiki/Rust_(language)>\n",
+            )
+        );
+    }
+
+    #[test]
+    fn test_html() {
+        assert_eq!(
+            render(&items(), Format::Html),
+            concat!(
+                "<ul>\n",
+                "  <li><a href=\"https://example.org/\">Example Domain</a></li>\n",
+                "  <li><a href=\"https://example.org/search?q=a,b\">",
+                "Results for &quot;a, b&quot; [page 1]</a></li>\n",
+                "  <li><a href=\"https://example.org/wiki/Rust_(language)\">",
+                "https://example.org/wiki/Rust_(language)</a></li>\n",
+                "</ul>\n",
+            )
+        );
+    }
+
+    #[test]
+    fn test_template() {
+        let template = Template::new("[{{ title }}]({{ url }})").unwrap();
+        assert_eq!(
+            template.render(&items()[0]),
+            Ok("[Example Domain](https://example.org/)".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_template_can_check_for_missing_fields() {
+        let template = Template::new(
+            "{% if title %}[[{{ url }}][{{ title }}]]{% else %}[[{{ url }}]]{% endif %}",
+        )
+        .unwrap();
+        assert_eq!(
+            template.render_all(&items()[1..]),
+            Ok(concat!(
+                "[[https://example.org/search?q=a,b][Results for \"a, b\" [page 1]]]\n",
+                "[[https://example.org/wiki/Rust_(language)]]\n",
+            )
+            .to_owned())
+        );
+    }
+
+    #[test]
+    fn test_template_numbers_and_devices() {
+        let template = Template::new("{{ window }}.{{ tab }} {{ device }}").unwrap();
+        let item = Item {
+            url: "https://example.org/".to_owned(),
+            window: Some(2),
+            tab: Some(3),
+            device: Some("Alex's iPhone".to_owned()),
+            ..Item::default()
+        };
+        assert_eq!(template.render(&item), Ok("2.3 Alex's iPhone".to_owned()));
+    }
+
+    #[test]
+    fn test_template_syntax_error() {
+        match Template::new("{{ url ") {
+            Err(Error::Usage(e)) => assert!(e.starts_with("Unable to parse template"), "{}", e),
+            Err(e) => panic!("Unexpected error: {:?}", e),
+            Ok(_) => panic!("Expected a syntax error"),
+        }
+    }
+
+    #[test]
+    fn test_template_unknown_variable_is_error() {
+        let template = Template::new("{{ author }}").unwrap();
+        match template.render(&items()[0]) {
+            Err(Error::Usage(e)) => assert!(e.contains("author"), "{}", e),
+            Err(e) => panic!("Unexpected error: {:?}", e),
+            Ok(s) => panic!("Unexpected success: {}", s),
+        }
+    }
+
+    #[test]
+    fn test_read_template() {
+        assert_eq!(read_template("{{ url }}"), Ok("{{ url }}".to_owned()));
+
+        let path = temp_path("template.txt");
+        fs::write(&path, "- {{ url }}").unwrap();
+        assert_eq!(
+            read_template(path.to_str().unwrap()),
+            Ok("- {{ url }}".to_owned())
+        );
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,155 @@
+//! Where to find Safari's data files.
+//!
+//! By default we read the files in the current user's Library folder, but
+//! you can point safari.rs at another directory, e.g. a backup, a Time
+//! Machine snapshot, or a folder of test fixtures.
+
+use std::env;
+use std::path::{Path, PathBuf};
+
+use dirs;
+
+use error::Error;
+
+/// The environment variable for choosing a Safari data directory.
+pub const SAFARI_DIR_ENV_VAR: &str = "SAFARI_RS_SAFARI_DIR";
+
+/// The paths of the Safari files that safari.rs reads.  Each field can be
+/// changed on its own, if the files aren't all in the same place.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SafariPaths {
+    /// Bookmarks and Reading List.
+    pub bookmarks: PathBuf,
+    /// Browsing history.
+    pub history: PathBuf,
+    /// iCloud Tabs, in newer versions of Safari.
+    pub cloud_tabs_db: PathBuf,
+    /// iCloud Tabs, in older versions of Safari.
+    pub synced_preferences: PathBuf,
+}
+
+impl SafariPaths {
+    /// The files Safari uses in a user's Library folder, e.g. `~/Library`.
+    pub fn in_library(library: &Path) -> SafariPaths {
+        SafariPaths {
+            bookmarks: library.join("Safari/Bookmarks.plist"),
+            history: library.join("Safari/History.db"),
+            cloud_tabs_db: library
+                .join("Containers/com.apple.Safari/Data/Library/Safari/CloudTabs.db"),
+            synced_preferences: library.join("SyncedPreferences/com.apple.Safari.plist"),
+        }
+    }
+
+    /// Every file in a single directory, e.g. a copy of `~/Library/Safari`
+    /// with CloudTabs.db and com.apple.Safari.plist copied in alongside.
+    pub fn in_dir(dir: &Path) -> SafariPaths {
+        SafariPaths {
+            bookmarks: dir.join("Bookmarks.plist"),
+            history: dir.join("History.db"),
+            cloud_tabs_db: dir.join("CloudTabs.db"),
+            synced_preferences: dir.join("com.apple.Safari.plist"),
+        }
+    }
+
+    /// The files in the current user's Library folder.
+    pub fn for_current_user() -> Result<SafariPaths, Error> {
+        match dirs::home_dir() {
+            Some(home) => Ok(SafariPaths::in_library(&home.join("Library"))),
+            None => Err(Error::Io {
+                path: PathBuf::from("~/Library"),
+                reason: "Unable to get home directory".to_owned(),
+            }),
+        }
+    }
+}
+
+/// Choose where to read Safari's files from.
+///
+/// The directory is taken from (in order of preference):
+///
+///   1. the `dir` argument, e.g. from the `--safari-dir` flag
+///   2. the `SAFARI_RS_SAFARI_DIR` environment variable
+///   3. the current user's Library folder
+///
+/// The directory can be a Library folder, e.g. `/Volumes/Backup/Library`,
+/// in which case we look in its `Safari` and `Containers` subdirectories;
+/// otherwise we expect every file to be in the directory itself.
+///
+/// It's an error if a directory named by (1) or (2) doesn't exist.
+///
+pub fn load(dir: Option<&str>) -> Result<SafariPaths, Error> {
+    let explicit_dir = match dir {
+        Some(d) => Some(PathBuf::from(d)),
+        None => env::var_os(SAFARI_DIR_ENV_VAR).map(PathBuf::from),
+    };
+
+    match explicit_dir {
+        Some(ref d) if !d.is_dir() => Err(Error::Io {
+            path: d.to_owned(),
+            reason: "No such directory".to_owned(),
+        }),
+        Some(ref d) if d.join("Safari").is_dir() => Ok(SafariPaths::in_library(d)),
+        Some(d) => Ok(SafariPaths::in_dir(&d)),
+        None => SafariPaths::for_current_user(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    use error::Error;
+    use paths::{load, SafariPaths};
+    use test_support::temp_path;
+
+    #[test]
+    fn test_in_library() {
+        let paths = SafariPaths::in_library(Path::new("/Users/alex/Library"));
+        assert_eq!(
+            paths.bookmarks,
+            PathBuf::from("/Users/alex/Library/Safari/Bookmarks.plist")
+        );
+        assert_eq!(
+            paths.synced_preferences,
+            PathBuf::from("/Users/alex/Library/SyncedPreferences/com.apple.Safari.plist")
+        );
+    }
+
+    #[test]
+    fn test_load_explicit_dir() {
+        let dir = env::temp_dir();
+        assert_eq!(
+            load(Some(dir.to_str().unwrap())),
+            Ok(SafariPaths::in_dir(&dir))
+        );
+        assert_eq!(
+            load(Some(dir.to_str().unwrap())).unwrap().history,
+            dir.join("History.db")
+        );
+    }
+
+    #[test]
+    fn test_load_library_dir() {
+        let library = temp_path("library");
+        fs::create_dir_all(library.join("Safari")).unwrap();
+        fs::create_dir_all(library.join("Containers")).unwrap();
+
+        let paths = load(Some(library.to_str().unwrap())).unwrap();
+        assert_eq!(paths, SafariPaths::in_library(&library));
+        assert_eq!(
+            paths.cloud_tabs_db,
+            library.join("Containers/com.apple.Safari/Data/Library/Safari/CloudTabs.db")
+        );
+        fs::remove_dir_all(&library).unwrap();
+    }
+
+    #[test]
+    fn test_load_missing_dir_is_error() {
+        match load(Some("/does/not/exist")) {
+            Err(Error::Io { path, .. }) => assert_eq!(path, PathBuf::from("/does/not/exist")),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,285 @@
+//! Helpers for reading Safari's plist files.
+//!
+//! Safari doesn't document the shape of its plist files, so we can't
+//! assume every key we want is there.  A `Node` is a value in a plist,
+//! plus the path of keys we followed to get there, so if something isn't
+//! the shape we expect, the error can say where.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{Cursor, Read};
+use std::path::Path;
+
+use chrono::{DateTime, UTC};
+use plist::Plist;
+
+use error::Error;
+
+/// Open and parse a plist file.
+pub fn read(path: &Path) -> Result<Plist, Error> {
+    let mut data = vec![];
+    if let Err(e) = File::open(path).and_then(|mut f| f.read_to_end(&mut data)) {
+        return Err(Error::PlistMissing {
+            path: path.to_owned(),
+            reason: e.to_string(),
+        });
+    }
+
+    let malformed = |reason: String| Error::PlistMalformed {
+        path: path.to_owned(),
+        key_path: "(root)".to_owned(),
+        reason,
+    };
+    check_binary_trailer(&data).map_err(|e| malformed(e.to_owned()))?;
+    Plist::read(Cursor::new(data)).map_err(|e| malformed(format!("not a valid plist: {:?}", e)))
+}
+
+/// The plist crate trusts the sizes in the trailer of a binary plist, and
+/// aborts if they're nonsense, e.g. because the file is truncated.  Check
+/// they fit inside the file before we parse it.
+///
+/// The trailer is the last 32 bytes of the file: 6 unused bytes, the size
+/// of each offset and object reference, then the number of objects, the
+/// top object and the offset of the offset table as big-endian u64s.
+fn check_binary_trailer(data: &[u8]) -> Result<(), &'static str> {
+    const HEADER: &[u8] = b"bplist00";
+    const TRAILER_LEN: usize = 32;
+
+    if !data.starts_with(HEADER) {
+        return Ok(());
+    }
+    if data.len() < HEADER.len() + TRAILER_LEN {
+        return Err("binary plist is too short");
+    }
+
+    let trailer = &data[data.len() - TRAILER_LEN..];
+    let read_u64 = |start: usize| {
+        trailer[start..start + 8]
+            .iter()
+            .fold(0u64, |acc, &b| (acc << 8) | u64::from(b))
+    };
+    let offset_size = u64::from(trailer[6]);
+    let ref_size = u64::from(trailer[7]);
+    let num_objects = read_u64(8);
+    let top_object = read_u64(16);
+    let offset_table_offset = read_u64(24);
+
+    let offset_table_end = num_objects
+        .checked_mul(offset_size)
+        .and_then(|len| len.checked_add(offset_table_offset));
+    let is_valid = (1..=8).contains(&offset_size)
+        && (1..=8).contains(&ref_size)
+        && top_object < num_objects
+        && offset_table_offset >= HEADER.len() as u64
+        && offset_table_end.is_some_and(|end| end <= (data.len() - TRAILER_LEN) as u64);
+    if is_valid {
+        Ok(())
+    } else {
+        Err("binary plist is truncated or corrupt")
+    }
+}
+
+/// A value in a plist file, and where we found it.
+pub struct Node<'a> {
+    pub value: &'a Plist,
+    file: &'a Path,
+    key_path: String,
+}
+
+impl<'a> Node<'a> {
+    /// The top-level value in a plist file.
+    pub fn root(value: &'a Plist, file: &'a Path) -> Node<'a> {
+        Node {
+            value,
+            file,
+            key_path: "(root)".to_owned(),
+        }
+    }
+
+    /// An error saying this value isn't what we expected.
+    pub fn malformed(&self, reason: &str) -> Error {
+        Error::PlistMalformed {
+            path: self.file.to_owned(),
+            key_path: self.key_path.clone(),
+            reason: reason.to_owned(),
+        }
+    }
+
+    fn child(&self, value: &'a Plist, key_path: String) -> Node<'a> {
+        Node {
+            value,
+            file: self.file,
+            key_path,
+        }
+    }
+
+    fn join(&self, key: &str) -> String {
+        if self.key_path == "(root)" {
+            key.to_owned()
+        } else {
+            format!("{}.{}", self.key_path, key)
+        }
+    }
+
+    /// This value, which should be a dictionary.
+    pub fn as_dictionary(&self) -> Result<&'a BTreeMap<String, Plist>, Error> {
+        self.value
+            .as_dictionary()
+            .ok_or_else(|| self.malformed("expected a dictionary"))
+    }
+
+    /// Look up a key in this dictionary, which may not be there.
+    pub fn get(&self, key: &str) -> Result<Option<Node<'a>>, Error> {
+        Ok(self
+            .as_dictionary()?
+            .get(key)
+            .map(|value| self.child(value, self.join(key))))
+    }
+
+    /// Look up a key in this dictionary, which must be there.
+    pub fn key(&self, key: &str) -> Result<Node<'a>, Error> {
+        match self.get(key)? {
+            Some(node) => Ok(node),
+            None => Err(self.malformed(&format!("missing key {:?}", key))),
+        }
+    }
+
+    /// Every entry in this dictionary, in key order.
+    pub fn entries(&self) -> Result<Vec<(&'a str, Node<'a>)>, Error> {
+        Ok(self
+            .as_dictionary()?
+            .iter()
+            .map(|(key, value)| (key.as_str(), self.child(value, self.join(key))))
+            .collect())
+    }
+
+    /// Every item in this array.
+    pub fn items(&self) -> Result<Vec<Node<'a>>, Error> {
+        match self.value.as_array() {
+            Some(array) => Ok(array
+                .iter()
+                .enumerate()
+                .map(|(i, value)| self.child(value, format!("{}[{}]", self.key_path, i)))
+                .collect()),
+            None => Err(self.malformed("expected an array")),
+        }
+    }
+
+    /// This value, which should be a string.
+    pub fn as_str(&self) -> Result<&'a str, Error> {
+        self.value
+            .as_string()
+            .ok_or_else(|| self.malformed("expected a string"))
+    }
+
+    /// A string value from this dictionary, if it's there.  We don't mind
+    /// if optional values are the wrong type; we just ignore them.
+    pub fn optional_string(&self, key: &str) -> Option<String> {
+        self.value
+            .as_dictionary()
+            .and_then(|d| d.get(key))
+            .and_then(|v| v.as_string())
+            .map(|s| s.to_owned())
+    }
+
+    /// A date value from this dictionary, if it's there.  Like
+    /// `optional_string`, we ignore values of the wrong type.
+    pub fn optional_date(&self, key: &str) -> Option<DateTime<UTC>> {
+        self.value
+            .as_dictionary()
+            .and_then(|d| d.get(key))
+            .and_then(|v| v.as_date())
+            .map(|d| d.clone().into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::fs;
+    use std::path::Path;
+
+    use plist::Plist;
+
+    use error::Error;
+    use plists::{check_binary_trailer, Node};
+
+    fn example() -> Plist {
+        let mut child = BTreeMap::new();
+        child.insert("URLString".to_owned(), Plist::Integer(42));
+        let mut root = BTreeMap::new();
+        root.insert(
+            "Children".to_owned(),
+            Plist::Array(vec![Plist::Dictionary(child)]),
+        );
+        Plist::Dictionary(root)
+    }
+
+    #[test]
+    fn test_errors_include_the_key_path() {
+        let plist = example();
+        let root = Node::root(&plist, Path::new("Bookmarks.plist"));
+        let children = root.key("Children").unwrap().items().unwrap();
+        assert_eq!(
+            children[0].key("URLString").unwrap().as_str(),
+            Err(Error::PlistMalformed {
+                path: "Bookmarks.plist".into(),
+                key_path: "Children[0].URLString".to_owned(),
+                reason: "expected a string".to_owned(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_missing_key_is_error() {
+        let plist = example();
+        let root = Node::root(&plist, Path::new("Bookmarks.plist"));
+        match root.key("Title") {
+            Err(Error::PlistMalformed { key_path, .. }) => assert_eq!(key_path, "(root)"),
+            _ => panic!("Expected an error for a missing key"),
+        }
+        assert!(root.get("Title").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_wrong_types_are_errors() {
+        let plist = example();
+        let root = Node::root(&plist, Path::new("Bookmarks.plist"));
+        assert!(root.items().is_err());
+        assert!(root.key("Children").unwrap().as_dictionary().is_err());
+        assert_eq!(root.optional_string("Children"), None);
+    }
+
+    #[test]
+    fn test_truncated_binary_plists_are_errors() {
+        let path = Path::new(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/fixtures/plist/reading-list.bplist"
+        ));
+        let data = fs::read(path).unwrap();
+        assert!(check_binary_trailer(&data).is_ok());
+
+        // The plist crate aborts on some of these, so make sure we catch
+        // every one before it gets there.
+        for len in 8..data.len() {
+            assert!(
+                check_binary_trailer(&data[..len]).is_err(),
+                "truncated to {} bytes",
+                len
+            );
+        }
+    }
+
+    #[test]
+    fn test_xml_plists_are_not_checked() {
+        assert!(check_binary_trailer(b"<?xml version=\"1.0\"?>").is_ok());
+    }
+
+    #[test]
+    fn test_missing_file_is_error() {
+        match super::read(Path::new("/does/not/exist.plist")) {
+            Err(Error::PlistMissing { .. }) => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,1287 @@
+//! Tidy URLs: remove tracking parameters, unwrap redirector links, and so
+//! on, with rules defined as data.
+//!
+//! The built-in rules live in `src/rules/default.json`, and users can add to
+//! or disable them with their own rules file.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use serde_json;
+use urlencoding::encode as urlencode;
+use urlparse::{parse_qs, urlparse, urlunparse, Query, Url};
+
+use error::Error;
+
+pub mod clearurls;
+
+use self::clearurls::{ClearUrls, Outcome};
+
+/// Which hosts a rule applies to.
+///
+/// All matching is done against the `netloc` of a URL, which includes
+/// the port if there is one.
+///
+/// ```text
+/// {"exact": "github.com"}     matches github.com only
+/// {"suffix": "tumblr.com"}    matches tumblr.com and its subdomains,
+///                             but not e.g. nottumblr.com
+/// {"glob": "*.example.*"}     `*` matches any run of characters,
+///                             `?` matches a single character
+/// ```
+///
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum HostMatcher {
+    Exact(String),
+    Suffix(String),
+    Glob(String),
+}
+
+impl HostMatcher {
+    /// Does this matcher apply to the given `netloc`?
+    pub fn matches(&self, netloc: &str) -> bool {
+        match *self {
+            HostMatcher::Exact(ref host) => netloc == host,
+            HostMatcher::Suffix(ref suffix) => {
+                let suffix = suffix.trim_start_matches('.');
+                netloc == suffix || netloc.ends_with(&format!(".{}", suffix))
+            }
+            HostMatcher::Glob(ref pattern) => glob_match(pattern, netloc),
+        }
+    }
+}
+
+/// Something a rule can do to a URL.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    /// Replace the host, e.g. to swap a mobile site for the desktop site.
+    RewriteHost(String),
+
+    /// Throw away the entire query string.
+    DropQuery,
+
+    /// Remove a single query parameter.
+    DropQueryParam(String),
+
+    /// Remove every query parameter whose name starts with this prefix.
+    DropParamPrefix(String),
+
+    /// Remove the fragment if it matches this glob pattern.  Use "*" to
+    /// remove any fragment.
+    DropFragment(String),
+
+    /// Throw away the path from the first occurrence of this string onwards.
+    TruncatePathAt(String),
+
+    /// Remove this string from the end of the path, if present.
+    StripPathSuffix(String),
+
+    /// Turn question/answer links into Stack Exchange referral links.  This
+    /// is a map from hostname to your user ID on that site.
+    StackExchangeReferral(BTreeMap<String, String>),
+
+    /// Turn Stack Exchange `/q/` and `/a/` short links back into full
+    /// `/questions/` links.
+    ExpandStackExchangeShortLink,
+
+    /// If this query parameter holds an http(s) URL, replace the whole URL
+    /// with it.  This unwraps redirectors like google.com/url?q=...
+    UnwrapQueryParam(String),
+}
+
+impl Action {
+    /// Apply this action to a URL, in place.
+    pub fn apply(&self, parsed_url: &mut Url) {
+        match *self {
+            Action::RewriteHost(ref host) => parsed_url.netloc = host.to_owned(),
+            Action::DropQuery => parsed_url.query = None,
+            Action::DropQueryParam(ref name) => remove_query_param(parsed_url, name),
+            Action::DropParamPrefix(ref prefix) => remove_query_param_prefix(parsed_url, prefix),
+            Action::DropFragment(ref pattern) => {
+                let should_drop = match parsed_url.fragment {
+                    Some(ref fragment) => glob_match(pattern, fragment),
+                    None => false,
+                };
+                if should_drop {
+                    parsed_url.fragment = None;
+                }
+            }
+            Action::TruncatePathAt(ref marker) => {
+                let new_path = match parsed_url.path.split(marker.as_str()).next() {
+                    Some(component) => component.to_owned(),
+                    None => String::from(""),
+                };
+                parsed_url.path = new_path;
+            }
+            Action::StripPathSuffix(ref suffix) => {
+                if parsed_url.path.ends_with(suffix.as_str()) {
+                    let new_len = parsed_url.path.len() - suffix.len();
+                    parsed_url.path.truncate(new_len);
+                }
+            }
+            Action::StackExchangeReferral(ref user_ids) => {
+                if let Some(user_id) = user_ids.get(&parsed_url.netloc) {
+                    fix_se_referral(parsed_url, user_id);
+                }
+            }
+            Action::ExpandStackExchangeShortLink => expand_se_short_link(parsed_url),
+            Action::UnwrapQueryParam(ref name) => {
+                let target = match parsed_url.query {
+                    Some(ref qs) => parse_qs(qs)
+                        .remove(name)
+                        .and_then(|values| values.into_iter().next())
+                        .filter(|v| v.starts_with("http://") || v.starts_with("https://")),
+                    None => None,
+                };
+                if let Some(target) = target {
+                    *parsed_url = urlparse(target);
+                }
+            }
+        }
+    }
+}
+
+/// A named set of actions to run against URLs on matching hosts.
+///
+/// If `paths` is non-empty, the rule only applies if the path also matches
+/// one of these glob patterns.
+///
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct Rule {
+    pub name: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    pub hosts: Vec<HostMatcher>,
+    #[serde(default)]
+    pub paths: Vec<String>,
+    pub actions: Vec<Action>,
+}
+
+impl Rule {
+    /// Does this rule apply to the given URL?
+    pub fn matches(&self, parsed_url: &Url) -> bool {
+        self.hosts.iter().any(|h| h.matches(&parsed_url.netloc))
+            && (self.paths.is_empty() || self.paths.iter().any(|p| glob_match(p, &parsed_url.path)))
+    }
+}
+
+/// How many times we'll unwrap a URL from inside another URL, so a
+/// redirector that points to itself can't send us round in circles.
+const MAX_UNWRAPS: usize = 10;
+
+/// The result of one pass of the rules over a URL.
+enum Pass {
+    /// We're done; this is the tidied URL.
+    Done(String),
+
+    /// We found a URL wrapped inside this one, which needs tidying in turn.
+    Unwrapped(String),
+}
+
+/// An ordered list of rules.
+///
+/// Rules are applied in order, and each rule sees the URL as left by the
+/// rules before it -- so a rule that rewrites mobile.twitter.com to
+/// twitter.com should come before any rules for twitter.com.
+///
+/// You can also apply a ClearURLs catalogue, which runs after the rules.
+///
+#[derive(Clone, Debug, Deserialize)]
+pub struct RuleSet {
+    pub rules: Vec<Rule>,
+    #[serde(skip)]
+    pub clearurls: Option<ClearUrls>,
+}
+
+impl RuleSet {
+    /// The rules that ship with safari.rs, as defined in `rules/default.json`.
+    pub fn builtin() -> RuleSet {
+        RuleSet::builtin_ref().clone()
+    }
+
+    /// The built-in rules, which we only parse once.
+    pub(crate) fn builtin_ref() -> &'static RuleSet {
+        static BUILTIN: OnceLock<RuleSet> = OnceLock::new();
+        BUILTIN.get_or_init(|| {
+            RuleSet::from_json(include_str!("rules/default.json"))
+                .expect("built-in rules are not valid JSON")
+        })
+    }
+
+    /// Parse a rule set from its JSON representation.
+    pub fn from_json(json: &str) -> Result<RuleSet, Error> {
+        serde_json::from_str(json).map_err(|e| Error::Rules {
+            path: None,
+            reason: format!("Unable to parse rules: {}", e),
+        })
+    }
+
+    /// Merge a set of user rules into this rule set.
+    ///
+    /// Rules named in `disable` are removed.  A user rule with the same name
+    /// as an existing rule replaces it in place (so it runs at the same point
+    /// in the order); any other user rules run after the existing rules.
+    ///
+    pub fn extend_with(&mut self, user_rules: UserRules) {
+        self.rules
+            .retain(|rule| !user_rules.disable.contains(&rule.name));
+
+        for rule in user_rules.rules {
+            match self.rules.iter().position(|r| r.name == rule.name) {
+                Some(idx) => self.rules[idx] = rule,
+                None => self.rules.push(rule),
+            }
+        }
+
+        self.rules.extend(user_rules.stack_exchange.into_rules());
+    }
+
+    /// Strip tracking junk and URL suffixes.
+    pub fn tidy(&self, url: &str) -> String {
+        self.explain(url).output
+    }
+
+    /// Strip tracking junk and URL suffixes, and record which rules
+    /// changed the URL along the way.
+    ///
+    /// If the URL is a wrapper around another URL (e.g. a redirector like
+    /// google.com/url?q=...), the embedded URL is tidied in turn.
+    ///
+    pub fn explain(&self, url: &str) -> Explanation {
+        let mut steps = vec![];
+        let mut current = url.to_owned();
+        let mut unwraps = 0;
+
+        let output = loop {
+            match self.tidy_once(&current, &mut steps) {
+                Pass::Done(output) => break output,
+                Pass::Unwrapped(next) => {
+                    current = next;
+                    unwraps += 1;
+                    if unwraps > MAX_UNWRAPS {
+                        break current;
+                    }
+                }
+            }
+        };
+
+        Explanation {
+            input: url.to_owned(),
+            output,
+            steps,
+        }
+    }
+
+    /// Run every rule over a URL once, recording any changes in `steps`.
+    ///
+    /// This stops early if we unwrap another URL from inside this one.
+    ///
+    fn tidy_once(&self, url: &str, steps: &mut Vec<Step>) -> Pass {
+        let mut parsed_url = urlparse(url);
+        for rule in &self.rules {
+            if rule.matches(&parsed_url) {
+                for action in &rule.actions {
+                    let before = parsed_url.clone();
+                    action.apply(&mut parsed_url);
+                    if parsed_url != before {
+                        let after = urlunparse(parsed_url.clone());
+                        steps.push(Step {
+                            rule: rule.name.to_owned(),
+                            action: action.to_string(),
+                            before: urlunparse(before),
+                            after: after.to_owned(),
+                        });
+                        if let Action::UnwrapQueryParam(_) = *action {
+                            return Pass::Unwrapped(after);
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut output = urlunparse(parsed_url);
+
+        if let Some(ref clearurls) = self.clearurls {
+            for provider in &clearurls.providers {
+                let (after, action, unwrapped) =
+                    match provider.apply(&output, clearurls.allow_referral_marketing) {
+                        Outcome::Unchanged => continue,
+                        Outcome::Redirected(u) => (u, "followed a ClearURLs redirection", true),
+                        Outcome::Cleaned(u) => (u, "applied ClearURLs rules", false),
+                    };
+                steps.push(Step {
+                    rule: format!("clearurls/{}", provider.name),
+                    action: action.to_owned(),
+                    before: output,
+                    after: after.to_owned(),
+                });
+                if unwrapped {
+                    return Pass::Unwrapped(after);
+                }
+                output = after;
+            }
+        }
+
+        Pass::Done(output)
+    }
+}
+
+/// A single action that changed a URL.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Step {
+    /// Name of the rule the action belongs to.
+    pub rule: String,
+    /// Description of what the action did.
+    pub action: String,
+    pub before: String,
+    pub after: String,
+}
+
+/// A record of how a URL was tidied.
+///
+/// Only actions that changed the URL are recorded as steps, so an
+/// unchanged URL has no steps.
+///
+#[derive(Clone, Debug, PartialEq)]
+pub struct Explanation {
+    pub input: String,
+    pub output: String,
+    pub steps: Vec<Step>,
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Action::RewriteHost(ref host) => write!(f, "rewrote the host to {}", host),
+            Action::DropQuery => write!(f, "removed the query string"),
+            Action::DropQueryParam(ref name) => write!(f, "removed query parameter {:?}", name),
+            Action::DropParamPrefix(ref prefix) => {
+                write!(f, "removed query parameters starting {:?}", prefix)
+            }
+            Action::DropFragment(ref pattern) => {
+                write!(f, "removed the fragment (matching {:?})", pattern)
+            }
+            Action::TruncatePathAt(ref marker) => write!(f, "truncated the path at {:?}", marker),
+            Action::StripPathSuffix(ref suffix) => {
+                write!(f, "removed {:?} from the end of the path", suffix)
+            }
+            Action::StackExchangeReferral(_) => {
+                write!(f, "rewrote as a Stack Exchange referral link")
+            }
+            Action::ExpandStackExchangeShortLink => {
+                write!(f, "expanded a Stack Exchange short link")
+            }
+            Action::UnwrapQueryParam(ref name) => {
+                write!(f, "unwrapped the URL in query parameter {:?}", name)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Explanation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "input:  {}", self.input)?;
+        if self.steps.is_empty() {
+            writeln!(f, "No rules changed this URL.")?;
+        }
+        for step in &self.steps {
+            writeln!(f, "{}: {}", step.rule, step.action)?;
+            writeln!(f, "    before: {}", step.before)?;
+            writeln!(f, "    after:  {}", step.after)?;
+        }
+        write!(f, "output: {}", self.output)
+    }
+}
+
+/// A user's own rules, which extend or override the built-in rules.
+///
+/// The file looks like `rules/default.json`, with an extra `disable` list
+/// for turning off built-in rules by name:
+///
+/// ```text
+/// {
+///   "disable": ["github-pr-files"],
+///   "rules": [ ... ]
+/// }
+/// ```
+///
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub struct UserRules {
+    #[serde(default)]
+    pub disable: Vec<String>,
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+    #[serde(default)]
+    pub stack_exchange: StackExchangeConfig,
+    #[serde(default)]
+    pub clearurls: Option<ClearUrlsConfig>,
+}
+
+/// Where to find a ClearURLs catalogue to apply after the rules.
+///
+/// ```text
+/// "clearurls": {
+///   "path": "clearurls.json",
+///   "allow_referral_marketing": false
+/// }
+/// ```
+///
+/// A relative `path` is relative to the rules file.
+///
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct ClearUrlsConfig {
+    pub path: String,
+    #[serde(default)]
+    pub allow_referral_marketing: bool,
+}
+
+/// How to handle links to questions and answers on Stack Exchange sites.
+///
+/// Both behaviours are off by default:
+///
+/// ```text
+/// "stack_exchange": {
+///   "account_id": "1558022",
+///   "referrals": {"stackoverflow.com": "1558022", ...},
+///   "canonicalise_short_links": true
+/// }
+/// ```
+///
+/// * `account_id` - a user ID to use for referral links on every site in
+///   `rules/stack_exchange_sites.txt`, without looking anything up.
+/// * `referrals` - map from hostname to your user ID on that site.  Links to
+///   questions and answers on these sites are rewritten as referral links,
+///   e.g. `/q/:question_id/:user_id`.  If your user ID is different on some
+///   sites, list them here and they override `account_id`;
+///   `se_referral_autogen.py` generates this map from your Stack Exchange
+///   account URL.
+/// * `canonicalise_short_links` - rewrite `/q/` and `/a/` short links on
+///   Stack Exchange sites as full `/questions/` links.
+///
+/// If both are set, short links are expanded first, so links to sites with
+/// a user ID end up as referral links.
+///
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub struct StackExchangeConfig {
+    #[serde(default)]
+    pub account_id: Option<String>,
+    #[serde(default)]
+    pub referrals: BTreeMap<String, String>,
+    #[serde(default)]
+    pub canonicalise_short_links: bool,
+}
+
+impl StackExchangeConfig {
+    /// Your user ID on each site: `account_id` on every site in the
+    /// bundled list, unless `referrals` has a different ID for it.
+    fn user_ids(&self) -> BTreeMap<String, String> {
+        let mut user_ids = BTreeMap::new();
+        if let Some(ref account_id) = self.account_id {
+            for host in stack_exchange_sites() {
+                user_ids.insert(host.to_owned(), account_id.to_owned());
+            }
+        }
+        user_ids.extend(self.referrals.clone());
+        user_ids
+    }
+
+    fn into_rules(self) -> Vec<Rule> {
+        let mut rules = vec![];
+        let user_ids = self.user_ids();
+
+        if self.canonicalise_short_links {
+            let mut hosts: Vec<HostMatcher> = stack_exchange_sites()
+                .map(|host| HostMatcher::Exact(host.to_owned()))
+                .collect();
+            for host in user_ids.keys() {
+                let matcher = HostMatcher::Exact(host.to_owned());
+                if !hosts.contains(&matcher) {
+                    hosts.push(matcher);
+                }
+            }
+            rules.push(Rule {
+                name: String::from("stack-exchange-short-links"),
+                description: Some(String::from(
+                    "Expand /q/ and /a/ short links on Stack Exchange sites.",
+                )),
+                hosts,
+                paths: vec![],
+                actions: vec![Action::ExpandStackExchangeShortLink],
+            });
+        }
+
+        if !user_ids.is_empty() {
+            rules.push(Rule {
+                name: String::from("stack-exchange-referral"),
+                description: Some(String::from(
+                    "Rewrite Stack Exchange links as referral links.",
+                )),
+                hosts: user_ids
+                    .keys()
+                    .map(|host| HostMatcher::Exact(host.to_owned()))
+                    .collect(),
+                paths: vec![],
+                actions: vec![Action::StackExchangeReferral(user_ids)],
+            });
+        }
+
+        rules
+    }
+}
+
+/// Hostnames of the sites in the Stack Exchange network, as listed in
+/// `rules/stack_exchange_sites.txt`.
+pub fn stack_exchange_sites() -> impl Iterator<Item = &'static str> {
+    include_str!("rules/stack_exchange_sites.txt")
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty())
+}
+
+impl UserRules {
+    /// Parse a set of user rules from its JSON representation.
+    pub fn from_json(json: &str) -> Result<UserRules, Error> {
+        serde_json::from_str(json).map_err(|e| Error::Rules {
+            path: None,
+            reason: format!("Unable to parse rules: {}", e),
+        })
+    }
+
+    /// Read a set of user rules from a file.
+    pub fn read(path: &Path) -> Result<UserRules, Error> {
+        UserRules::from_json(&read_rules_file(path)?).map_err(|e| in_file(e, path))
+    }
+}
+
+/// Read a file of rules, e.g. the user's rules or a ClearURLs catalogue.
+fn read_rules_file(path: &Path) -> Result<String, Error> {
+    let mut json = String::new();
+    match File::open(path).and_then(|mut file| file.read_to_string(&mut json)) {
+        Ok(_) => Ok(json),
+        Err(e) => Err(Error::Rules {
+            path: Some(path.to_owned()),
+            reason: format!("Unable to read rules file: {}", e),
+        }),
+    }
+}
+
+/// Add the file we were reading to an error from parsing its rules.
+fn in_file(e: Error, path: &Path) -> Error {
+    match e {
+        Error::Rules { reason, .. } => Error::Rules {
+            path: Some(path.to_owned()),
+            reason,
+        },
+        e => e,
+    }
+}
+
+/// Environment variable that can point to a user rules file.
+pub const RULES_ENV_VAR: &str = "SAFARI_RS_RULES";
+
+/// Where we look for a user rules file if one isn't specified explicitly,
+/// e.g. ~/.config/safari.rs/rules.json on Linux.
+pub fn default_rules_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|mut path| {
+        path.push("safari.rs");
+        path.push("rules.json");
+        path
+    })
+}
+
+/// Load the built-in rules, extended with the user's rules file (if any).
+///
+/// The rules file is taken from (in order of preference):
+///
+///   1. the `path` argument, e.g. from the `--rules` flag
+///   2. the `SAFARI_RS_RULES` environment variable
+///   3. `default_rules_path()`, if it exists
+///
+/// It's an error if a file named by (1) or (2) doesn't exist.
+///
+pub fn load(path: Option<&str>) -> Result<RuleSet, Error> {
+    let mut rules = RuleSet::builtin();
+
+    let explicit_path = match path {
+        Some(p) => Some(PathBuf::from(p)),
+        None => env::var_os(RULES_ENV_VAR).map(PathBuf::from),
+    };
+
+    let rules_path = match explicit_path {
+        Some(p) => p,
+        None => match default_rules_path() {
+            Some(ref p) if p.exists() => p.to_owned(),
+            _ => return Ok(rules),
+        },
+    };
+
+    let user_rules = UserRules::read(&rules_path)?;
+
+    if let Some(ref config) = user_rules.clearurls {
+        let clearurls_path = match rules_path.parent() {
+            Some(dir) => dir.join(&config.path),
+            None => Path::new(&config.path).to_owned(),
+        };
+        let mut clearurls = ClearUrls::read(&clearurls_path)?;
+        clearurls.allow_referral_marketing = config.allow_referral_marketing;
+        rules.clearurls = Some(clearurls);
+    }
+
+    rules.extend_with(user_rules);
+
+    Ok(rules)
+}
+
+/// Match a string against a glob pattern.
+///
+/// This is a deliberately small glob syntax: `*` matches any run of
+/// characters (including none), `?` matches exactly one character, and
+/// everything else matches itself.
+///
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    let (mut p, mut t) = (0, 0);
+
+    // Where to resume if we need to backtrack to the last `*`.
+    let mut star: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = star {
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+fn partial_urlencode(value: &str) -> String {
+    // The urlencode from the urlencoding library goes further than I like,
+    // and also URL encodes ASCII digits. Reverse that stuff.
+    urlencode(value)
+        .replace("%30", "0")
+        .replace("%31", "1")
+        .replace("%32", "2")
+        .replace("%33", "3")
+        .replace("%34", "4")
+        .replace("%35", "5")
+        .replace("%36", "6")
+        .replace("%37", "7")
+        .replace("%38", "8")
+        .replace("%39", "9")
+}
+
+/// Re-encode a query string for Rust
+fn encode_querystring(query: Query) -> Option<String> {
+    let mut query_components: Vec<String> = vec![];
+    for (key, value) in query {
+        for v in value.iter() {
+            query_components.push(format!("{}={}", key, partial_urlencode(v)));
+        }
+    }
+    if !query_components.is_empty() {
+        Some(query_components.join("&"))
+    } else {
+        None
+    }
+}
+
+/// Remove every query parameter whose name matches a condition.
+///
+/// - `parsed_url` - the `Url` structure returned by urlparse
+/// - `should_remove` - returns true for names of query parameters to remove.
+///
+fn remove_query_params_where<F>(parsed_url: &mut Url, should_remove: F)
+where
+    F: Fn(&str) -> bool,
+{
+    parsed_url.query = match parsed_url.query {
+        Some(ref qs) => {
+            let mut query = parse_qs(qs);
+            query.retain(|key, _| !should_remove(key));
+            encode_querystring(query)
+        }
+        None => None,
+    };
+}
+
+/// Remove a query parameter from a URL.
+///
+/// - `parsed_url` - the `Url` structure returned by urlparse
+/// - `query_param` - name of the query parameter to remove.
+///
+fn remove_query_param(parsed_url: &mut Url, query_param: &str) {
+    remove_query_params_where(parsed_url, |key| key == query_param);
+}
+
+/// Remove every query parameter that starts with a given prefix.
+///
+/// - `parsed_url` - the `Url` structure returned by urlparse
+/// - `prefix` - prefix of the query parameters to remove.
+///
+fn remove_query_param_prefix(parsed_url: &mut Url, prefix: &str) {
+    remove_query_params_where(parsed_url, |key| key.starts_with(prefix));
+}
+
+/// Turn a URL into a Stack Exchange referral link.
+///
+/// - `parsed_url` - the `Url` structure returned by urlparse
+/// - `user_id` - your user ID on the SE site
+///
+fn fix_se_referral(parsed_url: &mut Url, user_id: &str) {
+    // A question URL is of the form
+    //
+    //    http://stackoverflow.com/questions/:question_id/:question_title
+    //
+    if !parsed_url.path.starts_with("/questions") {
+        return;
+    }
+
+    let new_path = match parsed_url.path.split('/').nth(2) {
+        // Check it's a number
+        Some(path_component) => match path_component.parse::<i32>() {
+            // Check if there's an answer fragment
+            Ok(q_id) => match parsed_url.fragment {
+                Some(ref fragment) => match fragment.parse::<i32>() {
+                    Ok(ans_id) => Some(format!("/a/{}/{}", ans_id, user_id)),
+                    Err(_) => None,
+                },
+                None => Some(format!("/q/{}/{}", q_id, user_id)),
+            },
+            Err(_) => None,
+        },
+        None => None,
+    };
+
+    // If we got something interesting, update the URL.
+    if let Some(p) = new_path {
+        parsed_url.path = p;
+        parsed_url.fragment = None;
+    }
+}
+
+/// Turn a Stack Exchange short link back into a full link.
+///
+/// ```text
+/// /q/:question_id(/:user_id)  ~>  /questions/:question_id
+/// /a/:answer_id(/:user_id)    ~>  /questions/:answer_id
+/// ```
+///
+/// Questions and answers share one set of IDs, and Stack Exchange
+/// redirects `/questions/:answer_id` to the answer on its question page,
+/// so we don't need to look up which question an answer belongs to.
+///
+fn expand_se_short_link(parsed_url: &mut Url) {
+    let new_path = {
+        let components: Vec<&str> = parsed_url.path.split('/').collect();
+
+        // Path components are ["", "q" or "a", id, optional user ID]
+        if components.len() < 3 || components.len() > 4 || components[2].parse::<u64>().is_err() {
+            return;
+        }
+
+        match components[1] {
+            "q" | "a" => format!("/questions/{}", components[2]),
+            _ => return,
+        }
+    };
+
+    parsed_url.path = new_path;
+}
+
+/// Tests for glob_match().
+#[cfg(test)]
+mod tests_glob {
+    use rules::glob_match;
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("*", ""));
+        assert!(glob_match("*", "example.com"));
+        assert!(glob_match("example.com", "example.com"));
+        assert!(glob_match("*.example.com", "www.example.com"));
+        assert!(glob_match("module-*", "module-subprocess"));
+        assert!(glob_match("www.amazon.??", "www.amazon.de"));
+        assert!(glob_match("*amazon*", "smile.amazon.co.uk"));
+
+        assert!(!glob_match("*.example.com", "example.com"));
+        assert!(!glob_match("module-*", "subprocess.run"));
+        assert!(!glob_match("www.amazon.??", "www.amazon.com"));
+    }
+}
+
+/// Tests for RuleSet.
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::path::PathBuf;
+
+    use error::Error;
+    use rules::{load, Action, HostMatcher, RuleSet, UserRules};
+    use test_support::temp_path;
+
+    #[test]
+    fn test_builtin_rules_parse() {
+        let rules = RuleSet::builtin();
+        assert!(!rules.rules.is_empty());
+        assert!(rules.rules.iter().any(|r| r.name == "twitter-mobile"));
+    }
+
+    #[test]
+    fn test_parse_rules_from_json() {
+        let rules = RuleSet::from_json(
+            r#"{
+              "rules": [
+                {
+                  "name": "example",
+                  "hosts": [{"glob": "*.example.com"}],
+                  "actions": ["drop_query", {"rewrite_host": "example.com"}]
+                }
+              ]
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(rules.rules.len(), 1);
+        assert_eq!(rules.rules[0].description, None);
+        assert_eq!(
+            rules.rules[0].hosts,
+            vec![HostMatcher::Glob(String::from("*.example.com"))]
+        );
+        assert_eq!(
+            rules.rules[0].actions,
+            vec![
+                Action::DropQuery,
+                Action::RewriteHost(String::from("example.com")),
+            ]
+        );
+        assert_eq!(
+            rules.tidy("https://www.example.com/page?id=1"),
+            "https://example.com/page"
+        );
+    }
+
+    #[test]
+    fn test_suffix_matches_on_a_label_boundary() {
+        let matcher = HostMatcher::Suffix(String::from("example.com"));
+        assert!(matcher.matches("example.com"));
+        assert!(matcher.matches("www.example.com"));
+        assert!(!matcher.matches("notexample.com"));
+        assert!(!matcher.matches("example.com.evil.net"));
+
+        // A leading dot is allowed, but doesn't change what matches.
+        let matcher = HostMatcher::Suffix(String::from(".example.com"));
+        assert!(matcher.matches("www.example.com"));
+        assert!(!matcher.matches("notexample.com"));
+    }
+
+    #[test]
+    fn test_invalid_rules_are_rejected() {
+        assert!(RuleSet::from_json(r#"{"rules": [{"name": "no-hosts"}]}"#).is_err());
+        assert!(RuleSet::from_json(
+            r#"{"rules": [{"name": "x", "hosts": [], "actions": ["explode"]}]}"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_user_rules_extend_and_override() {
+        let mut rules = RuleSet::builtin();
+        let user_rules = UserRules::from_json(
+            r#"{
+              "disable": ["github-pr-files"],
+              "rules": [
+                {
+                  "name": "youtube",
+                  "hosts": [{"suffix": "youtube.com"}],
+                  "actions": [{"drop_query_param": "t"}]
+                },
+                {
+                  "name": "example-tracking",
+                  "hosts": [{"exact": "example.com"}],
+                  "actions": [{"drop_query_param": "ref"}]
+                }
+              ]
+            }"#,
+        )
+        .unwrap();
+        let youtube_idx = rules.rules.iter().position(|r| r.name == "youtube");
+
+        rules.extend_with(user_rules);
+
+        // Disabled rules no longer apply
+        assert_eq!(
+            rules.tidy("https://github.com/alexwlchan/safari.rs/pull/1/files"),
+            "https://github.com/alexwlchan/safari.rs/pull/1/files"
+        );
+
+        // Overridden rules replace the built-in rule in place
+        assert_eq!(
+            rules.rules.iter().position(|r| r.name == "youtube"),
+            youtube_idx
+        );
+        assert_eq!(
+            rules.tidy("https://www.youtube.com/watch?t=10"),
+            "https://www.youtube.com/watch"
+        );
+        assert_eq!(
+            rules.tidy("https://www.youtube.com/watch?feature=youtu.be"),
+            "https://www.youtube.com/watch?feature=youtu.be"
+        );
+
+        // New rules are added at the end
+        assert_eq!(rules.rules.last().unwrap().name, "example-tracking");
+        assert_eq!(
+            rules.tidy("https://example.com/?ref=home"),
+            "https://example.com/"
+        );
+    }
+
+    #[test]
+    fn test_load_rules_file() {
+        let path = temp_path("rules.json");
+        let mut file = File::create(&path).unwrap();
+        file.write_all(br#"{"disable": ["twitter-mobile"]}"#)
+            .unwrap();
+
+        let rules = load(Some(path.to_str().unwrap())).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert!(rules.rules.iter().all(|r| r.name != "twitter-mobile"));
+        assert_eq!(
+            rules.tidy("https://mobile.twitter.com/"),
+            "https://mobile.twitter.com/"
+        );
+    }
+
+    #[test]
+    fn test_missing_rules_file_is_error() {
+        assert!(load(Some("/does/not/exist/rules.json")).is_err());
+    }
+
+    #[test]
+    fn test_broken_rules_file_error_has_path() {
+        let path = temp_path("broken-rules.json");
+        fs::write(&path, "{\"disable\": ").unwrap();
+
+        let result = load(Some(path.to_str().unwrap()));
+        fs::remove_file(&path).unwrap();
+        match result {
+            Err(Error::Rules {
+                path: Some(p),
+                reason,
+            }) => {
+                assert_eq!(p, path);
+                assert!(reason.starts_with("Unable to parse rules"), "{}", reason);
+            }
+            other => panic!("Unexpected result: {:?}", other),
+        }
+
+        match load(Some("/does/not/exist/rules.json")) {
+            Err(Error::Rules { path, .. }) => {
+                assert_eq!(path, Some(PathBuf::from("/does/not/exist/rules.json")))
+            }
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_explain_records_each_change() {
+        let explanation = RuleSet::builtin()
+            .explain("https://mobile.twitter.com/b0rk/status/1?s=20&utm_source=x");
+
+        assert_eq!(
+            explanation.input,
+            "https://mobile.twitter.com/b0rk/status/1?s=20&utm_source=x"
+        );
+        assert_eq!(explanation.output, "https://twitter.com/b0rk/status/1");
+
+        let rules: Vec<&str> = explanation.steps.iter().map(|s| s.rule.as_str()).collect();
+        assert_eq!(
+            rules,
+            vec!["twitter-mobile", "tracking-prefixes", "twitter-sharing"]
+        );
+
+        assert_eq!(
+            explanation.steps[0].action,
+            "rewrote the host to twitter.com"
+        );
+        assert_eq!(
+            explanation.steps[0].before,
+            "https://mobile.twitter.com/b0rk/status/1?s=20&utm_source=x"
+        );
+        assert_eq!(
+            explanation.steps[0].after,
+            "https://twitter.com/b0rk/status/1?s=20&utm_source=x"
+        );
+        assert_eq!(
+            explanation.steps[2].before,
+            "https://twitter.com/b0rk/status/1?s=20"
+        );
+        assert_eq!(explanation.steps[2].after, explanation.output);
+    }
+
+    #[test]
+    fn test_explain_unchanged_url() {
+        let explanation = RuleSet::builtin().explain("https://example.org/");
+        assert_eq!(explanation.output, "https://example.org/");
+        assert!(explanation.steps.is_empty());
+        assert_eq!(
+            explanation.to_string(),
+            "input:  https://example.org/\nNo rules changed this URL.\noutput: https://example.org/"
+        );
+    }
+
+    #[test]
+    fn test_stack_exchange_is_untouched_by_default() {
+        let rules = RuleSet::builtin();
+        for url in &[
+            "https://stackoverflow.com/questions/1732348/regex-match-open-tags",
+            "https://stackoverflow.com/q/1732348/1558022",
+            "https://stackoverflow.com/a/82852/1558022",
+        ] {
+            assert_eq!(rules.tidy(url), *url);
+        }
+    }
+
+    fn stack_exchange_rules(json: &str) -> RuleSet {
+        let mut rules = RuleSet::builtin();
+        rules.extend_with(UserRules::from_json(json).unwrap());
+        rules
+    }
+
+    #[test]
+    fn test_stack_exchange_referrals() {
+        let rules = stack_exchange_rules(
+            r#"{"stack_exchange": {"referrals": {"stackoverflow.com": "123"}}}"#,
+        );
+
+        assert_eq!(
+            rules.tidy("http://stackoverflow.com/questions/1732348/regex-match-open-tags"),
+            "http://stackoverflow.com/q/1732348/123"
+        );
+        assert_eq!(
+            rules.tidy("http://stackoverflow.com/questions/82831/how-do-i-check#82852"),
+            "http://stackoverflow.com/a/82852/123"
+        );
+
+        // Sites without a user ID are left alone
+        assert_eq!(
+            rules.tidy("https://superuser.com/questions/1234/a-question"),
+            "https://superuser.com/questions/1234/a-question"
+        );
+    }
+
+    #[test]
+    fn test_stack_exchange_account_id() {
+        let rules = stack_exchange_rules(
+            r#"{"stack_exchange": {
+                "account_id": "123",
+                "referrals": {"superuser.com": "456"}
+            }}"#,
+        );
+
+        // The account ID is used on every site in the bundled list...
+        assert_eq!(
+            rules.tidy("https://stackoverflow.com/questions/1732348/regex-match-open-tags"),
+            "https://stackoverflow.com/q/1732348/123"
+        );
+        assert_eq!(
+            rules.tidy("https://mathoverflow.net/questions/7155/a-question#7156"),
+            "https://mathoverflow.net/a/7156/123"
+        );
+
+        // ...unless there's a per-site ID in referrals...
+        assert_eq!(
+            rules.tidy("https://superuser.com/questions/1234/a-question"),
+            "https://superuser.com/q/1234/456"
+        );
+
+        // ...and sites outside the network are left alone.
+        assert_eq!(
+            rules.tidy("https://example.com/questions/1234/a-question"),
+            "https://example.com/questions/1234/a-question"
+        );
+    }
+
+    #[test]
+    fn test_stack_exchange_canonicalise_short_links() {
+        let rules =
+            stack_exchange_rules(r#"{"stack_exchange": {"canonicalise_short_links": true}}"#);
+
+        assert_eq!(
+            rules.tidy("https://stackoverflow.com/q/1732348/1558022"),
+            "https://stackoverflow.com/questions/1732348"
+        );
+        assert_eq!(
+            rules.tidy("https://scifi.stackexchange.com/q/58980"),
+            "https://scifi.stackexchange.com/questions/58980"
+        );
+        assert_eq!(
+            rules.tidy("https://stackoverflow.com/a/82852/1558022"),
+            "https://stackoverflow.com/questions/82852"
+        );
+
+        // Other paths and non-SE sites are left alone
+        assert_eq!(
+            rules.tidy("https://stackoverflow.com/questions/1732348/regex"),
+            "https://stackoverflow.com/questions/1732348/regex"
+        );
+        assert_eq!(
+            rules.tidy("https://stackoverflow.com/q/not-a-number"),
+            "https://stackoverflow.com/q/not-a-number"
+        );
+        assert_eq!(
+            rules.tidy("https://example.com/q/1234/5678"),
+            "https://example.com/q/1234/5678"
+        );
+    }
+
+    #[test]
+    fn test_stack_exchange_canonicalise_then_referral() {
+        let rules = stack_exchange_rules(
+            r#"{
+              "stack_exchange": {
+                "referrals": {"stackoverflow.com": "123"},
+                "canonicalise_short_links": true
+              }
+            }"#,
+        );
+
+        assert_eq!(
+            rules.tidy("https://stackoverflow.com/q/1732348/1558022"),
+            "https://stackoverflow.com/q/1732348/123"
+        );
+        assert_eq!(
+            rules.tidy("https://superuser.com/q/1234/5678"),
+            "https://superuser.com/questions/1234"
+        );
+    }
+
+    #[test]
+    fn test_rule_paths() {
+        let rules = RuleSet::from_json(
+            r#"{
+              "rules": [
+                {
+                  "name": "example-search",
+                  "hosts": [{"exact": "example.com"}],
+                  "paths": ["/search", "/search/*"],
+                  "actions": ["drop_query"]
+                }
+              ]
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            rules.tidy("https://example.com/search?q=1"),
+            "https://example.com/search"
+        );
+        assert_eq!(
+            rules.tidy("https://example.com/search/images?q=1"),
+            "https://example.com/search/images"
+        );
+        assert_eq!(
+            rules.tidy("https://example.com/about?q=1"),
+            "https://example.com/about?q=1"
+        );
+    }
+
+    #[test]
+    fn test_unwrapped_urls_are_tidied() {
+        let explanation = RuleSet::builtin().explain(
+            "https://www.google.com/url?q=https%3A%2F%2Fl.facebook.com%2Fl.php%3Fu%3Dhttps%253A%252F%252Fmobile.twitter.com%252Fb0rk%253Fs%253D20",
+        );
+        assert_eq!(explanation.output, "https://twitter.com/b0rk");
+
+        let rules: Vec<&str> = explanation.steps.iter().map(|s| s.rule.as_str()).collect();
+        assert_eq!(
+            rules,
+            vec![
+                "google-redirect",
+                "facebook-redirect",
+                "twitter-mobile",
+                "twitter-sharing",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unwrapping_does_not_loop_forever() {
+        let rules = RuleSet::from_json(
+            r#"{
+              "rules": [
+                {
+                  "name": "loop",
+                  "hosts": [{"exact": "example.com"}],
+                  "actions": [{"unwrap_query_param": "u"}]
+                }
+              ]
+            }"#,
+        )
+        .unwrap();
+
+        // Each pass unwraps one level, so this would go on for a while
+        let mut url = String::from("https://example.com/");
+        for _ in 0..20 {
+            url = format!(
+                "https://example.com/?u={}",
+                url.replace("%", "%25")
+                    .replace(":", "%3A")
+                    .replace("/", "%2F")
+                    .replace("?", "%3F")
+                    .replace("=", "%3D")
+            );
+        }
+
+        let explanation = rules.explain(&url);
+        assert_eq!(explanation.steps.len(), 11);
+        assert!(explanation.output.starts_with("https://example.com/?u="));
+    }
+
+    #[test]
+    fn test_rules_apply_in_order() {
+        let rules = RuleSet::from_json(
+            r#"{
+              "rules": [
+                {
+                  "name": "rename",
+                  "hosts": [{"exact": "m.example.com"}],
+                  "actions": [{"rewrite_host": "example.com"}]
+                },
+                {
+                  "name": "strip",
+                  "hosts": [{"exact": "example.com"}],
+                  "actions": [{"strip_path_suffix": "/amp"}, {"drop_fragment": "*"}]
+                }
+              ]
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            rules.tidy("https://m.example.com/story/amp#top"),
+            "https://example.com/story"
+        );
+    }
+}
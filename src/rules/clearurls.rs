@@ -0,0 +1,401 @@
+//! Support for the ClearURLs rules catalogue.
+//!
+//! ClearURLs (<https://clearurls.xyz>) maintains a big list of tracking
+//! parameters, as a JSON file of "providers":
+//!
+//! ```text
+//! {
+//!   "providers": {
+//!     "amazon": {
+//!       "urlPattern": "^https?:\\/\\/(?:[a-z0-9-]+\\.)*?amazon(?:\\.[a-z]{2,}){1,}",
+//!       "completeProvider": false,
+//!       "rules": ["p[fd]_rd_[a-z]*", "uid", ...],
+//!       "referralMarketing": ["tag", "ascsubtag"],
+//!       "rawRules": ["\\/ref=[^/?]*"],
+//!       "exceptions": [...],
+//!       "redirections": [...]
+//!     },
+//!     ...
+//!   }
+//! }
+//! ```
+//!
+//! All of these are (JavaScript) regular expressions, matched without
+//! regard to case.  We apply a provider to any URL that matches its
+//! `urlPattern` and none of its `exceptions`:
+//!
+//!   * If one of the `redirections` matches, the URL is replaced with
+//!     the (URL-decoded) first capture group.
+//!   * Otherwise, `rawRules` are deleted from anywhere in the URL, then
+//!     query parameters and fragment parameters whose names match one of
+//!     the `rules` (or `referralMarketing`, unless allowed) are removed.
+//!
+//! Complete providers are meant to be blocked outright, which doesn't make
+//! sense for tidying a URL, so we only follow their redirections.
+//!
+//! Some patterns use JavaScript regex features that Rust doesn't support
+//! (e.g. lookahead).  Providers with patterns we can't compile are skipped,
+//! rather than making the whole file unusable.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use regex::{Regex, RegexBuilder};
+use serde_json;
+use urlparse::{parse_qs, unquote, urlparse, urlunparse};
+
+use super::{encode_querystring, in_file, read_rules_file, remove_query_params_where};
+use error::Error;
+
+#[derive(Deserialize)]
+struct Catalogue {
+    providers: BTreeMap<String, ProviderData>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ProviderData {
+    url_pattern: String,
+    #[serde(default)]
+    complete_provider: bool,
+    #[serde(default)]
+    rules: Vec<String>,
+    #[serde(default)]
+    raw_rules: Vec<String>,
+    #[serde(default)]
+    referral_marketing: Vec<String>,
+    #[serde(default)]
+    exceptions: Vec<String>,
+    #[serde(default)]
+    redirections: Vec<String>,
+}
+
+/// What happened when we applied a provider to a URL.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Outcome {
+    Unchanged,
+    Redirected(String),
+    Cleaned(String),
+}
+
+/// A single ClearURLs provider, with all its patterns compiled.
+#[derive(Clone, Debug)]
+pub struct Provider {
+    pub name: String,
+    complete_provider: bool,
+    url_pattern: Regex,
+    rules: Vec<Regex>,
+    raw_rules: Vec<Regex>,
+    referral_marketing: Vec<Regex>,
+    exceptions: Vec<Regex>,
+    redirections: Vec<Regex>,
+}
+
+fn compile(pattern: &str) -> Result<Regex, String> {
+    RegexBuilder::new(pattern)
+        .case_insensitive(true)
+        .build()
+        .map_err(|e| format!("{}", e))
+}
+
+/// Compile a list of patterns that match the whole of a parameter name.
+fn compile_param_rules(patterns: &[String]) -> Result<Vec<Regex>, String> {
+    patterns
+        .iter()
+        .map(|p| compile(&format!("^(?:{})$", p)))
+        .collect()
+}
+
+fn compile_all(patterns: &[String]) -> Result<Vec<Regex>, String> {
+    patterns.iter().map(|p| compile(p)).collect()
+}
+
+impl Provider {
+    fn new(name: String, data: ProviderData) -> Result<Provider, String> {
+        Ok(Provider {
+            name,
+            complete_provider: data.complete_provider,
+            url_pattern: compile(&data.url_pattern)?,
+            rules: compile_param_rules(&data.rules)?,
+            raw_rules: compile_all(&data.raw_rules)?,
+            referral_marketing: compile_param_rules(&data.referral_marketing)?,
+            exceptions: compile_all(&data.exceptions)?,
+            redirections: compile_all(&data.redirections)?,
+        })
+    }
+
+    /// Does this provider apply to this URL?
+    pub fn matches(&self, url: &str) -> bool {
+        self.url_pattern.is_match(url) && !self.exceptions.iter().any(|e| e.is_match(url))
+    }
+
+    /// Apply this provider to a URL.
+    ///
+    /// * `allow_referral_marketing` - if true, keep the parameters listed
+    ///   in `referralMarketing`.
+    ///
+    pub fn apply(&self, url: &str, allow_referral_marketing: bool) -> Outcome {
+        if !self.matches(url) {
+            return Outcome::Unchanged;
+        }
+
+        for redirection in &self.redirections {
+            let target = redirection
+                .captures(url)
+                .and_then(|captures| captures.get(1));
+            if let Some(target) = target {
+                let target = target.as_str();
+                return Outcome::Redirected(unquote(target).unwrap_or_else(|_| target.to_owned()));
+            }
+        }
+
+        if self.complete_provider {
+            return Outcome::Unchanged;
+        }
+
+        let mut cleaned = url.to_owned();
+        for raw_rule in &self.raw_rules {
+            cleaned = raw_rule.replace_all(&cleaned, "").into_owned();
+        }
+
+        let should_remove = |name: &str| {
+            self.rules.iter().any(|r| r.is_match(name))
+                || (!allow_referral_marketing
+                    && self.referral_marketing.iter().any(|r| r.is_match(name)))
+        };
+
+        let mut parsed_url = urlparse(&cleaned);
+        remove_query_params_where(&mut parsed_url, should_remove);
+
+        // Some trackers put their parameters in the fragment instead,
+        // e.g. #utm_source=...  Only treat the fragment as parameters if
+        // every part of it looks like one, so we don't mangle anchors.
+        let fragment_params = match parsed_url.fragment {
+            Some(ref f) if f.split('&').all(|part| part.contains('=')) => {
+                let mut params = parse_qs(f);
+                params.retain(|name, _| !should_remove(name));
+                Some(encode_querystring(params))
+            }
+            _ => None,
+        };
+        if let Some(fragment) = fragment_params {
+            parsed_url.fragment = fragment;
+        }
+
+        let cleaned = urlunparse(parsed_url);
+        if cleaned == url {
+            Outcome::Unchanged
+        } else {
+            Outcome::Cleaned(cleaned)
+        }
+    }
+}
+
+/// A set of ClearURLs providers.
+#[derive(Clone, Debug, Default)]
+pub struct ClearUrls {
+    pub providers: Vec<Provider>,
+
+    /// Names of providers we couldn't use, because they have patterns
+    /// that don't compile as Rust regular expressions.
+    pub skipped: Vec<String>,
+
+    /// If true, don't remove `referralMarketing` parameters.
+    pub allow_referral_marketing: bool,
+}
+
+impl ClearUrls {
+    /// Parse a ClearURLs catalogue from its JSON representation.
+    pub fn from_json(json: &str) -> Result<ClearUrls, Error> {
+        let catalogue: Catalogue = match serde_json::from_str(json) {
+            Ok(v) => v,
+            Err(e) => {
+                return Err(Error::Rules {
+                    path: None,
+                    reason: format!("Unable to parse ClearURLs rules: {}", e),
+                })
+            }
+        };
+
+        let mut clearurls = ClearUrls::default();
+        for (name, data) in catalogue.providers {
+            match Provider::new(name.to_owned(), data) {
+                Ok(provider) => clearurls.providers.push(provider),
+                Err(_) => clearurls.skipped.push(name),
+            }
+        }
+        Ok(clearurls)
+    }
+
+    /// Read a ClearURLs catalogue from a file, e.g. a local copy of
+    /// <https://rules2.clearurls.xyz/data.minify.json>
+    pub fn read(path: &Path) -> Result<ClearUrls, Error> {
+        ClearUrls::from_json(&read_rules_file(path)?).map_err(|e| in_file(e, path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::path::Path;
+
+    use rules::clearurls::{ClearUrls, Outcome};
+    use rules::{load, RuleSet};
+    use test_support::temp_path;
+
+    fn fixture() -> ClearUrls {
+        ClearUrls::read(Path::new(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/fixtures/clearurls.json"
+        )))
+        .unwrap()
+    }
+
+    fn rules_with_fixture() -> RuleSet {
+        let mut rules = RuleSet::builtin();
+        rules.clearurls = Some(fixture());
+        rules
+    }
+
+    #[test]
+    fn test_read_catalogue() {
+        let clearurls = fixture();
+        let names: Vec<&str> = clearurls
+            .providers
+            .iter()
+            .map(|p| p.name.as_str())
+            .collect();
+        assert_eq!(
+            names,
+            vec!["amazon", "doubleclick", "globalRules", "google"]
+        );
+
+        // This provider uses lookahead, which the regex crate doesn't support
+        assert_eq!(clearurls.skipped, vec!["lookahead"]);
+    }
+
+    #[test]
+    fn test_invalid_catalogue_is_error() {
+        assert!(ClearUrls::from_json("{}").is_err());
+        assert!(ClearUrls::from_json(r#"{"providers": {"x": {}}}"#).is_err());
+        assert!(ClearUrls::read(Path::new("/does/not/exist.json")).is_err());
+    }
+
+    #[test]
+    fn test_provider_rules_and_raw_rules() {
+        let rules = rules_with_fixture();
+        assert_eq!(
+            rules.tidy(
+                "https://www.amazon.com/dp/B01DFKBL68/ref=sr_1_1?qid=123&sr=8-1&keywords=tea"
+            ),
+            "https://www.amazon.com/dp/B01DFKBL68?keywords=tea"
+        );
+    }
+
+    #[test]
+    fn test_global_rules() {
+        let rules = rules_with_fixture();
+        assert_eq!(
+            rules.tidy("https://example.org/post?fbclid=IwAR123&igshid=abc"),
+            "https://example.org/post"
+        );
+        assert_eq!(
+            rules.tidy("https://example.org/post#utm_source=feed&utm_medium=rss"),
+            "https://example.org/post"
+        );
+
+        // Ordinary fragments are left alone
+        assert_eq!(
+            rules.tidy("https://example.org/post#section-2"),
+            "https://example.org/post#section-2"
+        );
+    }
+
+    #[test]
+    fn test_referral_marketing() {
+        let mut rules = rules_with_fixture();
+        assert_eq!(
+            rules.tidy("https://www.amazon.com/dp/B01DFKBL68?tag=someone-21"),
+            "https://www.amazon.com/dp/B01DFKBL68"
+        );
+
+        rules.clearurls.as_mut().unwrap().allow_referral_marketing = true;
+        assert_eq!(
+            rules.tidy("https://www.amazon.com/dp/B01DFKBL68?tag=someone-21"),
+            "https://www.amazon.com/dp/B01DFKBL68?tag=someone-21"
+        );
+    }
+
+    #[test]
+    fn test_exceptions() {
+        let rules = rules_with_fixture();
+        assert_eq!(
+            rules.tidy("https://docs.google.com/document/d/123/edit?source=abc"),
+            "https://docs.google.com/document/d/123/edit?source=abc"
+        );
+    }
+
+    #[test]
+    fn test_redirections() {
+        let clearurls = fixture();
+        let google = clearurls
+            .providers
+            .iter()
+            .find(|p| p.name == "google")
+            .unwrap();
+        assert_eq!(
+            google.apply(
+                "https://www.google.com/url?sa=t&url=https%3A%2F%2Fexample.org%2Fpage&usg=abc",
+                false
+            ),
+            Outcome::Redirected(String::from("https://example.org/page"))
+        );
+    }
+
+    #[test]
+    fn test_complete_provider_is_not_cleaned() {
+        let clearurls = fixture();
+        let doubleclick = clearurls
+            .providers
+            .iter()
+            .find(|p| p.name == "doubleclick")
+            .unwrap();
+        assert_eq!(
+            doubleclick.apply("https://ad.doubleclick.net/ddm/ad?utm_source=x", false),
+            Outcome::Unchanged
+        );
+    }
+
+    #[test]
+    fn test_load_from_rules_file() {
+        let path = temp_path("clearurls.json");
+        let mut file = File::create(&path).unwrap();
+        write!(
+            file,
+            r#"{{"clearurls": {{"path": "{}/tests/fixtures/clearurls.json", "allow_referral_marketing": true}}}}"#,
+            env!("CARGO_MANIFEST_DIR")
+        )
+        .unwrap();
+
+        let rules = load(Some(path.to_str().unwrap())).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let clearurls = rules.clearurls.as_ref().unwrap();
+        assert_eq!(clearurls.providers.len(), 4);
+        assert!(clearurls.allow_referral_marketing);
+        assert_eq!(
+            rules.tidy("https://example.org/?fbclid=123"),
+            "https://example.org/"
+        );
+    }
+
+    #[test]
+    fn test_explain_names_provider() {
+        let explanation = rules_with_fixture().explain("https://example.org/?fbclid=123");
+        assert_eq!(explanation.output, "https://example.org/");
+        assert_eq!(explanation.steps.len(), 1);
+        assert_eq!(explanation.steps[0].rule, "clearurls/globalRules");
+    }
+}
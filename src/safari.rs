@@ -1,74 +1,556 @@
-use std::collections::{BTreeMap, HashMap};
-use std::fs::File;
+//! Talk to Safari: read and close open tabs, and read Reading List and
+//! iCloud Tabs from Safari's plist files.
+
+use std::cmp::Ordering;
+use std::path::Path;
 use std::process;
+use std::str::FromStr;
 
+use chrono::{DateTime, UTC};
 use plist::Plist;
 use tera::{Context, Tera};
 
-use applescript::run as run_applescript;
-use urls;
-
-macro_rules! error(
-  ($($arg:tt)*) => { { return Err(format!($($arg)*)) } }
-);
+use applescript::ScriptRunner;
+use cloudtabs;
+use error::Error;
+use jxa;
+use paths::SafariPaths;
+use plists::{self, Node};
+use rules::RuleSet;
 
 /// Returns true/false if Safari is running.
+///
+/// If we can't run `ps` to find out, we assume it isn't.
 pub fn is_safari_running() -> bool {
-    let cmd_result = process::Command::new("ps")
-        .arg("-eaf")
-        .output()
-        .expect("Unable to test if Safari is running.");
-    for line in String::from_utf8(cmd_result.stdout).unwrap().lines() {
-        if line.contains("Safari.app/Contents/MacOS/Safari") {
-            return true;
+    match process::Command::new("ps").arg("-eaf").output() {
+        Ok(cmd_result) => String::from_utf8_lossy(&cmd_result.stdout)
+            .lines()
+            .any(|line| line.contains("Safari.app/Contents/MacOS/Safari")),
+        Err(_) => false,
+    }
+}
+
+/// Returns an error if Safari isn't running.
+pub fn ensure_safari_is_running() -> Result<(), Error> {
+    if is_safari_running() {
+        Ok(())
+    } else {
+        Err(Error::SafariNotRunning)
+    }
+}
+
+/// A Safari window.
+///
+/// Safari's scripting dictionary doesn't say whether a window is a private
+/// browsing window, so we can't tell private windows apart.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Window {
+    /// Window index.  1 is frontmost.
+    pub index: u32,
+    /// Safari's ID for this window, which doesn't change when windows are
+    /// reordered or closed.
+    pub id: Option<u32>,
+    pub visible: bool,
+    pub minimised: bool,
+    pub tabs: Vec<Tab>,
+}
+
+/// A tab in a Safari window.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Tab {
+    /// Index of the window this tab is in.  1 is frontmost.
+    pub window: u32,
+    /// Tab index.  1 is leftmost.
+    pub index: u32,
+    /// The URL of the tab, or the reason we couldn't read it (e.g. a blank
+    /// new tab doesn't have a URL).  This is the URL as Safari has it, before
+    /// any tidying.
+    pub url: Result<String, Error>,
+    pub title: Option<String>,
+    /// True if this is the tab that's showing in its window.
+    pub is_current: bool,
+}
+
+impl Window {
+    fn from_jxa(window: jxa::Window) -> Window {
+        let index = window.index;
+        Window {
+            index,
+            id: window.id,
+            visible: window.visible,
+            minimised: window.miniaturized,
+            tabs: window
+                .tabs
+                .into_iter()
+                .map(|t| Tab {
+                    window: index,
+                    index: t.index,
+                    url: match t.url {
+                        Some(url) => Ok(url),
+                        None => Err(Error::TabUnreadable {
+                            window: index,
+                            tab: t.index,
+                            reason: "Safari doesn't have a URL for this tab".to_owned(),
+                        }),
+                    },
+                    title: t.title,
+                    is_current: t.visible,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Return every window in Safari, with all of its tabs.
+///
+/// Windows are in front-to-back order, and tabs are in left-to-right order.
+///
+/// We get everything with a single JXA script if we can, and only fall
+/// back to asking about each window and tab with AppleScript if that fails.
+///
+pub fn get_windows(runner: &dyn ScriptRunner) -> Result<Vec<Window>, Error> {
+    match jxa::get_all_windows(runner) {
+        Ok(windows) => Ok(windows.into_iter().map(Window::from_jxa).collect()),
+        Err(_) => get_windows_with_applescript(runner),
+    }
+}
+
+/// Return every tab in Safari, across all windows.
+pub fn get_all_tabs(runner: &dyn ScriptRunner) -> Result<Vec<Tab>, Error> {
+    Ok(get_windows(runner)?
+        .into_iter()
+        .flat_map(|w| w.tabs)
+        .collect())
+}
+
+/// Look up a single tab in Safari.
+///
+/// * `runner` - Runs the scripts that talk to Safari.
+/// * `window` - Window index.  1 is frontmost.  If None, assumes the
+///   frontmost window.
+/// * `tab` - Tab index.  1 is leftmost.  If None, assumes the current tab
+///   of the window.
+///
+/// If the JXA script fails, we only ask AppleScript about the tab we want,
+/// rather than every tab in every window.
+///
+pub fn get_tab(
+    runner: &dyn ScriptRunner,
+    window: Option<u32>,
+    tab: Option<u32>,
+) -> Result<Tab, Error> {
+    let window_index = window.unwrap_or(1);
+    let windows = match jxa::get_all_windows(runner) {
+        Ok(windows) => windows,
+        Err(_) => return get_tab_with_applescript(runner, window_index, tab),
+    };
+    let window = match windows
+        .into_iter()
+        .map(Window::from_jxa)
+        .find(|w| w.index == window_index)
+    {
+        Some(w) => w,
+        None => {
+            return Err(Error::NoSuchTab {
+                window: window_index,
+                tab,
+            })
         }
+    };
+
+    match tab {
+        Some(t_idx) => window
+            .tabs
+            .into_iter()
+            .find(|t| t.index == t_idx)
+            .ok_or(Error::NoSuchTab {
+                window: window_index,
+                tab,
+            }),
+        None => window
+            .tabs
+            .into_iter()
+            .find(|t| t.is_current)
+            .ok_or(Error::NoCurrentTab {
+                window: window_index,
+            }),
     }
-    false
 }
 
 /// Return a URL from a Safari window.
 ///
 /// Given a (window, tab) pair, this function looks up the URL of the tab.
-/// Note that it doesn't do any error handling, so will throw an execution
-/// error if it fails.
 ///
+/// * `runner` - Runs the scripts that talk to Safari.
 /// * `window` - Window index.  1 is frontmost.  If None, assumes the
-///              frontmost window.
+///   frontmost window.
 /// * `tab` - Tab index.  1 is leftmost.  If None, assumes the frontmost tab.
+/// * `rules` - Rules used to tidy the URL.
 ///
-pub fn get_url(window: Option<u32>, tab: Option<u32>) -> Result<String, String> {
-    match get_property(window, tab, "URL") {
-        Ok(url) => Ok(urls::tidy_url(&url)),
-        Err(e) => Err(e),
-    }
+pub fn get_url(
+    runner: &dyn ScriptRunner,
+    window: Option<u32>,
+    tab: Option<u32>,
+    rules: &RuleSet,
+) -> Result<String, Error> {
+    get_tab(runner, window, tab)?
+        .url
+        .map(|url| rules.tidy(&url))
 }
 
 /// Return a title from a Safari window.
 ///
-/// Given a (window, tab) pair, this function looks up the URL of the tab.
-/// Note that it doesn't do any error handling, so will throw an execution
-/// error if it fails.
+/// Given a (window, tab) pair, this function looks up the title of the tab.
 ///
+/// * `runner` - Runs the scripts that talk to Safari.
 /// * `window` - Window index.  1 is frontmost.  If None, assumes the
-///              frontmost window.
+///   frontmost window.
 /// * `tab` - Tab index.  1 is leftmost.  If None, assumes the frontmost tab.
 ///
-pub fn get_title(window: Option<u32>, tab: Option<u32>) -> Result<String, String> {
-    get_property(window, tab, "name")
+pub fn get_title(
+    runner: &dyn ScriptRunner,
+    window: Option<u32>,
+    tab: Option<u32>,
+) -> Result<String, Error> {
+    let tab = get_tab(runner, window, tab)?;
+    tab.title.ok_or(Error::TabUnreadable {
+        window: tab.window,
+        tab: tab.index,
+        reason: "Safari doesn't have a title for this tab".to_owned(),
+    })
+}
+
+/// Tests for the window and tab enumeration.
+#[cfg(test)]
+mod tests_windows {
+    use std::fs;
+
+    use applescript::{FakeRunner, Output};
+    use error::Error;
+    use rules::RuleSet;
+    use safari::{get_all_tabs, get_tab, get_title, get_url, get_windows, Tab};
+
+    fn fixture(name: &str) -> String {
+        fs::read_to_string(format!(
+            "{}/tests/fixtures/jxa/{}",
+            env!("CARGO_MANIFEST_DIR"),
+            name
+        ))
+        .unwrap()
+    }
+
+    fn jxa(name: &str) -> FakeRunner {
+        FakeRunner::new(vec![FakeRunner::ok(&fixture(name))])
+    }
+
+    /// A runner where the JXA script fails, so we fall back to asking
+    /// about each window and tab with AppleScript.
+    fn jxa_unavailable(mut outputs: Vec<Output>) -> FakeRunner {
+        outputs.insert(0, FakeRunner::err("osascript: unknown language JavaScript"));
+        FakeRunner::new(outputs)
+    }
+
+    fn tab(window: u32, index: u32, url: &str, title: &str, is_current: bool) -> Tab {
+        Tab {
+            window,
+            index,
+            url: Ok(url.to_owned()),
+            title: Some(title.to_owned()),
+            is_current,
+        }
+    }
+
+    #[test]
+    fn test_windows_come_from_one_jxa_script() {
+        let runner = jxa("two-windows.json");
+        let windows = get_windows(&runner).unwrap();
+        assert_eq!(runner.scripts().len(), 1);
+
+        assert_eq!(windows.len(), 2);
+        assert_eq!(windows[0].index, 1);
+        assert_eq!(windows[0].id, Some(4721));
+        assert!(!windows[0].minimised);
+        assert_eq!(
+            windows[0].tabs[1],
+            tab(
+                1,
+                2,
+                "https://mobile.twitter.com/alexwlchan",
+                "Alex Chan (@alexwlchan) / Twitter",
+                true
+            )
+        );
+        assert!(windows[1].minimised);
+        assert_eq!(windows[1].tabs.len(), 2);
+    }
+
+    #[test]
+    fn test_all_tabs_are_in_window_and_tab_order() {
+        let tabs = get_all_tabs(&jxa("two-windows.json")).unwrap();
+        let positions: Vec<(u32, u32)> = tabs.iter().map(|t| (t.window, t.index)).collect();
+        assert_eq!(positions, vec![(1, 1), (1, 2), (1, 3), (2, 1), (2, 2)]);
+    }
+
+    #[test]
+    fn test_tabs_without_urls_are_reported() {
+        let tabs = get_all_tabs(&jxa("unreadable-tabs.json")).unwrap();
+        assert_eq!(tabs.len(), 3);
+        assert!(tabs[0].url.is_err());
+        assert_eq!(tabs[0].title, Some("Untitled".to_owned()));
+        assert_eq!(tabs[1].url, Ok("https://example.org/".to_owned()));
+        assert_eq!(tabs[2].title, None);
+    }
+
+    #[test]
+    fn test_get_tab_defaults_to_current_tab_of_front_window() {
+        let t = get_tab(&jxa("two-windows.json"), None, None).unwrap();
+        assert_eq!((t.window, t.index), (1, 2));
+    }
+
+    #[test]
+    fn test_get_tab_in_window() {
+        let t = get_tab(&jxa("two-windows.json"), Some(2), None).unwrap();
+        assert_eq!((t.window, t.index), (2, 1));
+
+        let t = get_tab(&jxa("two-windows.json"), Some(1), Some(3)).unwrap();
+        assert_eq!(t.url, Ok("https://example.org/".to_owned()));
+    }
+
+    #[test]
+    fn test_get_tab_invalid_index() {
+        assert_eq!(
+            get_tab(&jxa("two-windows.json"), Some(3), None),
+            Err(Error::NoSuchTab {
+                window: 3,
+                tab: None
+            })
+        );
+        assert_eq!(
+            get_tab(&jxa("two-windows.json"), Some(1), Some(4)),
+            Err(Error::NoSuchTab {
+                window: 1,
+                tab: Some(4)
+            })
+        );
+        assert_eq!(
+            get_tab(&jxa("no-windows.json"), None, None),
+            Err(Error::NoSuchTab {
+                window: 1,
+                tab: None
+            })
+        );
+
+        // A window without any tabs doesn't have a current tab
+        assert_eq!(
+            get_tab(&jxa("unreadable-tabs.json"), Some(2), None),
+            Err(Error::NoCurrentTab { window: 2 })
+        );
+    }
+
+    #[test]
+    fn test_url_is_tidied() {
+        let url = get_url(&jxa("two-windows.json"), None, None, &RuleSet::builtin());
+        assert_eq!(url, Ok("https://twitter.com/alexwlchan".to_owned()));
+    }
+
+    #[test]
+    fn test_get_title() {
+        let title = get_title(&jxa("two-windows.json"), Some(2), Some(2));
+        assert_eq!(title, Ok("plist - Rust".to_owned()));
+
+        match get_title(&jxa("unreadable-tabs.json"), Some(1), Some(3)) {
+            Err(Error::TabUnreadable {
+                window: 1, tab: 3, ..
+            }) => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_jxa_errors_fall_back_to_applescript() {
+        let runner = jxa_unavailable(vec![
+            FakeRunner::ok("1"),
+            FakeRunner::ok("4721, true, false, 1, 1"),
+            FakeRunner::ok("https://example.org/"),
+            FakeRunner::ok("Example Domain"),
+        ]);
+        let windows = get_windows(&runner).unwrap();
+        assert_eq!(windows.len(), 1);
+        assert_eq!(windows[0].id, Some(4721));
+        assert_eq!(
+            windows[0].tabs,
+            vec![tab(1, 1, "https://example.org/", "Example Domain", true)]
+        );
+    }
+
+    #[test]
+    fn test_applescript_fallback_includes_last_tab_of_every_window() {
+        // Regression test: we used to iterate over 1..tab_count, which
+        // skipped the rightmost tab of every window.
+        let runner = jxa_unavailable(vec![
+            FakeRunner::ok("2"),
+            FakeRunner::ok("101, true, false, 3, 3"),
+            FakeRunner::ok("https://example.org/1"),
+            FakeRunner::ok("One"),
+            FakeRunner::ok("https://example.org/2"),
+            FakeRunner::ok("Two"),
+            FakeRunner::ok("https://example.org/3"),
+            FakeRunner::ok("Three"),
+            FakeRunner::ok("102, true, true, 1, 1"),
+            FakeRunner::ok("https://example.org/only"),
+            FakeRunner::ok("Only"),
+        ]);
+
+        let tabs = get_all_tabs(&runner).unwrap();
+        assert_eq!(
+            tabs,
+            vec![
+                tab(1, 1, "https://example.org/1", "One", false),
+                tab(1, 2, "https://example.org/2", "Two", false),
+                tab(1, 3, "https://example.org/3", "Three", true),
+                tab(2, 1, "https://example.org/only", "Only", true),
+            ]
+        );
+
+        let scripts = runner.scripts();
+        assert_eq!(
+            scripts[scripts.len() - 2],
+            "tell application \"Safari\" to get URL of tab 1 of window 2"
+        );
+    }
+
+    #[test]
+    fn test_applescript_fallback_reports_unreadable_tabs() {
+        let runner = jxa_unavailable(vec![
+            FakeRunner::ok("1"),
+            FakeRunner::ok("101, true, false, 1, 2"),
+            FakeRunner::err("execution error: Safari got an error: AppleEvent timed out. (-1712)"),
+            FakeRunner::err("execution error: Safari got an error: AppleEvent timed out. (-1712)"),
+            FakeRunner::ok("https://example.org/b"),
+            FakeRunner::ok("B"),
+        ]);
+
+        let tabs = get_all_tabs(&runner).unwrap();
+        assert_eq!(tabs.len(), 2);
+        match tabs[0].url {
+            Err(Error::TabUnreadable {
+                window: 1,
+                tab: 1,
+                ref reason,
+            }) => assert!(reason.contains("timed out"), "{}", reason),
+            ref other => panic!("Unexpected result: {:?}", other),
+        }
+        assert_eq!(tabs[0].title, None);
+        assert_eq!(tabs[1], tab(1, 2, "https://example.org/b", "B", false));
+    }
+
+    #[test]
+    fn test_applescript_fallback_only_reads_the_tab_we_want() {
+        let runner = jxa_unavailable(vec![
+            FakeRunner::ok("2, 3"),
+            FakeRunner::ok("https://example.org/"),
+            FakeRunner::ok("Example Domain"),
+        ]);
+        let t = get_tab(&runner, Some(2), None).unwrap();
+        assert_eq!(t, tab(2, 2, "https://example.org/", "Example Domain", true));
+        assert_eq!(
+            runner.scripts()[1..].to_vec(),
+            vec![
+                "tell window 2 of application \"Safari\" to get {index of current tab, count of tabs}",
+                "tell application \"Safari\" to get URL of tab 2 of window 2",
+                "tell application \"Safari\" to get name of tab 2 of window 2",
+            ]
+        );
+
+        let runner = jxa_unavailable(vec![
+            FakeRunner::ok("2, 3"),
+            FakeRunner::ok("https://example.org/"),
+            FakeRunner::ok("Example Domain"),
+        ]);
+        let url = get_url(&runner, None, Some(3), &RuleSet::builtin());
+        assert_eq!(url, Ok("https://example.org/".to_owned()));
+        assert_eq!(runner.scripts().len(), 4);
+    }
+
+    #[test]
+    fn test_applescript_fallback_invalid_index() {
+        let runner = jxa_unavailable(vec![FakeRunner::err(
+            "execution error: Safari got an error: Can\u{2019}t get window 3. Invalid index. (-1719)",
+        )]);
+        assert_eq!(
+            get_tab(&runner, Some(3), None),
+            Err(Error::NoSuchTab {
+                window: 3,
+                tab: None
+            })
+        );
+
+        let runner = jxa_unavailable(vec![FakeRunner::ok("1, 3")]);
+        assert_eq!(
+            get_tab(&runner, Some(1), Some(4)),
+            Err(Error::NoSuchTab {
+                window: 1,
+                tab: Some(4)
+            })
+        );
+
+        let runner = jxa_unavailable(vec![FakeRunner::ok("missing value, 0")]);
+        assert_eq!(
+            get_tab(&runner, None, None),
+            Err(Error::NoCurrentTab { window: 1 })
+        );
+    }
+
+    #[test]
+    fn test_applescript_fallback_skips_windows_that_error() {
+        let runner = jxa_unavailable(vec![
+            FakeRunner::ok("2"),
+            FakeRunner::err(
+                "execution error: Safari got an error: Can\u{2019}t get window 1. (-1728)",
+            ),
+            FakeRunner::ok("102, true, false, 1, 1"),
+            FakeRunner::ok("https://example.org/"),
+            FakeRunner::ok("Example Domain"),
+        ]);
+        let windows = get_windows(&runner).unwrap();
+        let indices: Vec<u32> = windows.iter().map(|w| w.index).collect();
+        assert_eq!(indices, vec![2]);
+    }
+
+    #[test]
+    fn test_applescript_fallback_with_no_windows() {
+        let runner = jxa_unavailable(vec![FakeRunner::ok("0")]);
+        assert!(get_windows(&runner).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_applescript_fallback_window_count_error_is_reported() {
+        let runner = jxa_unavailable(vec![FakeRunner::err(
+            "execution error: Safari got an error: Connection is invalid. (-609)",
+        )]);
+        match get_windows(&runner) {
+            Err(Error::Script(e)) => assert!(e.contains("Connection is invalid"), "{}", e),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
 }
 
 /// Look up a property on a Safari window.
 ///
 /// Given a (window, tab) pair, this function looks up the property of that tab.
-/// Note that it doesn't do any error handling, so will throw an execution
-/// error if it fails.
 ///
+/// * `runner` - Runs the AppleScript that talks to Safari.
 /// * `window` - Window index.  1 is frontmost.  If None, assumes the
-///              frontmost window.
+///   frontmost window.
 /// * `tab` - Tab index.  1 is leftmost.  If None, assumes the frontmost tab.
 /// * `property` - Name of the property, as defined in the OSA scripting dictionary.
 ///
-fn get_property(window: Option<u32>, tab: Option<u32>, property: &str) -> Result<String, String> {
+fn get_property(
+    runner: &dyn ScriptRunner,
+    window: Option<u32>,
+    tab: Option<u32>,
+    property: &str,
+) -> Result<String, Error> {
     // If a tab isn't specified, assume the user wants the frontmost tab.
     let command = match window {
         Some(w_idx) => match tab {
@@ -86,409 +568,1069 @@ fn get_property(window: Option<u32>, tab: Option<u32>, property: &str) -> Result
             property
         ),
     };
-    let output = run_applescript(&command);
+    let output = runner.run(&command);
 
     if output.status.success() {
         Ok(output.stdout.trim().to_owned())
+    } else if output.stderr.contains("Invalid index") {
+        Err(Error::NoSuchTab {
+            window: window.unwrap_or(1),
+            tab,
+        })
     } else {
-        if output.stderr.contains("Invalid index") {
-            error!("Invalid index: no such window or tab.")
-        } else {
-            error!("Unexpected error from osascript: {:?}", output.stderr)
-        }
+        Err(Error::Script(format!(
+            "Unexpected error from osascript: {:?}",
+            output.stderr
+        )))
     }
 }
 
 /// Tests for get_property().
 #[cfg(test)]
 mod tests_property {
+    use applescript::FakeRunner;
+    use error::Error;
     use safari::get_property;
 
     #[test]
     fn test_invalid_property_is_rejected() {
-        let result = get_property(None, None, "fooble");
+        let runner = FakeRunner::new(vec![FakeRunner::err(
+            "36:42: syntax error: A identifier can\u{2019}t go after this identifier. (-2740)",
+        )]);
+        let result = get_property(&runner, None, None, "fooble");
         assert!(result.is_err());
     }
+
+    #[test]
+    fn test_frontmost_tab_of_frontmost_window() {
+        let runner = FakeRunner::new(vec![FakeRunner::ok("https://example.org/")]);
+        let result = get_property(&runner, None, None, "URL");
+        assert_eq!(result, Ok("https://example.org/".to_owned()));
+        assert_eq!(
+            runner.scripts(),
+            vec!["tell application \"Safari\" to get URL of document 1"]
+        );
+    }
+
+    #[test]
+    fn test_frontmost_tab_of_window() {
+        let runner = FakeRunner::new(vec![FakeRunner::ok("Example Domain")]);
+        let result = get_property(&runner, Some(2), None, "name");
+        assert_eq!(result, Ok("Example Domain".to_owned()));
+        assert_eq!(
+            runner.scripts(),
+            vec!["tell application \"Safari\" to get name of document 2"]
+        );
+    }
+
+    #[test]
+    fn test_specific_tab_of_window() {
+        let runner = FakeRunner::new(vec![FakeRunner::ok("https://example.org/")]);
+        let result = get_property(&runner, Some(2), Some(3), "URL");
+        assert_eq!(result, Ok("https://example.org/".to_owned()));
+        assert_eq!(
+            runner.scripts(),
+            vec!["tell application \"Safari\" to get URL of tab 3 of window 2"]
+        );
+    }
+
+    #[test]
+    fn test_invalid_index_is_reported() {
+        let runner = FakeRunner::new(vec![FakeRunner::err(
+            "execution error: Safari got an error: Can\u{2019}t get window 5. Invalid index. (-1719)",
+        )]);
+        let result = get_property(&runner, Some(5), None, "URL");
+        assert_eq!(
+            result,
+            Err(Error::NoSuchTab {
+                window: 5,
+                tab: None
+            })
+        );
+    }
+
+    #[test]
+    fn test_other_errors_are_reported() {
+        let runner = FakeRunner::new(vec![FakeRunner::err(
+            "execution error: Safari got an error: AppleEvent timed out. (-1712)",
+        )]);
+        match get_property(&runner, None, None, "URL") {
+            Err(Error::Script(e)) => assert!(e.contains("AppleEvent timed out"), "{}", e),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
 }
 
-/// Return a list of URLs from Safari.
-///
-/// This returns a list of URLs, one for every tab in Safari.  Iteration
-/// order depends on AppleScript, which I don't think is guaranteed to be
-/// stable (in particular, I think it depends on which window is frontmost).
-///
-pub fn get_all_urls() -> Vec<String> {
-    let windows = get_window_tab_count_pairs();
-    let mut urls = vec![];
-    for window in windows {
-        for tab in 1..window.tab_count {
-            match get_url(Some(window.window_index), Some(tab)) {
-                Ok(u) => urls.push(u),
-                Err(_) => {}
+/// Read the URL and title of a tab with AppleScript.
+fn read_tab_with_applescript(
+    runner: &dyn ScriptRunner,
+    window: u32,
+    index: u32,
+    is_current: bool,
+) -> Tab {
+    Tab {
+        window,
+        index,
+        url: get_property(runner, Some(window), Some(index), "URL").map_err(|e| {
+            Error::TabUnreadable {
+                window,
+                tab: index,
+                reason: e.to_string(),
             }
+        }),
+        title: get_property(runner, Some(window), Some(index), "name").ok(),
+        is_current,
+    }
+}
+
+/// Look up a single tab by asking AppleScript about just that window and
+/// tab.  This is the fallback for get_tab() if the JXA script fails.
+fn get_tab_with_applescript(
+    runner: &dyn ScriptRunner,
+    window: u32,
+    tab: Option<u32>,
+) -> Result<Tab, Error> {
+    let r = runner.run(&format!(
+        "tell window {} of application \"Safari\" to get {{index of current tab, count of tabs}}",
+        window
+    ));
+    if !r.status.success() {
+        if r.stderr.contains("Invalid index") {
+            return Err(Error::NoSuchTab { window, tab: None });
+        }
+        return Err(Error::Script(format!(
+            "Unable to read window {}: {:?}",
+            window, r.stderr
+        )));
+    }
+
+    // The output is a comma-separated list, e.g. "2, 3".  If the window
+    // doesn't have a current tab, the index is "missing value".
+    let fields: Vec<&str> = r.stdout.trim().split(", ").collect();
+    let tab_count = match fields.get(1).map(|n| n.parse::<u32>()) {
+        Some(Ok(n)) => n,
+        _ => {
+            return Err(Error::Script(format!(
+                "Unexpected output from osascript: {:?}",
+                r.stdout
+            )))
+        }
+    };
+    let current_tab = fields[0].parse::<u32>().ok();
+
+    let index = match tab {
+        Some(t) if t >= 1 && t <= tab_count => t,
+        Some(_) => return Err(Error::NoSuchTab { window, tab }),
+        None => current_tab.ok_or(Error::NoCurrentTab { window })?,
+    };
+    Ok(read_tab_with_applescript(
+        runner,
+        window,
+        index,
+        current_tab == Some(index),
+    ))
+}
+
+/// Get every window and tab in Safari by asking AppleScript about each
+/// window and tab in turn.  This is much slower than the JXA script.
+///
+/// There are lots of bugs in Safari's AppleScript handler, so knowing
+/// that there are N windows does not imply you can look up the tabs for
+/// each window 1, ..., N.  There might be gaps -- looking up a window
+/// in the middle could crash the AppleScript handler -- so we skip any
+/// window we can't read.
+fn get_windows_with_applescript(runner: &dyn ScriptRunner) -> Result<Vec<Window>, Error> {
+    let r = runner.run("tell application \"Safari\" to get count of windows");
+    if !r.status.success() {
+        return Err(Error::Script(format!(
+            "Unable to count Safari windows: {:?}",
+            r.stderr
+        )));
+    }
+    let window_count = match r.stdout.trim().parse::<u32>() {
+        Ok(v) => v,
+        Err(_) => {
+            return Err(Error::Script(format!(
+                "Unexpected window count from osascript: {:?}",
+                r.stdout
+            )))
         }
+    };
+
+    let mut windows = vec![];
+    for index in 1..=window_count {
+        let r = runner.run(&format!(
+            "tell window {} of application \"Safari\" to get {{id, visible, miniaturized, index of current tab, count of tabs}}",
+            index
+        ));
+        if !r.status.success() {
+            continue;
+        }
+
+        // The output is a comma-separated list, e.g. "4721, true, false, 1, 3"
+        let fields: Vec<&str> = r.stdout.trim().split(", ").collect();
+        if fields.len() != 5 {
+            continue;
+        }
+        let (current_tab, tab_count) = match (fields[3].parse::<u32>(), fields[4].parse::<u32>()) {
+            (Ok(c), Ok(n)) => (c, n),
+            _ => continue,
+        };
+
+        let tabs = (1..=tab_count)
+            .map(|t| read_tab_with_applescript(runner, index, t, t == current_tab))
+            .collect();
+
+        windows.push(Window {
+            index,
+            id: fields[0].parse().ok(),
+            visible: fields[1] == "true",
+            minimised: fields[2] == "true",
+            tabs,
+        });
     }
-    urls
+    Ok(windows)
 }
 
-/// Convert URL patterns into AppleScript conditions.
+/// Does this URL match a close-tabs pattern?
 ///
-/// These conditions can be used in an `if` statement in AppleScript to
-/// decide whether a tab should be closed.  Three patterns are supported,
-/// a limited regex syntax:
+/// Three patterns are supported, a limited regex syntax:
 ///
-///     example.com             matches anywhere in the URL
-///     ^http://examples.com    matches at the start of the URL
-///     example.com/$           matches at the end of the URL
+/// ```text
+/// example.com             matches anywhere in the URL
+/// ^http://examples.com    matches at the start of the URL
+/// example.com/$           matches at the end of the URL
+/// ```
 ///
-fn parse_conditions(url_patterns: Vec<&str>) -> Vec<String> {
-    url_patterns
-        .iter()
-        .map(|p| {
-            if p.starts_with("^") {
-                format!("starts with \"{}\"", p.replace("^", ""))
-            } else if p.ends_with("$") {
-                format!("ends with \"{}\"", p.replace("$", ""))
-            } else {
-                format!("contains \"{}\"", p)
-            }
-        })
-        .collect()
+fn matches_pattern(url: &str, pattern: &str) -> bool {
+    if pattern.starts_with('^') {
+        url.starts_with(&pattern.replace('^', ""))
+    } else if pattern.ends_with('$') {
+        url.ends_with(&pattern.replace('$', ""))
+    } else {
+        url.contains(pattern)
+    }
 }
 
-/// Tests for parse_conditions().
+/// Tests for matches_pattern().
 #[cfg(test)]
 mod tests {
-    use safari::parse_conditions;
+    use safari::matches_pattern;
 
     #[test]
-    fn test_parse_conditions() {
-        let patterns = vec!["github.com", "^facebook.com", "twitter.com$"];
-        let expected = vec![
-            "contains \"github.com\"",
-            "starts with \"facebook.com\"",
-            "ends with \"twitter.com\"",
-        ];
-        let actual = parse_conditions(patterns);
-        assert_eq!(actual, expected);
+    fn test_matches_pattern() {
+        assert!(matches_pattern(
+            "https://github.com/alexwlchan",
+            "github.com"
+        ));
+        assert!(matches_pattern("facebook.com/alexwlchan", "^facebook.com"));
+        assert!(matches_pattern("https://twitter.com", "twitter.com$"));
+
+        assert!(!matches_pattern("https://example.org", "github.com"));
+        assert!(!matches_pattern("https://facebook.com", "^facebook.com"));
+        assert!(!matches_pattern(
+            "https://twitter.com/alexwlchan",
+            "twitter.com$"
+        ));
     }
 }
 
-/// Close tabs in Safari that match URL patterns.
+/// A tab to close, for the clean-tabs script.
+#[derive(Serialize)]
+struct CloseTarget {
+    /// How to refer to the window in AppleScript, e.g. "window id 4721"
+    window: String,
+    index: u32,
+}
+
+/// Build the AppleScript that closes the given tabs.
 ///
-/// Takes a list of URL patterns, and tries to close any matching Safari tabs.
-/// Bugginess in AppleScript means this isn't always perfect, but we can
-/// have a go.  In general it will fail to close tabs, rather than close
-/// the wrong tabs.
+/// We refer to windows by ID where we can, because closing the last tab
+/// in a window closes the window, and changes the index of the windows
+/// behind it.  Within a window, we close tabs from right to left, so
+/// closing a tab doesn't change the index of tabs we haven't closed yet.
 ///
-pub fn close_tabs(url_patterns: Vec<&str>) {
-    let conditions = parse_conditions(url_patterns);
+fn close_tabs_script(windows: &[Window], to_close: &[&Tab]) -> Result<String, Error> {
+    let mut targets = vec![];
+    for window in windows {
+        let window_ref = match window.id {
+            Some(id) => format!("window id {}", id),
+            None => format!("window {}", window.index),
+        };
+        let mut indices: Vec<u32> = to_close
+            .iter()
+            .filter(|t| t.window == window.index)
+            .map(|t| t.index)
+            .collect();
+        indices.sort_unstable_by(|a, b| b.cmp(a));
+        for index in indices {
+            targets.push(CloseTarget {
+                window: window_ref.clone(),
+                index,
+            });
+        }
+    }
 
     let clean_tabs_template = include_str!("scripts/clean-tabs.scpt");
     let mut context = Context::new();
-    context.add("conditions", &conditions);
-    let script = Tera::one_off(&clean_tabs_template, &context, false).unwrap();
+    context.add("tabs", &targets);
+    Tera::one_off(clean_tabs_template, &context, false)
+        .map_err(|e| Error::Script(format!("Unable to build the clean-tabs script: {}", e)))
+}
+
+/// Close tabs in Safari that match URL patterns.
+///
+/// Takes a list of URL patterns, and closes any Safari tabs whose URL
+/// matches one of them.  Returns the tabs that we closed.
+///
+pub fn close_tabs(runner: &dyn ScriptRunner, url_patterns: Vec<&str>) -> Result<Vec<Tab>, Error> {
+    let windows = get_windows(runner)?;
+    let to_close: Vec<&Tab> = windows
+        .iter()
+        .flat_map(|w| w.tabs.iter())
+        .filter(|t| match t.url {
+            Ok(ref url) => url_patterns.iter().any(|p| matches_pattern(url, p)),
+            Err(_) => false,
+        })
+        .collect();
+
+    if to_close.is_empty() {
+        return Ok(vec![]);
+    }
 
-    // Run it twice to get around weird AppleScript bugs.
-    run_applescript(&script);
-    run_applescript(&script);
+    let output = runner.run(&close_tabs_script(&windows, &to_close)?);
+    if !output.status.success() {
+        return Err(Error::Script(format!(
+            "Unexpected error from osascript: {:?}",
+            output.stderr
+        )));
+    }
+    Ok(to_close.into_iter().cloned().collect())
 }
 
-/// Get the Bookmarks.plist dict for a given title
-fn read_bookmarks_plist(title: &str) -> Result<Plist, String> {
-    // All Safari data lives at ~/Library/Safari/Bookmarks.plist
-    // TODO: There's probably a more idiomatic Rust-like way to write this.
-    let mut plist_path = match dirs::home_dir() {
-        Some(v) => v,
-        None => error!("Unable to get home directory?"),
-    };
-    plist_path.push("Library/Safari/Bookmarks.plist");
+/// Tests for close_tabs().
+#[cfg(test)]
+mod tests_close_tabs {
+    use std::fs;
 
-    let file = match File::open(plist_path) {
-        Ok(v) => v,
-        Err(e) => error!("Unable to open ~/Library/Safari/Bookmarks.plist: {:?}", e),
-    };
+    use applescript::FakeRunner;
+    use safari::close_tabs;
 
-    let plist = match Plist::read(file) {
-        Ok(v) => v,
-        Err(e) => error!("Unable to read ~/Library/Safari/Bookmarks.plist: {:?}", e),
-    };
+    fn two_windows() -> String {
+        fs::read_to_string(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/fixtures/jxa/two-windows.json"
+        ))
+        .unwrap()
+    }
 
-    let data = match plist.as_dictionary() {
-        Some(v) => v,
-        None => error!("Unable to parse ~/Library/Safari/Bookmarks.plist as dictionary?"),
-    };
+    #[test]
+    fn test_close_tabs_by_window_id_from_right_to_left() {
+        let runner = FakeRunner::new(vec![FakeRunner::ok(&two_windows()), FakeRunner::ok("")]);
+        let closed = close_tabs(
+            &runner,
+            vec!["github.com", "^https://example.org", "plist$"],
+        )
+        .unwrap();
 
-    // The structure of Bookmarks.plist is as follows:
-    //
-    //     <dict>
-    //       <key>Children</key>
-    //       <array>
-    //         <dict>
-    //           <key>Title</key><string>History</string>
-    //           ... dict data ...
-    //         </dict>
-    //         <dict>
-    //           <key>Title</key><string>com.apple.ReadingList</string>
-    //           ... dict data ...
-    //         </dict>
-    //         ... other array items ...
-    //       </array>
-    //     </dict>
-    //
-    let children = match data.get("Children") {
-        Some(child_key) => match child_key.as_array() {
-            Some(v) => v,
-            None => {
-                error!("Top-level children key in ~/Library/Safari/Bookmarks.plist isn't an array?")
-            }
-        },
-        None => error!("Unable to find top-level Children key in ~/Library/Safari/Bookmarks.plist"),
-    };
+        let positions: Vec<(u32, u32)> = closed.iter().map(|t| (t.window, t.index)).collect();
+        assert_eq!(positions, vec![(1, 1), (1, 3), (2, 2)]);
 
-    let mut matching_children = children.iter().filter(|d| match d.as_dictionary() {
-        Some(dict) => match dict.get("Title") {
-            Some(title_elem) => match title_elem.as_string() {
-                Some(v) => v == title,
-                None => false,
-            },
-            None => false,
-        },
-        None => false,
-    });
+        let scripts = runner.scripts();
+        assert_eq!(scripts.len(), 2);
+        let close_lines: Vec<&str> = scripts[1]
+            .lines()
+            .map(|l| l.trim())
+            .filter(|l| l.starts_with("close"))
+            .collect();
+        assert_eq!(
+            close_lines,
+            vec![
+                "close tab 3 of window id 4721",
+                "close tab 1 of window id 4721",
+                "close tab 2 of window id 4735",
+            ]
+        );
+        assert!(scripts[1].starts_with("tell application \"Safari\""));
+    }
+
+    #[test]
+    fn test_no_matching_tabs_runs_no_script() {
+        let runner = FakeRunner::new(vec![FakeRunner::ok(&two_windows())]);
+        let closed = close_tabs(&runner, vec!["nothing-matches-this"]).unwrap();
+        assert!(closed.is_empty());
+        assert_eq!(runner.scripts().len(), 1);
+    }
+
+    #[test]
+    fn test_close_error_is_reported() {
+        let runner = FakeRunner::new(vec![
+            FakeRunner::ok(&two_windows()),
+            FakeRunner::err("execution error: Safari got an error: AppleEvent timed out. (-1712)"),
+        ]);
+        assert!(close_tabs(&runner, vec!["github.com"]).is_err());
+    }
+}
+
+/// Find the top-level folder with a given title in Bookmarks.plist.
+///
+/// The structure of Bookmarks.plist is as follows:
+///
+/// ```text
+/// <dict>
+///   <key>Children</key>
+///   <array>
+///     <dict>
+///       <key>Title</key><string>History</string>
+///       ... dict data ...
+///     </dict>
+///     <dict>
+///       <key>Title</key><string>com.apple.ReadingList</string>
+///       ... dict data ...
+///     </dict>
+///     ... other array items ...
+///   </array>
+/// </dict>
+/// ```
+fn find_bookmarks_folder<'a>(root: &Node<'a>, title: &str) -> Result<Node<'a>, Error> {
+    let children = root.key("Children")?;
+    let mut matching_children = children
+        .items()?
+        .into_iter()
+        .filter(|child| child.optional_string("Title").as_deref() == Some(title));
 
     // Check we got one, and only one result.
     let result = match matching_children.next() {
         Some(v) => v,
-        None => error!("Unable to find key {} in Bookmarks.plist", title),
+        None => return Err(children.malformed(&format!("no folder titled {}", title))),
     };
+    if matching_children.next().is_some() {
+        return Err(children.malformed(&format!("more than one folder titled {}", title)));
+    }
+    Ok(result)
+}
 
-    match matching_children.next() {
-        Some(_) => error!("Got more than one result for {} in Bookmarks.plist", title),
-        None => {}
-    };
+/// An item in Reading List.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReadingListItem {
+    pub url: String,
+    pub title: Option<String>,
+    /// When the item was added to Reading List.
+    pub date_added: Option<DateTime<UTC>>,
+    /// When the item was last opened.  This is None for unread items.
+    pub date_last_viewed: Option<DateTime<UTC>>,
+    /// The snippet of text Safari shows under the title.
+    pub preview_text: Option<String>,
+    /// The URL of the thumbnail Safari shows next to the title.
+    pub image_url: Option<String>,
+}
+
+impl ReadingListItem {
+    /// Safari only records DateLastViewed once you've opened an item.
+    pub fn is_unread(&self) -> bool {
+        self.date_last_viewed.is_none()
+    }
+}
 
-    Ok(result.to_owned())
+/// The order to print Reading List items in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SortOrder {
+    NewestFirst,
+    OldestFirst,
 }
 
-/// Return a list of URLs from Reading List.
+impl FromStr for SortOrder {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<SortOrder, Error> {
+        match s {
+            "newest" => Ok(SortOrder::NewestFirst),
+            "oldest" => Ok(SortOrder::OldestFirst),
+            _ => Err(Error::Usage(format!(
+                "Unrecognised sort order {:?}; expected newest or oldest",
+                s
+            ))),
+        }
+    }
+}
+
+/// Which Reading List items to print, and in what order.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ReadingListQuery {
+    /// If set, only items which have (or haven't) been read.
+    pub read: Option<bool>,
+    /// If set, only items added on or after this date.  Items without a
+    /// DateAdded are left out.
+    pub since: Option<DateTime<UTC>>,
+    /// If set, sort by date added.  Items without a DateAdded go last.
+    pub sort: Option<SortOrder>,
+}
+
+impl ReadingListQuery {
+    pub fn apply(&self, items: Vec<ReadingListItem>) -> Vec<ReadingListItem> {
+        let mut items: Vec<ReadingListItem> = items
+            .into_iter()
+            .filter(|item| match self.read {
+                Some(read) => item.is_unread() != read,
+                None => true,
+            })
+            .filter(|item| match (self.since, item.date_added) {
+                (Some(since), Some(added)) => added >= since,
+                (Some(_), None) => false,
+                (None, _) => true,
+            })
+            .collect();
+
+        if let Some(order) = self.sort {
+            // sort_by is stable, so items added at the same time (or without
+            // a date) stay in the order they appear in Bookmarks.plist.
+            items.sort_by(|a, b| match (a.date_added, b.date_added) {
+                (Some(a), Some(b)) => match order {
+                    SortOrder::NewestFirst => b.cmp(&a),
+                    SortOrder::OldestFirst => a.cmp(&b),
+                },
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (None, None) => Ordering::Equal,
+            });
+        }
+        items
+    }
+}
+
+/// Return the items in Reading List.
 ///
 /// Iteration order depends on the order in which they're stored in
 /// Bookmarks.plist, which is usually (but not guaranteed to be) newest first.
+/// Use a `ReadingListQuery` to sort them by date.
 ///
-pub fn get_reading_list_urls() -> Result<Vec<String>, String> {
-    let plist = match read_bookmarks_plist("com.apple.ReadingList") {
-        Ok(v) => v,
-        Err(e) => return Err(e),
+pub fn get_reading_list(
+    paths: &SafariPaths,
+    rules: &RuleSet,
+) -> Result<Vec<ReadingListItem>, Error> {
+    let plist = plists::read(&paths.bookmarks)?;
+    parse_reading_list(&Node::root(&plist, &paths.bookmarks), rules)
+}
+
+/// Parse Reading List from Bookmarks.plist.  Each item looks something
+/// like this:
+///
+/// ```text
+/// <dict>
+///   <key>URLString</key><string>[[ URL ]]</string>
+///   <key>URIDictionary</key>
+///   <dict>
+///     <key>title</key><string>[[ Title ]]</string>
+///   </dict>
+///   <key>ReadingList</key>
+///   <dict>
+///     <key>DateAdded</key><date>[[ Date ]]</date>
+///     <key>DateLastViewed</key><date>[[ Date ]]</date>
+///     <key>PreviewText</key><string>[[ Preview text ]]</string>
+///   </dict>
+///   <key>imageURL</key><string>[[ URL ]]</string>
+/// </dict>
+/// ```
+///
+/// Only the URL is required; any of the other keys may be missing.
+fn parse_reading_list(root: &Node, rules: &RuleSet) -> Result<Vec<ReadingListItem>, Error> {
+    let reading_list = find_bookmarks_folder(root, "com.apple.ReadingList")?;
+
+    // If Reading List is empty, there isn't a Children key.
+    let children = match reading_list.get("Children")? {
+        Some(children) => children.items()?,
+        None => return Ok(vec![]),
     };
 
-    // TODO: All these unwrap() calls should probably be handled better
-    let children = plist
-        .as_dictionary()
-        .unwrap()
-        .get("Children")
-        .unwrap()
-        .as_array()
-        .unwrap();
+    let mut items = vec![];
+    for child in children {
+        let url = child.key("URLString")?.as_str()?;
+        let title = child
+            .get("URIDictionary")?
+            .and_then(|d| d.optional_string("title"));
+        let metadata = child.get("ReadingList")?;
+        items.push(ReadingListItem {
+            url: rules.tidy(url),
+            title,
+            date_added: metadata.as_ref().and_then(|d| d.optional_date("DateAdded")),
+            date_last_viewed: metadata
+                .as_ref()
+                .and_then(|d| d.optional_date("DateLastViewed")),
+            preview_text: metadata
+                .as_ref()
+                .and_then(|d| d.optional_string("PreviewText")),
+            image_url: child.optional_string("imageURL"),
+        });
+    }
+    Ok(items)
+}
 
-    Ok(children
-        .iter()
-        .map(|child| {
-            child
-                .as_dictionary()
-                .unwrap()
-                .get("URLString")
-                .unwrap()
-                .as_string()
-                .unwrap()
-        })
-        .map(|url| urls::tidy_url(url))
-        .collect())
+/// Get the com.apple.Safari.plist preferences file, which has iCloud Tabs
+/// in older versions of Safari.
+///
+/// The structure of com.apple.Safari.plist is as follows:
+///
+/// ```text
+/// <dict>
+///   <key>values</key>
+///   <dict>
+///     <key>[[ device UUID ]]</key>
+///     <dict>
+///       ... dict data ...
+///       <key>value</key>
+///       <dict>
+///         <key>DeviceName</key><string>[[ device name ]]</string>
+///         <key>LastModified</key><date>[[ Date ]]</date>
+///         <key>Tabs</key>
+///         <array>
+///           <dict>
+///             <key>Title</key><string>[[ Title ]]</string>
+///             <key>URL</key><string>[[ URL ]]</string>
+///             ... other tab data ...
+///           </dict>
+///           ... array items for other tabs ...
+///         </array>
+///       </dict>
+///     </dict>
+///     ... other devices ...
+///   </dict>
+/// </dict>
+/// ```
+fn read_safari_plist(path: &Path) -> Result<Plist, Error> {
+    plists::read(path)
 }
 
-/// Get the com.apple.Safari.plist preferences file
-fn read_safari_plist() -> Result<BTreeMap<String, Plist>, String> {
-    // All Safari data lives at ~/Library/Safari/Bookmarks.plist
-    // TODO: There's probably a more idiomatic Rust-like way to write this.
-    let mut plist_path = match dirs::home_dir() {
-        Some(v) => v,
-        None => error!("Unable to get home directory?"),
-    };
-    plist_path.push("Library/SyncedPreferences/com.apple.Safari.plist");
+/// A device in iCloud Tabs.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CloudDevice {
+    /// The key for this device in com.apple.Safari.plist.  Device names
+    /// aren't unique (you might have two phones called "Alex's iPhone"),
+    /// so this is how we tell devices apart.
+    pub uuid: String,
+    pub name: String,
+    /// When this device last synced its tabs to iCloud.
+    pub last_modified: Option<DateTime<UTC>>,
+    /// The tabs open on this device, in the order Safari shows them.
+    pub tabs: Vec<CloudTab>,
+}
 
-    let file = match File::open(plist_path) {
-        Ok(v) => v,
-        Err(e) => error!("Unable to open com.apple.Safari.plist: {:?}", e),
-    };
+/// A tab open on another device, from iCloud Tabs.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CloudTab {
+    /// Position of this tab on its device, starting from 1.
+    pub index: u32,
+    pub url: String,
+    pub title: Option<String>,
+}
 
-    let plist = match Plist::read(file) {
-        Ok(v) => v,
-        Err(e) => error!("Unable to read com.apple.Safari.plist: {:?}", e),
-    };
+/// Return every device in iCloud Tabs, in the order of their UUIDs.
+///
+/// We read CloudTabs.db if it exists, and com.apple.Safari.plist if not.
+pub fn get_icloud_devices(paths: &SafariPaths, rules: &RuleSet) -> Result<Vec<CloudDevice>, Error> {
+    if paths.cloud_tabs_db.exists() {
+        return cloudtabs::read(&paths.cloud_tabs_db, rules);
+    }
 
-    let data = match plist.as_dictionary() {
-        Some(v) => v,
-        None => error!("Unable to parse com.apple.Safari.plist as dictionary?"),
-    };
+    let plist = read_safari_plist(&paths.synced_preferences)?;
+    parse_icloud_devices(&Node::root(&plist, &paths.synced_preferences), rules)
+}
 
-    // The structure of com.apple.Safari.plist is as follows:
-    //
-    //      <dict>
-    //        <key>values</key>
-    //        <dict>
-    //          <dict>
-    //            <key>[[ UUID ]]</key>
-    //            <dict>
-    //              ... dict data ...
-    //              <key>value</key>
-    //              <dict>
-    //                ... dict data ...
-    //                <key>DeviceName</key><string>[[ Device name ]]</string>
-    //                <key>Tabs</key>
-    //                <array>
-    //                  <dict>
-    //                    <key>Title</key><string>[[ Title ]]</string>
-    //                    <key>URL</key><string>[[ URL ]]</string>
-    //                  </dict>
-    //                  ... array items for other tabs ...
-    //                </array>
-    //              </dict>
-    //            </dict>
-    //            ... dicts for other devices ...
-    //          </dict>
-    //        </dict>
-    //      </dict>
-    //
-    match data.get("values") {
-        Some(child_key) => match child_key.as_dictionary() {
-            Some(v) => Ok(v.to_owned()),
-            None => error!("Top-level values key in com.apple.Safari.plist isn't an dictionary?"),
-        },
-        None => error!("Unable to find top-level values key in com.apple.Safari.plist"),
+fn parse_icloud_devices(root: &Node, rules: &RuleSet) -> Result<Vec<CloudDevice>, Error> {
+    let mut devices = vec![];
+    for (uuid, device) in root.key("values")?.entries()? {
+        let data = device.key("value")?;
+        let name = data.key("DeviceName")?.as_str()?;
+
+        // If a device is registered with iCloud but Safari isn't running or
+        // there aren't any tabs open, there isn't a Tabs field.
+        let mut tabs = vec![];
+        if let Some(entries) = data.get("Tabs")? {
+            for (i, tab) in entries.items()?.into_iter().enumerate() {
+                let url = tab.key("URL")?.as_str()?;
+                tabs.push(CloudTab {
+                    index: i as u32 + 1,
+                    url: rules.tidy(url),
+                    title: tab.optional_string("Title"),
+                });
+            }
+        }
+
+        devices.push(CloudDevice {
+            uuid: uuid.to_owned(),
+            name: name.to_owned(),
+            last_modified: data.optional_date("LastModified"),
+            tabs,
+        });
     }
+    Ok(devices)
 }
 
-/// Return a list of devices in iCloud Tabs.
-pub fn list_icloud_tabs_devices() -> Result<Vec<String>, String> {
-    let plist = match read_safari_plist() {
-        Ok(plist) => plist,
-        Err(e) => return Err(e),
+#[cfg(test)]
+mod tests_plists {
+    use std::path::{Path, PathBuf};
+
+    use plist::Plist;
+
+    use dates::parse_date;
+    use error::Error;
+    use paths::SafariPaths;
+    use plists::{self, Node};
+    use rules::RuleSet;
+    use safari::{
+        get_icloud_devices, get_reading_list, parse_icloud_devices, parse_reading_list, CloudTab,
+        ReadingListItem, ReadingListQuery, SortOrder,
     };
 
-    Ok(plist
-        .values()
-        .map(|value| {
-            value
-                .as_dictionary()
+    fn fixture_path(name: &str) -> PathBuf {
+        PathBuf::from(format!(
+            "{}/tests/fixtures/plist/{}",
+            env!("CARGO_MANIFEST_DIR"),
+            name
+        ))
+    }
+
+    fn fixture(name: &str) -> (PathBuf, Plist) {
+        let path = fixture_path(name);
+        let plist = plists::read(&path).unwrap();
+        (path, plist)
+    }
+
+    /// Paths for reading plist fixtures, with no CloudTabs.db.
+    fn fixture_paths(bookmarks: &str, icloud_tabs: &str) -> SafariPaths {
+        SafariPaths {
+            bookmarks: fixture_path(bookmarks),
+            synced_preferences: fixture_path(icloud_tabs),
+            ..SafariPaths::in_dir(&fixture_path("does-not-exist"))
+        }
+    }
+
+    #[test]
+    fn test_readers_use_configured_paths() {
+        let paths = fixture_paths("reading-list.plist", "icloud-tabs.plist");
+        let rules = RuleSet::builtin();
+        assert_eq!(get_reading_list(&paths, &rules).unwrap().len(), 3);
+
+        // There's no CloudTabs.db, so we fall back to the plist.
+        assert_eq!(get_icloud_devices(&paths, &rules).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_readers_report_missing_files() {
+        let paths = SafariPaths::in_dir(&fixture_path("does-not-exist"));
+        let rules = RuleSet::builtin();
+        match get_reading_list(&paths, &rules) {
+            Err(Error::PlistMissing { path, .. }) => assert_eq!(path, paths.bookmarks),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        match get_icloud_devices(&paths, &rules) {
+            Err(Error::PlistMissing { path, .. }) => assert_eq!(path, paths.synced_preferences),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    fn reading_list() -> Vec<ReadingListItem> {
+        let (path, plist) = fixture("reading-list.plist");
+        parse_reading_list(&Node::root(&plist, &path), &RuleSet::builtin()).unwrap()
+    }
+
+    fn urls(items: &[ReadingListItem]) -> Vec<&str> {
+        items.iter().map(|item| item.url.as_str()).collect()
+    }
+
+    #[test]
+    fn test_reading_list() {
+        let items = reading_list();
+        assert_eq!(items.len(), 3);
+        assert_eq!(
+            items[0],
+            ReadingListItem {
+                url: "https://example.org/".to_owned(),
+                title: Some("Example Domain".to_owned()),
+                date_added: Some(parse_date("2019-01-02T03:04:05Z").unwrap()),
+                date_last_viewed: Some(parse_date("2019-01-03T10:00:00Z").unwrap()),
+                preview_text: Some(
+                    "This domain is for use in illustrative examples in documents.".to_owned()
+                ),
+                image_url: Some("https://example.org/preview.png".to_owned()),
+            }
+        );
+        assert_eq!(
+            items[1],
+            ReadingListItem {
+                url: "https://www.rust-lang.org/".to_owned(),
+                title: None,
+                date_added: None,
+                date_last_viewed: None,
+                preview_text: None,
+                image_url: None,
+            }
+        );
+        assert_eq!(items[2].url, "https://blog.rust-lang.org/");
+        assert!(!items[0].is_unread());
+        assert!(items[2].is_unread());
+    }
+
+    #[test]
+    fn test_reading_list_query_filters_by_read_status() {
+        let unread = ReadingListQuery {
+            read: Some(false),
+            ..ReadingListQuery::default()
+        };
+        assert_eq!(
+            urls(&unread.apply(reading_list())),
+            vec!["https://www.rust-lang.org/", "https://blog.rust-lang.org/"]
+        );
+
+        let read = ReadingListQuery {
+            read: Some(true),
+            ..ReadingListQuery::default()
+        };
+        assert_eq!(
+            urls(&read.apply(reading_list())),
+            vec!["https://example.org/"]
+        );
+    }
+
+    #[test]
+    fn test_reading_list_query_sorts_undated_items_last() {
+        let newest = ReadingListQuery {
+            sort: Some(SortOrder::NewestFirst),
+            ..ReadingListQuery::default()
+        };
+        assert_eq!(
+            urls(&newest.apply(reading_list())),
+            vec![
+                "https://blog.rust-lang.org/",
+                "https://example.org/",
+                "https://www.rust-lang.org/",
+            ]
+        );
+
+        let oldest = ReadingListQuery {
+            sort: Some(SortOrder::OldestFirst),
+            ..ReadingListQuery::default()
+        };
+        assert_eq!(
+            urls(&oldest.apply(reading_list())),
+            vec![
+                "https://example.org/",
+                "https://blog.rust-lang.org/",
+                "https://www.rust-lang.org/",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_reading_list_query_since() {
+        let query = ReadingListQuery {
+            since: Some(parse_date("2019-01-15").unwrap()),
+            ..ReadingListQuery::default()
+        };
+        assert_eq!(
+            urls(&query.apply(reading_list())),
+            vec!["https://blog.rust-lang.org/"]
+        );
+        assert_eq!(ReadingListQuery::default().apply(reading_list()).len(), 3);
+    }
+
+    #[test]
+    fn test_sort_order_from_str() {
+        assert_eq!("newest".parse(), Ok(SortOrder::NewestFirst));
+        assert_eq!("oldest".parse(), Ok(SortOrder::OldestFirst));
+        assert!("alphabetical".parse::<SortOrder>().is_err());
+    }
+
+    #[test]
+    fn test_reading_list_without_children_is_error() {
+        let plist = Plist::Dictionary(Default::default());
+        let root = Node::root(&plist, Path::new("Bookmarks.plist"));
+        match parse_reading_list(&root, &RuleSet::builtin()) {
+            Err(Error::PlistMalformed { key_path, .. }) => assert_eq!(key_path, "(root)"),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_reading_list_item_with_non_string_url_is_error() {
+        let (path, mut plist) = fixture("reading-list.plist");
+        {
+            let folders = plist.as_dictionary_mut().unwrap().get_mut("Children");
+            let reading_list = folders.unwrap().as_array_mut().unwrap().last_mut();
+            let items = reading_list
+                .unwrap()
+                .as_dictionary_mut()
                 .unwrap()
-                .get("value")
+                .get_mut("Children");
+            let item = items.unwrap().as_array_mut().unwrap()[1]
+                .as_dictionary_mut()
+                .unwrap();
+            item.insert("URLString".to_owned(), Plist::Integer(42));
+        }
+
+        match parse_reading_list(&Node::root(&plist, &path), &RuleSet::builtin()) {
+            Err(Error::PlistMalformed { key_path, .. }) => {
+                assert_eq!(key_path, "Children[2].Children[1].URLString")
+            }
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_icloud_devices() {
+        let (path, plist) = fixture("icloud-tabs.plist");
+        let devices =
+            parse_icloud_devices(&Node::root(&plist, &path), &RuleSet::builtin()).unwrap();
+
+        // Both iPhones have the same name, but they're separate devices.
+        let names: Vec<(&str, &str)> = devices
+            .iter()
+            .map(|d| (d.uuid.as_str(), d.name.as_str()))
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                ("0C5A8C53-2A3B-4C1C-9A4E-3B1B2F1E7D10", "Alex's iPhone"),
+                ("5B6E7F80-1A2B-3C4D-5E6F-708192A3B4C5", "Alex's iPhone"),
+                ("9F1D2E3C-4B5A-6978-8A9B-0C1D2E3F4A5B", "Alex's iPad"),
+            ]
+        );
+
+        assert_eq!(
+            devices[0].last_modified,
+            Some(parse_date("2019-01-05T12:00:00Z").unwrap())
+        );
+        assert_eq!(
+            devices[0].tabs,
+            vec![
+                CloudTab {
+                    index: 1,
+                    url: "https://example.org/".to_owned(),
+                    title: Some("Example Domain".to_owned()),
+                },
+                CloudTab {
+                    index: 2,
+                    url: "https://docs.rs/plist".to_owned(),
+                    title: None,
+                },
+            ]
+        );
+
+        // A device without any open tabs doesn't have a Tabs key.
+        assert_eq!(devices[2].tabs, vec![]);
+    }
+
+    #[test]
+    fn test_icloud_tab_with_non_string_url_is_error() {
+        let (path, mut plist) = fixture("icloud-tabs.plist");
+        {
+            let devices = plist.as_dictionary_mut().unwrap().get_mut("values");
+            let device = devices
                 .unwrap()
-                .as_dictionary()
+                .as_dictionary_mut()
                 .unwrap()
-                .get("DeviceName")
+                .get_mut("0C5A8C53-2A3B-4C1C-9A4E-3B1B2F1E7D10");
+            let value = device
                 .unwrap()
-                .as_string()
+                .as_dictionary_mut()
                 .unwrap()
-                .to_owned()
-        })
-        .collect())
-}
+                .get_mut("value");
+            let tabs = value.unwrap().as_dictionary_mut().unwrap().get_mut("Tabs");
+            let tab = tabs.unwrap().as_array_mut().unwrap()[0]
+                .as_dictionary_mut()
+                .unwrap();
+            tab.insert("URL".to_owned(), Plist::Boolean(true));
+        }
 
-/// Return a list of URLs from iCloud Tabs.
-pub fn get_icloud_tabs_urls() -> Result<HashMap<String, Vec<String>>, String> {
-    let plist = match read_safari_plist() {
-        Ok(plist) => plist,
-        Err(e) => return Err(e),
-    };
+        match parse_icloud_devices(&Node::root(&plist, &path), &RuleSet::builtin()) {
+            Err(Error::PlistMalformed { key_path, .. }) => assert_eq!(
+                key_path,
+                "values.0C5A8C53-2A3B-4C1C-9A4E-3B1B2F1E7D10.value.Tabs[0].URL"
+            ),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
 
-    // Within the `values` dictionary, each device is as follows:
-    //
-    //    <key>[[ device UUID ]]</key>
-    //    <dict>
-    //      [[ device data ]]
-    //      <dict>
-    //        <key>Tabs</key>
-    //        <key>DeviceName</key><string>[[ device name ]]</key>
-    //        <key>Tabs</key>
-    //        <array>
-    //          <dict>
-    //            <key>URL</key><string>[[ URL ]]</string>
-    //            [[ other tab data ]]
-    //          </dict>
-    //          [[ other tabs ]]
-    //
-    let mut result: HashMap<String, Vec<String>> = HashMap::new();
-    let devices_with_tabs = plist.values().map(|value| {
-        value
-            .as_dictionary()
-            .unwrap()
-            .get("value")
-            .unwrap()
-            .as_dictionary()
-            .unwrap()
-    });
-    for data in devices_with_tabs {
-        let name: String = data
-            .get("DeviceName")
-            .unwrap()
-            .as_string()
-            .unwrap()
-            .to_owned();
+    #[test]
+    fn test_binary_plists_match_xml() {
+        let rules = RuleSet::builtin();
+        let xml = fixture_paths("reading-list.plist", "icloud-tabs.plist");
+        let binary = fixture_paths("reading-list.bplist", "icloud-tabs.bplist");
+        assert_eq!(
+            get_reading_list(&binary, &rules).unwrap(),
+            get_reading_list(&xml, &rules).unwrap()
+        );
+        assert_eq!(
+            get_icloud_devices(&binary, &rules).unwrap(),
+            get_icloud_devices(&xml, &rules).unwrap()
+        );
+    }
 
-        // If a device is registered with iCloud but Safari isn't running or
-        // there aren't any tabs open, there isn't a Tabs field.
-        if !data.contains_key("Tabs") {
-            continue;
-        }
+    #[test]
+    fn test_empty_reading_list() {
+        let paths = fixture_paths("reading-list-empty.bplist", "icloud-tabs.plist");
+        assert_eq!(get_reading_list(&paths, &RuleSet::builtin()), Ok(vec![]));
+    }
 
-        let urls: Vec<String> = data
-            .get("Tabs")
-            .unwrap()
-            .as_array()
-            .unwrap()
-            .iter()
-            .map(|tab| {
-                tab.as_dictionary()
-                    .unwrap()
-                    .get("URL")
-                    .unwrap()
-                    .as_string()
-                    .unwrap()
-            })
-            .map(|url| urls::tidy_url(url))
-            .collect();
-        result.insert(name, urls);
+    #[test]
+    fn test_malformed_reading_lists_are_errors() {
+        let cases = vec![
+            ("missing-children.plist", "(root)"),
+            ("reading-list-duplicate.bplist", "Children"),
+            (
+                "reading-list-non-string-url.bplist",
+                "Children[2].Children[1].URLString",
+            ),
+            ("array-root.bplist", "(root)"),
+            ("not-a-plist.plist", "(root)"),
+            ("truncated.bplist", "(root)"),
+        ];
+        for (name, expected_key_path) in cases {
+            let paths = fixture_paths(name, "icloud-tabs.plist");
+            match get_reading_list(&paths, &RuleSet::builtin()) {
+                Err(Error::PlistMalformed { key_path, .. }) => {
+                    assert_eq!(key_path, expected_key_path, "{}", name)
+                }
+                other => panic!("Unexpected result for {}: {:?}", name, other),
+            }
+        }
     }
-    Ok(result)
-}
 
-struct SafariWindow {
-    window_index: u32,
-    tab_count: u32,
-}
+    #[test]
+    fn test_icloud_devices_without_tabs() {
+        let paths = fixture_paths("reading-list.plist", "icloud-tabs-no-tabs.bplist");
+        let devices = get_icloud_devices(&paths, &RuleSet::builtin()).unwrap();
+        assert_eq!(devices.len(), 3);
+        assert!(devices.iter().all(|d| d.tabs.is_empty()));
+    }
 
-/// Get a list of (window, tab_count) pairs for the currently running
-/// version of Safari.
-///
-/// There are lots of bugs in Safari's AppleScript handler, so knowing
-/// that there are N windows does not imply you can look up the tabs for
-/// each window 1, ..., N.  There might be gaps -- looking up a window
-/// in the middle could crash the AppleScript handler.
-fn get_window_tab_count_pairs() -> Vec<SafariWindow> {
-    let mut pairs = vec![];
-    let r = run_applescript("tell application \"Safari\" to get count of windows");
-    let window_count = r.stdout.trim().parse::<u32>().unwrap();
-    for window in 1..(window_count + 1) {
-        let r = run_applescript(&format!(
-            "tell application \"Safari\" to get count of tabs of window {}",
-            window
-        ));
-        if r.status.success() {
-            pairs.push(SafariWindow {
-                window_index: window,
-                tab_count: r.stdout.trim().parse::<u32>().unwrap(),
-            });
+    #[test]
+    fn test_malformed_icloud_tabs_are_errors() {
+        let cases = vec![
+            (
+                "icloud-tabs-non-string-url.bplist",
+                "values.0C5A8C53-2A3B-4C1C-9A4E-3B1B2F1E7D10.value.Tabs[1].URL",
+            ),
+            (
+                "icloud-tabs-tabs-not-array.plist",
+                "values.0C5A8C53-2A3B-4C1C-9A4E-3B1B2F1E7D10.value.Tabs",
+            ),
+            (
+                "icloud-tabs-missing-device-name.plist",
+                "values.0C5A8C53-2A3B-4C1C-9A4E-3B1B2F1E7D10.value",
+            ),
+            ("icloud-tabs-missing-values.plist", "(root)"),
+            ("array-root.bplist", "(root)"),
+            ("not-a-plist.plist", "(root)"),
+            ("truncated.bplist", "(root)"),
+        ];
+        for (name, expected_key_path) in cases {
+            let paths = fixture_paths("reading-list.plist", name);
+            match get_icloud_devices(&paths, &RuleSet::builtin()) {
+                Err(Error::PlistMalformed { key_path, .. }) => {
+                    assert_eq!(key_path, expected_key_path, "{}", name)
+                }
+                other => panic!("Unexpected result for {}: {:?}", name, other),
+            }
         }
     }
-    pairs
 }
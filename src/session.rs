@@ -0,0 +1,560 @@
+//! Save the tabs you have open in Safari, and reopen them later.
+//!
+//! A session is a JSON file with every window's tabs, in order.  Sessions
+//! are kept in the safari.rs data directory, e.g.
+//! `~/Library/Application Support/safari.rs/sessions/work.json` on macOS.
+//!
+//! The file has a `version` field, so we can change the format later and
+//! still read old sessions.  The current format looks like this:
+//!
+//! ```text
+//! {
+//!   "version": 1,
+//!   "name": "work",
+//!   "saved_at": "2019-01-05T12:00:00Z",
+//!   "windows": [
+//!     {
+//!       "tabs": [
+//!         {"url": "https://github.com/", "title": "GitHub"},
+//!         {"url": "https://example.org/", "title": "Example Domain"}
+//!       ],
+//!       "current_tab": 2
+//!     }
+//!   ]
+//! }
+//! ```
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, UTC};
+use serde_json::{self, Value};
+use tera::{Context, Tera};
+
+use applescript::ScriptRunner;
+use dates::format_date;
+use error::Error;
+use files::write_atomically;
+use output::Item;
+use safari::{self, Window};
+
+/// The version of the session file format that we write.  We can read any
+/// file with this version or lower.
+pub const SESSION_VERSION: u64 = 1;
+
+/// A saved set of Safari windows and tabs.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Session {
+    pub version: u64,
+    pub name: String,
+    /// When the session was saved, as an RFC 3339 timestamp.
+    pub saved_at: String,
+    /// Windows in front-to-back order.
+    pub windows: Vec<SessionWindow>,
+}
+
+/// A window in a saved session.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct SessionWindow {
+    /// Tabs in left-to-right order.
+    pub tabs: Vec<SessionTab>,
+    /// The tab that was showing in this window, starting from 1.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub current_tab: Option<u32>,
+}
+
+/// A tab in a saved session.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct SessionTab {
+    pub url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+}
+
+impl Session {
+    /// Build a session from Safari's open windows.
+    ///
+    /// We can't reopen tabs that don't have a URL (e.g. a blank new tab),
+    /// so we leave them out, along with any windows that are left empty.
+    pub fn from_windows(name: &str, windows: &[Window], saved_at: &DateTime<UTC>) -> Session {
+        let windows = windows
+            .iter()
+            .filter_map(|window| {
+                let mut current_tab = None;
+                let mut tabs = vec![];
+                for tab in &window.tabs {
+                    if let Ok(ref url) = tab.url {
+                        tabs.push(SessionTab {
+                            url: url.to_owned(),
+                            title: tab.title.clone(),
+                        });
+                        if tab.is_current {
+                            current_tab = Some(tabs.len() as u32);
+                        }
+                    }
+                }
+                if tabs.is_empty() {
+                    None
+                } else {
+                    Some(SessionWindow { tabs, current_tab })
+                }
+            })
+            .collect();
+
+        Session {
+            version: SESSION_VERSION,
+            name: name.to_owned(),
+            saved_at: format_date(saved_at),
+            windows,
+        }
+    }
+
+    /// How many tabs there are in every window of this session.
+    pub fn tab_count(&self) -> usize {
+        self.windows.iter().map(|w| w.tabs.len()).sum()
+    }
+
+    /// Every tab in this session, with its window and tab index, for
+    /// printing with `output::render`.
+    pub fn items(&self) -> Vec<Item> {
+        let mut items = vec![];
+        for (w, window) in self.windows.iter().enumerate() {
+            for (t, tab) in window.tabs.iter().enumerate() {
+                items.push(Item {
+                    url: tab.url.clone(),
+                    title: tab.title.clone(),
+                    window: Some(w as u32 + 1),
+                    tab: Some(t as u32 + 1),
+                    ..Item::default()
+                });
+            }
+        }
+        items
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    /// Parse a session file.  It's an error if it isn't a session file
+    /// that we understand, e.g. because a newer version of safari.rs saved it.
+    pub fn from_json(json: &str) -> Result<Session, Error> {
+        let malformed = |reason: String| Error::SessionMalformed { path: None, reason };
+        let value: Value =
+            serde_json::from_str(json).map_err(|e| malformed(format!("not valid JSON: {}", e)))?;
+        match value.get("version").and_then(|v| v.as_u64()) {
+            Some(v) if v > SESSION_VERSION => {
+                return Err(malformed(format!(
+                    "saved by a newer version of safari.rs (format version {})",
+                    v
+                )))
+            }
+            Some(_) => (),
+            None => return Err(malformed("missing a format version".to_owned())),
+        }
+        serde_json::from_value(value).map_err(|e| malformed(format!("not a session file: {}", e)))
+    }
+}
+
+/// A window to open, for the restore-session script.  URLs are already
+/// quoted as AppleScript strings.
+#[derive(Serialize)]
+struct RestoreTarget {
+    first_url: String,
+    other_urls: Vec<String>,
+    current_tab: Option<u32>,
+}
+
+/// Quote a string for use in AppleScript.
+fn applescript_string(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Build the AppleScript that reopens every window in a session.
+///
+/// We open windows from back to front, so the window that was frontmost
+/// when the session was saved ends up at the front again.
+///
+pub fn restore_script(session: &Session) -> Result<String, Error> {
+    let targets: Vec<RestoreTarget> = session
+        .windows
+        .iter()
+        .rev()
+        .filter(|window| !window.tabs.is_empty())
+        .map(|window| RestoreTarget {
+            first_url: applescript_string(&window.tabs[0].url),
+            other_urls: window.tabs[1..]
+                .iter()
+                .map(|tab| applescript_string(&tab.url))
+                .collect(),
+            current_tab: window.current_tab.filter(|_| window.tabs.len() > 1),
+        })
+        .collect();
+
+    let template = include_str!("scripts/restore-session.scpt");
+    let mut context = Context::new();
+    context.add("windows", &targets);
+    Tera::one_off(template, &context, false)
+        .map_err(|e| Error::Script(format!("Unable to build the restore-session script: {}", e)))
+}
+
+/// Save Safari's open windows and tabs as a session.
+pub fn capture(
+    runner: &dyn ScriptRunner,
+    name: &str,
+    saved_at: &DateTime<UTC>,
+) -> Result<Session, Error> {
+    let windows = safari::get_windows(runner)?;
+    Ok(Session::from_windows(name, &windows, saved_at))
+}
+
+/// Reopen every window and tab in a session.
+pub fn restore(runner: &dyn ScriptRunner, session: &Session) -> Result<(), Error> {
+    let output = runner.run(&restore_script(session)?);
+    if !output.status.success() {
+        return Err(Error::Script(format!(
+            "Unexpected error from osascript: {:?}",
+            output.stderr
+        )));
+    }
+    Ok(())
+}
+
+/// Where we keep saved sessions, if the platform has a data directory,
+/// e.g. `~/Library/Application Support/safari.rs/sessions` on macOS.
+pub fn default_sessions_dir() -> Option<PathBuf> {
+    dirs::data_dir().map(|mut path| {
+        path.push("safari.rs");
+        path.push("sessions");
+        path
+    })
+}
+
+/// A directory of saved sessions, one JSON file per session.
+#[derive(Debug)]
+pub struct SessionStore {
+    dir: PathBuf,
+}
+
+impl SessionStore {
+    pub fn new(dir: &Path) -> SessionStore {
+        SessionStore {
+            dir: dir.to_owned(),
+        }
+    }
+
+    /// The sessions in the default sessions directory.
+    pub fn open_default() -> Result<SessionStore, Error> {
+        match default_sessions_dir() {
+            Some(dir) => Ok(SessionStore::new(&dir)),
+            None => Err(Error::Io {
+                path: PathBuf::from("safari.rs/sessions"),
+                reason: "Unable to find a data directory for sessions".to_owned(),
+            }),
+        }
+    }
+
+    /// The file for a session.  Names are used as filenames, so they can't
+    /// be empty, contain a slash, or start with a dot.
+    pub fn path(&self, name: &str) -> Result<PathBuf, Error> {
+        if name.is_empty() || name.starts_with('.') || name.contains(['/', '\\']) {
+            return Err(Error::Usage(format!(
+                "Invalid session name {:?}; names can't be empty, \
+                 start with a dot or contain a slash",
+                name
+            )));
+        }
+        Ok(self.dir.join(format!("{}.json", name)))
+    }
+
+    /// Save a session, replacing any existing session with the same name.
+    /// Returns the path of the session file.
+    pub fn save(&self, session: &Session) -> Result<PathBuf, Error> {
+        let path = self.path(&session.name)?;
+        write_atomically(&path, session.to_json().as_bytes())?;
+        Ok(path)
+    }
+
+    /// Load a saved session by name.
+    pub fn load(&self, name: &str) -> Result<Session, Error> {
+        let path = self.path(name)?;
+        if !path.exists() {
+            return Err(Error::Usage(format!(
+                "No session named {:?}; use `session list` to see saved sessions",
+                name
+            )));
+        }
+        let json = fs::read_to_string(&path).map_err(|e| Error::Io {
+            path: path.clone(),
+            reason: e.to_string(),
+        })?;
+        Session::from_json(&json).map_err(|e| match e {
+            Error::SessionMalformed { reason, .. } => Error::SessionMalformed {
+                path: Some(path),
+                reason,
+            },
+            e => e,
+        })
+    }
+
+    /// The names of every saved session, in alphabetical order.
+    pub fn list(&self) -> Result<Vec<String>, Error> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(_) if !self.dir.exists() => return Ok(vec![]),
+            Err(e) => {
+                return Err(Error::Io {
+                    path: self.dir.clone(),
+                    reason: e.to_string(),
+                })
+            }
+        };
+
+        let mut names: Vec<String> = entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+            .filter_map(|path| {
+                path.file_stem()
+                    .and_then(|stem| stem.to_str())
+                    .map(|stem| stem.to_owned())
+            })
+            .collect();
+        names.sort();
+        Ok(names)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+
+    use applescript::FakeRunner;
+    use dates::parse_date;
+    use error::Error;
+    use session::{
+        capture, restore, restore_script, Session, SessionStore, SessionTab, SessionWindow,
+    };
+    use test_support::temp_path;
+
+    fn jxa_fixture(name: &str) -> String {
+        fs::read_to_string(format!(
+            "{}/tests/fixtures/jxa/{}",
+            env!("CARGO_MANIFEST_DIR"),
+            name
+        ))
+        .unwrap()
+    }
+
+    fn saved_session() -> Session {
+        let runner = FakeRunner::new(vec![FakeRunner::ok(&jxa_fixture("two-windows.json"))]);
+        let saved_at = parse_date("2019-01-05T12:00:00Z").unwrap();
+        capture(&runner, "work", &saved_at).unwrap()
+    }
+
+    fn temp_store(name: &str) -> (PathBuf, SessionStore) {
+        let dir = temp_path(&format!("sessions-{}", name));
+        let _ = fs::remove_dir_all(&dir);
+        let store = SessionStore::new(&dir);
+        (dir, store)
+    }
+
+    #[test]
+    fn test_capture_keeps_windows_and_tab_order() {
+        let session = saved_session();
+        assert_eq!(session.version, 1);
+        assert_eq!(session.saved_at, "2019-01-05T12:00:00Z");
+        assert_eq!(session.windows.len(), 2);
+        assert_eq!(session.tab_count(), 5);
+
+        let urls: Vec<&str> = session.windows[0]
+            .tabs
+            .iter()
+            .map(|t| t.url.as_str())
+            .collect();
+        assert_eq!(
+            urls,
+            vec![
+                "https://github.com/alexwlchan/safari.rs",
+                "https://mobile.twitter.com/alexwlchan",
+                "https://example.org/",
+            ]
+        );
+        assert_eq!(session.windows[0].current_tab, Some(2));
+        assert_eq!(session.windows[1].current_tab, Some(1));
+    }
+
+    #[test]
+    fn test_capture_skips_tabs_without_urls() {
+        let runner = FakeRunner::new(vec![FakeRunner::ok(&jxa_fixture("unreadable-tabs.json"))]);
+        let saved_at = parse_date("2019-01-05").unwrap();
+        let session = capture(&runner, "work", &saved_at).unwrap();
+
+        // The blank tab is dropped, so the current tab is now the first one,
+        // and the window without any tabs is dropped entirely.
+        assert_eq!(
+            session.windows,
+            vec![SessionWindow {
+                tabs: vec![
+                    SessionTab {
+                        url: "https://example.org/".to_owned(),
+                        title: Some("Example Domain".to_owned()),
+                    },
+                    SessionTab {
+                        url: "favorites://".to_owned(),
+                        title: None,
+                    },
+                ],
+                current_tab: Some(1),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        let session = saved_session();
+        assert_eq!(Session::from_json(&session.to_json()), Ok(session));
+    }
+
+    #[test]
+    fn test_json_format() {
+        let json = r#"{
+            "version": 1,
+            "name": "reading",
+            "saved_at": "2019-01-05T12:00:00Z",
+            "windows": [{"tabs": [{"url": "https://example.org/"}]}]
+        }"#;
+        let session = Session::from_json(json).unwrap();
+        assert_eq!(session.windows[0].tabs[0].title, None);
+        assert_eq!(session.windows[0].current_tab, None);
+        assert!(!session.to_json().contains("current_tab"));
+    }
+
+    #[test]
+    fn test_newer_versions_are_rejected() {
+        let json = r#"{"version": 2, "name": "work", "saved_at": "", "windows": []}"#;
+        match Session::from_json(json) {
+            Err(Error::SessionMalformed { path: None, reason }) => {
+                assert!(reason.contains("newer version"), "{}", reason)
+            }
+            other => panic!("Unexpected result: {:?}", other),
+        }
+
+        let json = r#"{"name": "work", "saved_at": "", "windows": []}"#;
+        assert!(Session::from_json(json).is_err());
+        assert!(Session::from_json("not json").is_err());
+    }
+
+    #[test]
+    fn test_items() {
+        let items = saved_session().items();
+        assert_eq!(items.len(), 5);
+        assert_eq!(items[3].url, "https://crates.io/crates/urlparse");
+        assert_eq!(items[3].window, Some(2));
+        assert_eq!(items[3].tab, Some(1));
+    }
+
+    #[test]
+    fn test_restore_script_opens_windows_back_to_front() {
+        let script = restore_script(&saved_session()).unwrap();
+        let lines: Vec<&str> = script
+            .lines()
+            .map(|l| l.trim())
+            .filter(|l| !l.is_empty() && !l.starts_with("--"))
+            .collect();
+        assert_eq!(
+            lines,
+            vec![
+                "tell application \"Safari\"",
+                "make new document with properties {URL:\"https://crates.io/crates/urlparse\"}",
+                "tell front window",
+                "make new tab at end of tabs with properties {URL:\"https://docs.rs/plist\"}",
+                "set current tab to tab 1",
+                "end tell",
+                "make new document with properties {URL:\"https://github.com/alexwlchan/safari.rs\"}",
+                "tell front window",
+                "make new tab at end of tabs with properties {URL:\"https://mobile.twitter.com/alexwlchan\"}",
+                "make new tab at end of tabs with properties {URL:\"https://example.org/\"}",
+                "set current tab to tab 2",
+                "end tell",
+                "end tell",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_restore_script_escapes_urls() {
+        let session = Session {
+            version: 1,
+            name: "odd".to_owned(),
+            saved_at: "2019-01-05T12:00:00Z".to_owned(),
+            windows: vec![SessionWindow {
+                tabs: vec![SessionTab {
+                    url: "https://example.org/?q=\"quoted\"&path=a\\b".to_owned(),
+                    title: None,
+                }],
+                current_tab: None,
+            }],
+        };
+        let script = restore_script(&session).unwrap();
+        assert!(script.contains(r#"{URL:"https://example.org/?q=\"quoted\"&path=a\\b"}"#));
+    }
+
+    #[test]
+    fn test_restore_runs_the_script() {
+        let runner = FakeRunner::new(vec![FakeRunner::ok("")]);
+        restore(&runner, &saved_session()).unwrap();
+        assert_eq!(runner.scripts().len(), 1);
+
+        let runner = FakeRunner::new(vec![FakeRunner::err("execution error")]);
+        match restore(&runner, &saved_session()) {
+            Err(Error::Script(_)) => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_store_save_load_and_list() {
+        let (dir, store) = temp_store("round-trip");
+        assert_eq!(store.list(), Ok(vec![]));
+
+        let session = saved_session();
+        let path = store.save(&session).unwrap();
+        assert_eq!(path, dir.join("work.json"));
+
+        let mut other = session.clone();
+        other.name = "reading".to_owned();
+        store.save(&other).unwrap();
+
+        assert_eq!(
+            store.list(),
+            Ok(vec!["reading".to_owned(), "work".to_owned()])
+        );
+        assert_eq!(store.load("work"), Ok(session));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_store_errors() {
+        let (dir, store) = temp_store("errors");
+        match store.load("missing") {
+            Err(Error::Usage(_)) => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        for name in &["", ".hidden", "../escape", "a/b"] {
+            assert!(store.path(name).is_err(), "{:?}", name);
+        }
+
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("broken.json"), "{}").unwrap();
+        match store.load("broken") {
+            Err(Error::SessionMalformed { path, .. }) => {
+                assert_eq!(path, Some(dir.join("broken.json")))
+            }
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
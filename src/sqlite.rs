@@ -0,0 +1,23 @@
+//! Helpers for reading Safari's SQLite databases, like CloudTabs.db and
+//! History.db.
+
+use std::path::Path;
+
+use rusqlite::{self, Connection, OpenFlags};
+
+use error::Error;
+
+/// Open a database read-only and run `query` against it.  Any error,
+/// from opening the file or from the query, is reported against the file.
+pub fn open_read_only<T, F>(path: &Path, query: F) -> Result<T, Error>
+where
+    F: FnOnce(&Connection) -> Result<T, rusqlite::Error>,
+{
+    // Safari may be using the database, so make sure we don't write to it.
+    Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .and_then(|conn| query(&conn))
+        .map_err(|e| Error::Database {
+            path: path.to_owned(),
+            reason: e.to_string(),
+        })
+}
@@ -0,0 +1,12 @@
+//! Helpers shared by the tests in several modules.
+
+use std::env;
+use std::path::PathBuf;
+use std::process;
+
+/// A path in the temporary directory for a test to use, e.g.
+/// `/tmp/safari-rs-1234-history.db`.  Tests run in parallel, so each one
+/// should use a different name.
+pub fn temp_path(name: &str) -> PathBuf {
+    env::temp_dir().join(format!("safari-rs-{}-{}", process::id(), name))
+}
@@ -1,386 +1,486 @@
-use reqwest::Client;
+//! Tidy URLs with the built-in rules (see `tidy_url`), and follow
+//! redirects over the network to find where a URL ends up.
 
-use urlencoding::encode as urlencode;
-use urlparse::{parse_qs, urlparse, urlunparse, Query, Url};
+use std::error::Error;
+use std::fmt;
+use std::io::Read;
+use std::str;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, OnceLock};
+use std::thread;
+use std::time::Duration;
 
-/// Follow redirects to resolve the final location of a URL
-pub fn resolve(url: &str) -> String {
-    let client = Client::new();
-    client.head(url).send().unwrap().url().as_str().to_owned()
-}
+use regex::Regex;
+use reqwest::header::{CONTENT_TYPE, LOCATION};
+use reqwest::{Client, RedirectPolicy, Response, StatusCode, Url};
+
+use rules::RuleSet;
+
+/// Settings for following redirects.
+#[derive(Clone, Debug)]
+pub struct ResolveOptions {
+    /// Give up if a URL redirects more than this many times.
+    pub max_redirects: usize,
+
+    /// How long to wait for each request.  None means wait forever.
+    pub timeout: Option<Duration>,
 
-fn partial_urlencode(value: &str) -> String {
-    // The urlencode from the urlencoding library goes further than I like,
-    // and also URL encodes ASCII digits. Reverse that stuff.
-    urlencode(value)
-        .replace("%30", "0")
-        .replace("%31", "1")
-        .replace("%32", "2")
-        .replace("%33", "3")
-        .replace("%34", "4")
-        .replace("%35", "5")
-        .replace("%36", "6")
-        .replace("%37", "7")
-        .replace("%38", "8")
-        .replace("%39", "9")
+    /// Follow `<meta http-equiv="refresh">` redirects in HTML pages.
+    pub follow_meta_refresh: bool,
+
+    /// Follow `<link rel="canonical">` links in HTML pages.
+    pub prefer_canonical: bool,
+
+    /// How much of an HTML page to read when looking for meta-refresh
+    /// or canonical links.
+    pub max_body_bytes: u64,
+
+    /// If set, tidy the final URL with these rules.
+    pub tidy: Option<RuleSet>,
 }
 
-/// Re-encode a query string for Rust
-fn encode_querystring(query: Query) -> Option<String> {
-    let mut query_components: Vec<String> = vec![];
-    for (key, value) in query {
-        for v in value.iter() {
-            query_components.push(format!("{}={}", key, partial_urlencode(v)));
+impl Default for ResolveOptions {
+    fn default() -> ResolveOptions {
+        ResolveOptions {
+            max_redirects: 10,
+            timeout: Some(Duration::from_secs(30)),
+            follow_meta_refresh: false,
+            prefer_canonical: false,
+            max_body_bytes: 256 * 1024,
+            tidy: None,
         }
     }
-    if query_components.len() > 0 {
-        Some(query_components.join("&"))
-    } else {
-        None
-    }
 }
 
-/// Strip tracking junk and URL suffixes.
-pub fn tidy_url(url: &str) -> String {
-    let mut parsed_url = urlparse(url);
-
-    // Always get the desktop version of Twitter URLs
-    if parsed_url.netloc == "mobile.twitter.com" {
-        parsed_url.netloc = String::from("twitter.com");
+impl ResolveOptions {
+    fn reads_html(&self) -> bool {
+        self.follow_meta_refresh || self.prefer_canonical
     }
+}
 
-    // Always get the non-mobile version of nytimes.com URLs
-    if parsed_url.netloc == "mobile.nytimes.com" {
-        parsed_url.netloc = String::from("nytimes.com");
-    }
+/// A redirect that came from the HTML of a page, rather than HTTP.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HtmlRedirect {
+    MetaRefresh,
+    Canonical,
+}
 
-    // Remove any tracking junk from Amazon URLs so they're not a
-    // ridiculous length
-    if (parsed_url.netloc == "www.amazon.co.uk") || (parsed_url.netloc == "smile.amazon.co.uk") {
-        parsed_url.query = None;
-        let mut new_path = String::from("");
-        for component in parsed_url.path.split("ref=") {
-            new_path = String::from(component);
-            break;
+impl fmt::Display for HtmlRedirect {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HtmlRedirect::MetaRefresh => write!(f, "meta refresh"),
+            HtmlRedirect::Canonical => write!(f, "canonical link"),
         }
-        parsed_url.path = new_path;
     }
+}
 
-    // Strip tracking junk from Medium, Mashable and Buzzfeed
-    if (parsed_url.netloc == "medium.com")
-        || (parsed_url.netloc == "www.buzzfeed.com")
-        || (parsed_url.netloc == "mashable.com")
-    {
-        parsed_url.fragment = None;
-    }
+/// A single request made while following redirects.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Hop {
+    pub url: String,
+    /// The HTTP method we used, e.g. "HEAD"
+    pub method: String,
+    /// The HTTP status code we got back, e.g. 301
+    pub status: u16,
+    /// Set if we left this page because of something in its HTML, rather
+    /// than an HTTP redirect.
+    #[serde(default)]
+    pub html_redirect: Option<HtmlRedirect>,
+}
 
-    // Strip tracking junk from TikTok URLs
-    if parsed_url.netloc == "www.tiktok.com" {
-        parsed_url.query = None;
-    }
+/// The final location of a URL, and how we got there.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Resolution {
+    pub url: String,
+    /// Every request we made, starting with the original URL.  The last
+    /// hop is the final URL.
+    pub chain: Vec<Hop>,
+}
 
-    // Remove '#notes' from Tumblr URLs
-    if parsed_url.netloc.ends_with("tumblr.com") {
-        remove_query_param(&mut parsed_url, "_branch_referrer");
-        remove_query_param(&mut parsed_url, "_branch_match_id");
-        remove_query_param(&mut parsed_url, "source");
+/// Reasons we couldn't resolve a URL.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ResolveError {
+    /// We couldn't set up an HTTP client.
+    Client(String),
 
-        parsed_url.fragment = match parsed_url.fragment {
-            Some(fragment) => {
-                if fragment == "notes" {
-                    None
-                } else {
-                    Some(fragment)
-                }
+    /// The URL we were given isn't a valid URL.
+    InvalidUrl { url: String, reason: String },
+
+    /// A request failed, e.g. DNS lookup, connection refused, or timeout.
+    Network { url: String, reason: String },
+
+    /// The URL redirected more than `max_redirects` times.
+    TooManyRedirects { chain: Vec<Hop> },
+
+    /// A redirect pointed to something that isn't a valid URL.
+    BadRedirect { url: String, location: String },
+
+    /// A redirect had a Location header that isn't UTF-8, so we can't
+    /// tell where it points.
+    UnreadableLocation { url: String },
+}
+
+impl fmt::Display for ResolveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ResolveError::Client(ref reason) => {
+                write!(f, "Unable to create HTTP client: {}", reason)
             }
-            None => None,
-        };
+            ResolveError::InvalidUrl {
+                ref url,
+                ref reason,
+            } => write!(f, "Invalid URL {}: {}", url, reason),
+            ResolveError::Network {
+                ref url,
+                ref reason,
+            } => write!(f, "Unable to fetch {}: {}", url, reason),
+            ResolveError::TooManyRedirects { ref chain } => write!(
+                f,
+                "Too many redirects: gave up after {} requests",
+                chain.len()
+            ),
+            ResolveError::BadRedirect {
+                ref url,
+                ref location,
+            } => write!(f, "{} redirected to an invalid URL: {}", url, location),
+            ResolveError::UnreadableLocation { ref url } => {
+                write!(
+                    f,
+                    "{} redirected with a Location header that isn't UTF-8",
+                    url
+                )
+            }
+        }
     }
+}
 
-    // Remove &feature=youtu.be from YouTube URLs
-    if parsed_url.netloc.ends_with("youtube.com") {
-        remove_query_param(&mut parsed_url, "feature");
-        remove_query_param(&mut parsed_url, "app");
-    }
-
-    // Remove any tracking parameters which:
-    //
-    //    * Come from UTM (Urchin Tracking Module)
-    //    * Come from Cloudflare tracking
-    //    * Come from HubSpot
-    //
-    parsed_url.query = match parsed_url.query {
-        Some(qs) => {
-            let mut query = parse_qs(&qs);
-            let ignore_keys: Vec<_> = query
-                .keys()
-                .filter(|key| {
-                    key.starts_with("utm_") || key.starts_with("__cf") || key.starts_with("hsa")
-                })
-                .map(|k| k.clone())
-                .collect();
-            for key in ignore_keys {
-                query.remove(&key);
-            }
-            encode_querystring(query)
+impl Error for ResolveError {}
+
+/// Create an HTTP client for resolving URLs.
+///
+/// The client doesn't follow redirects by itself, so `resolve` can record
+/// each one.  A client can be reused to resolve several URLs.
+///
+pub fn build_client(options: &ResolveOptions) -> Result<Client, ResolveError> {
+    Client::builder()
+        .redirect(RedirectPolicy::none())
+        .timeout(options.timeout)
+        .build()
+        .map_err(|e| ResolveError::Client(e.to_string()))
+}
+
+/// Follow redirects to resolve the final location of a URL.
+///
+/// We use HEAD requests so we don't download the page, but some servers
+/// reject HEAD requests, so we fall back to GET if we get a 403, 405 or 501.
+///
+/// If the options ask us to follow meta-refresh or canonical links, we
+/// use GET requests throughout, and read (a bounded amount of) any HTML
+/// page that isn't an HTTP redirect.
+///
+pub fn resolve(
+    client: &Client,
+    url: &str,
+    options: &ResolveOptions,
+) -> Result<Resolution, ResolveError> {
+    let mut current = match Url::parse(url) {
+        Ok(u) => u,
+        Err(e) => {
+            return Err(ResolveError::InvalidUrl {
+                url: url.to_owned(),
+                reason: e.to_string(),
+            })
         }
-        None => None,
     };
-
-    // Tidy up the query and anchor links in modules on docs.python.org
-    if parsed_url.netloc == "docs.python.org" {
-        // If this is a module page, scrap any module- fragment.
-        parsed_url.fragment = match parsed_url.fragment {
-            Some(fragment) => {
-                if fragment.starts_with("module-") {
-                    None
-                } else {
-                    Some(fragment)
+    let mut chain = vec![];
+
+    loop {
+        let (method, mut response) = fetch(client, &current, options.reads_html())?;
+        let status = response.status();
+        chain.push(Hop {
+            url: current.as_str().to_owned(),
+            method: method.to_owned(),
+            status: status.as_u16(),
+            html_redirect: None,
+        });
+
+        // A redirect status without a Location header (e.g. 304 Not Modified)
+        // doesn't go anywhere, so this is as far as we can get.
+        //
+        // Some servers send non-ASCII UTF-8 in the Location header, which
+        // `HeaderValue::to_str` rejects, so we decode the raw bytes instead.
+        let location = match response.headers().get(LOCATION) {
+            Some(value) if status.is_redirection() => match str::from_utf8(value.as_bytes()) {
+                Ok(location) => Some(location.to_owned()),
+                Err(_) => {
+                    return Err(ResolveError::UnreadableLocation {
+                        url: current.into_string(),
+                    })
                 }
-            }
-            None => None,
+            },
+            _ => None,
         };
 
-        // Scrap the highlight
-        remove_query_param(&mut parsed_url, "highlight");
-    }
+        let location = match location {
+            Some(location) => location,
+            None => match find_html_redirect(&mut response, &current, options) {
+                Some((kind, location)) => {
+                    if let Some(hop) = chain.last_mut() {
+                        hop.html_redirect = Some(kind);
+                    }
+                    location
+                }
+                None => {
+                    let url = match options.tidy {
+                        Some(ref rules) => rules.tidy(current.as_str()),
+                        None => current.into_string(),
+                    };
+                    return Ok(Resolution { url, chain });
+                }
+            },
+        };
 
-    // If I'm on a GitHub pull request and looking at the files tab,
-    // link to the top of the pull request.
-    if parsed_url.netloc == "github.com" {
-        if parsed_url.path.ends_with("/files") {
-            parsed_url.path = parsed_url.path.replace("/files", "");
+        if chain.len() > options.max_redirects {
+            return Err(ResolveError::TooManyRedirects { chain });
         }
-    }
 
-    // Remove tracking query parameters from telegraph.co.uk URLs
-    // https://github.com/alexwlchan/safari.rs/issues/48
-    if parsed_url.netloc == "www.telegraph.co.uk" {
-        remove_query_param(&mut parsed_url, "WT.mc_id");
-    }
-
-    // Remove Google Analytics parameters from Etsy URLs.
-    if parsed_url.netloc == "www.etsy.com" {
-        remove_query_param(&mut parsed_url, "awc");
-        remove_query_param(&mut parsed_url, "click_key");
-        remove_query_param(&mut parsed_url, "click_sum");
-        remove_query_param(&mut parsed_url, "crt");
-        remove_query_param(&mut parsed_url, "frs");
-        remove_query_param(&mut parsed_url, "pro");
-        remove_query_param(&mut parsed_url, "source");
-
-        parsed_url.query = match parsed_url.query {
-            Some(qs) => {
-                let mut query = parse_qs(&qs);
-                let utm_keys: Vec<_> = query
-                    .keys()
-                    .filter(|key| {
-                        key.starts_with("ga_")
-                            || key.starts_with("ref")
-                            || key.starts_with("organic_search_click")
-                    })
-                    .map(|k| k.clone())
-                    .collect();
-                for key in utm_keys {
-                    query.remove(&key);
-                }
-                encode_querystring(query)
+        // The Location header may be relative to the current URL.
+        current = match current.join(&location) {
+            Ok(u) => u,
+            Err(_) => {
+                return Err(ResolveError::BadRedirect {
+                    url: current.into_string(),
+                    location,
+                })
             }
-            None => None,
         };
     }
-
-    // Un-mobile-ify blogspot links.
-    if parsed_url.netloc.ends_with("blogspot.com") {
-        remove_query_param(&mut parsed_url, "m");
-    }
-
-    // Un-mobile-ify blogspot links.
-    if parsed_url.netloc == "www.redbubble.com" {
-        remove_query_param(&mut parsed_url, "ref");
-        remove_query_param(&mut parsed_url, "asc");
-    }
-
-    // Always remove the _ga Google Analytics tracking parameter.
-    remove_query_param(&mut parsed_url, "_ga");
-
-    // Always remove the Google Click ID tracking parameters.
-    remove_query_param(&mut parsed_url, "gclid");
-
-    // Remove tracking parameters from stacks.wellcomecollection.org URLs,
-    // which are from Medium.
-    if parsed_url.netloc == "stacks.wellcomecollection.org" {
-        remove_query_param(&mut parsed_url, "source");
-    }
-
-    // Remove tracking parameters from Wordery URLs.
-    if parsed_url.netloc == "wordery.com" {
-        remove_query_param(&mut parsed_url, "cTrk");
-    }
-
-    // Remove sharing/referral parameter from Twitter URLs.
-    if parsed_url.netloc == "twitter.com" {
-        remove_query_param(&mut parsed_url, "s");
-        remove_query_param(&mut parsed_url, "ref_url");
-        remove_query_param(&mut parsed_url, "ref_src");
-        remove_query_param(&mut parsed_url, "cxt");
-    }
-
-    // Convert links to questions on Stack Overflow to insert my sharing
-    // link for the Announcer badge.
-    // This code is autogenerated by the `se_referral_autogen.py` script.
-    fix_se_referral(&mut parsed_url, "academia.stackexchange.com", "7658");
-    fix_se_referral(&mut parsed_url, "anime.stackexchange.com", "7674");
-    fix_se_referral(&mut parsed_url, "apple.stackexchange.com", "14295");
-    fix_se_referral(&mut parsed_url, "area51.stackexchange.com", "47881");
-    fix_se_referral(&mut parsed_url, "askubuntu.com", "265738");
-    fix_se_referral(&mut parsed_url, "aviation.stackexchange.com", "1372");
-    fix_se_referral(&mut parsed_url, "bicycles.stackexchange.com", "17362");
-    fix_se_referral(&mut parsed_url, "biology.stackexchange.com", "16115");
-    fix_se_referral(&mut parsed_url, "bricks.stackexchange.com", "445");
-    fix_se_referral(&mut parsed_url, "chemistry.stackexchange.com", "5443");
-    fix_se_referral(&mut parsed_url, "chess.stackexchange.com", "2564");
-    fix_se_referral(&mut parsed_url, "christianity.stackexchange.com", "10196");
-    fix_se_referral(&mut parsed_url, "codegolf.stackexchange.com", "13285");
-    fix_se_referral(&mut parsed_url, "codereview.stackexchange.com", "36525");
-    fix_se_referral(&mut parsed_url, "cogsci.stackexchange.com", "7973");
-    fix_se_referral(
-        &mut parsed_url,
-        "communitybuilding.stackexchange.com",
-        "372",
-    );
-    fix_se_referral(&mut parsed_url, "cooking.stackexchange.com", "25134");
-    fix_se_referral(&mut parsed_url, "crypto.stackexchange.com", "1185");
-    fix_se_referral(&mut parsed_url, "diy.stackexchange.com", "25263");
-    fix_se_referral(&mut parsed_url, "dsp.stackexchange.com", "8360");
-    fix_se_referral(&mut parsed_url, "earthscience.stackexchange.com", "352");
-    fix_se_referral(&mut parsed_url, "elementaryos.stackexchange.com", "3586");
-    fix_se_referral(&mut parsed_url, "english.stackexchange.com", "22597");
-    fix_se_referral(&mut parsed_url, "gaming.stackexchange.com", "73524");
-    fix_se_referral(&mut parsed_url, "gardening.stackexchange.com", "813");
-    fix_se_referral(&mut parsed_url, "gis.stackexchange.com", "26054");
-    fix_se_referral(&mut parsed_url, "graphicdesign.stackexchange.com", "19347");
-    fix_se_referral(&mut parsed_url, "law.stackexchange.com", "2488");
-    fix_se_referral(&mut parsed_url, "lifehacks.stackexchange.com", "11245");
-    fix_se_referral(&mut parsed_url, "linguistics.stackexchange.com", "2722");
-    fix_se_referral(&mut parsed_url, "math.stackexchange.com", "24160");
-    fix_se_referral(&mut parsed_url, "matheducators.stackexchange.com", "661");
-    fix_se_referral(&mut parsed_url, "mathematica.stackexchange.com", "5190");
-    fix_se_referral(&mut parsed_url, "mathoverflow.net", "38734");
-    fix_se_referral(&mut parsed_url, "mechanics.stackexchange.com", "14629");
-    fix_se_referral(&mut parsed_url, "meta.stackexchange.com", "226928");
-    fix_se_referral(&mut parsed_url, "money.stackexchange.com", "13518");
-    fix_se_referral(&mut parsed_url, "movies.stackexchange.com", "9285");
-    fix_se_referral(
-        &mut parsed_url,
-        "networkengineering.stackexchange.com",
-        "16668",
-    );
-    fix_se_referral(&mut parsed_url, "opensource.stackexchange.com", "2909");
-    fix_se_referral(&mut parsed_url, "parenting.stackexchange.com", "14304");
-    fix_se_referral(&mut parsed_url, "patents.stackexchange.com", "3882");
-    fix_se_referral(&mut parsed_url, "philosophy.stackexchange.com", "16838");
-    fix_se_referral(&mut parsed_url, "photo.stackexchange.com", "29700");
-    fix_se_referral(&mut parsed_url, "physics.stackexchange.com", "38614");
-    fix_se_referral(&mut parsed_url, "politics.stackexchange.com", "5590");
-    fix_se_referral(&mut parsed_url, "productivity.stackexchange.com", "1990");
-    fix_se_referral(&mut parsed_url, "puzzling.stackexchange.com", "4692");
-    fix_se_referral(&mut parsed_url, "rpg.stackexchange.com", "22823");
-    fix_se_referral(&mut parsed_url, "salesforce.stackexchange.com", "36215");
-    fix_se_referral(&mut parsed_url, "scifi.stackexchange.com", "3567");
-    fix_se_referral(&mut parsed_url, "security.stackexchange.com", "9814");
-    fix_se_referral(&mut parsed_url, "serverfault.com", "206273");
-    fix_se_referral(&mut parsed_url, "skeptics.stackexchange.com", "5416");
-    fix_se_referral(
-        &mut parsed_url,
-        "softwareengineering.stackexchange.com",
-        "94977",
-    );
-    fix_se_referral(&mut parsed_url, "space.stackexchange.com", "1003");
-    fix_se_referral(&mut parsed_url, "sqa.stackexchange.com", "7301");
-    fix_se_referral(&mut parsed_url, "stackapps.com", "21515");
-    fix_se_referral(&mut parsed_url, "stackoverflow.com", "1558022");
-    fix_se_referral(&mut parsed_url, "stats.stackexchange.com", "32450");
-    fix_se_referral(&mut parsed_url, "superuser.com", "243137");
-    fix_se_referral(&mut parsed_url, "tex.stackexchange.com", "9668");
-    fix_se_referral(&mut parsed_url, "travel.stackexchange.com", "12415");
-    fix_se_referral(&mut parsed_url, "unix.stackexchange.com", "43183");
-    fix_se_referral(&mut parsed_url, "ux.stackexchange.com", "9976");
-    fix_se_referral(&mut parsed_url, "webapps.stackexchange.com", "45296");
-    fix_se_referral(&mut parsed_url, "webmasters.stackexchange.com", "35749");
-    fix_se_referral(&mut parsed_url, "workplace.stackexchange.com", "14106");
-    fix_se_referral(&mut parsed_url, "worldbuilding.stackexchange.com", "2575");
-    fix_se_referral(&mut parsed_url, "writers.stackexchange.com", "11018");
-
-    urlunparse(parsed_url)
 }
 
-/// Remove a query parameter from a URL.
+/// Resolve several URLs, using up to `jobs` requests at once.
 ///
-/// - `parsed_url` - the `Url` structure returned by urlparse
-/// - `query_param` - name of the query parameter to remove.
+/// All the requests share one client (and its connection pool).  The
+/// results are in the same order as `urls`, and a URL that fails doesn't
+/// stop us resolving the others.
 ///
-fn remove_query_param(parsed_url: &mut Url, query_param: &str) {
-    parsed_url.query = match parsed_url.query {
-        Some(ref qs) => {
-            let mut query = parse_qs(&qs);
-            query.remove(query_param);
-            encode_querystring(query)
+pub fn resolve_all(
+    client: &Client,
+    urls: &[String],
+    options: &ResolveOptions,
+    jobs: usize,
+) -> Vec<Result<Resolution, ResolveError>> {
+    let next = AtomicUsize::new(0);
+    let (tx, rx) = mpsc::channel();
+
+    thread::scope(|scope| {
+        for _ in 0..jobs.clamp(1, urls.len().max(1)) {
+            let tx = tx.clone();
+            let next = &next;
+            scope.spawn(move || loop {
+                let i = next.fetch_add(1, Ordering::SeqCst);
+                if i >= urls.len() {
+                    break;
+                }
+                let _ = tx.send((i, resolve(client, &urls[i], options)));
+            });
         }
-        None => None,
-    };
+    });
+    drop(tx);
+
+    let mut results: Vec<_> = rx.into_iter().collect();
+    results.sort_by_key(|&(i, _)| i);
+    results.into_iter().map(|(_, result)| result).collect()
 }
 
-/// Turn a URL into a Stack Exchange referral link.
-///
-/// - `parsed_url` - the `Url` structure returned by urlparse
-/// - `hostname` - hostname of the SE site in question
-/// - `user_id` - your user ID on the SE site
-///
-fn fix_se_referral(parsed_url: &mut Url, hostname: &str, user_id: &str) {
-    if parsed_url.netloc != hostname {
-        return;
-    }
-
-    // A question URL is of the form
-    //
-    //    http://stackoverflow.com/questions/:question_id/:question_title
-    //
-    if !parsed_url.path.starts_with("/questions") {
-        return;
-    }
-
-    // Take a copy of the original path to get around ownership rules
-    let original_path = parsed_url.path.to_owned();
-
-    let new_path = match original_path.split("/").nth(2) {
-        Some(path_component) => {
-            // Check it's a number
-            match path_component.parse::<i32>() {
-                Ok(q_id) => {
-                    // Check if there's an answer fragment
-                    match parsed_url.fragment.to_owned() {
-                        Some(fragment) => match fragment.parse::<i32>() {
-                            Ok(ans_id) => Some(format!("/a/{}/{}", ans_id, user_id)),
-                            Err(_) => None,
-                        },
-                        None => Some(format!("/q/{}/{}", q_id, user_id)),
-                    }
-                }
-                Err(_) => None,
-            }
+/// Make a single request for a URL, without following redirects.
+fn fetch(
+    client: &Client,
+    url: &Url,
+    want_body: bool,
+) -> Result<(&'static str, Response), ResolveError> {
+    // reqwest prefixes its error messages with the URL, which we already
+    // include when we print the error.
+    let network_error = |e: ::reqwest::Error| {
+        let prefix = format!("{}: ", url);
+        let reason = e.to_string();
+        ResolveError::Network {
+            url: url.as_str().to_owned(),
+            reason: reason.trim_start_matches(prefix.as_str()).to_owned(),
         }
-        None => None,
     };
 
-    // If we got something interesting, update the URL.
-    match new_path {
-        Some(p) => {
-            parsed_url.path = p;
-            parsed_url.fragment = None;
+    if want_body {
+        let response = client.get(url.clone()).send().map_err(network_error)?;
+        return Ok(("GET", response));
+    }
+
+    let response = client.head(url.clone()).send().map_err(network_error)?;
+    match response.status() {
+        StatusCode::FORBIDDEN | StatusCode::METHOD_NOT_ALLOWED | StatusCode::NOT_IMPLEMENTED => {
+            let response = client.get(url.clone()).send().map_err(network_error)?;
+            Ok(("GET", response))
         }
-        None => (),
+        _ => Ok(("HEAD", response)),
+    }
+}
+
+/// Look for a meta-refresh or canonical link in an HTML response, if the
+/// options ask for them.  Returns None if there isn't one, if it points back
+/// to the current page, or if the response isn't HTML.
+fn find_html_redirect(
+    response: &mut Response,
+    current: &Url,
+    options: &ResolveOptions,
+) -> Option<(HtmlRedirect, String)> {
+    if !options.reads_html() || !response.status().is_success() {
+        return None;
+    }
+
+    let is_html = match response.headers().get(CONTENT_TYPE) {
+        Some(value) => value.to_str().unwrap_or("").contains("html"),
+        None => true,
     };
+    if !is_html {
+        return None;
+    }
+
+    let mut body = vec![];
+    if response
+        .take(options.max_body_bytes)
+        .read_to_end(&mut body)
+        .is_err()
+    {
+        return None;
+    }
+    let html = String::from_utf8_lossy(&body);
+
+    let mut candidates = vec![];
+    if options.follow_meta_refresh {
+        if let Some(target) = find_meta_refresh(&html) {
+            candidates.push((HtmlRedirect::MetaRefresh, target));
+        }
+    }
+    if options.prefer_canonical {
+        if let Some(target) = find_canonical(&html) {
+            candidates.push((HtmlRedirect::Canonical, target));
+        }
+    }
+
+    // A canonical link usually points to the page itself, and we don't want
+    // to follow that forever.
+    candidates
+        .into_iter()
+        .find(|(_, target)| match current.join(target) {
+            Ok(u) => &u != current,
+            Err(_) => true,
+        })
+}
+
+/// Find every HTML tag with the given name, and return its attributes.
+/// Attribute names are lowercased, and values have entities decoded.
+fn html_tags(html: &str, name: &str) -> Vec<Vec<(String, String)>> {
+    // We look at every page we resolve, so only compile these once.
+    static TAG_RE: OnceLock<Regex> = OnceLock::new();
+    static ATTR_RE: OnceLock<Regex> = OnceLock::new();
+    let tag_re = TAG_RE.get_or_init(|| Regex::new(r"(?is)<([a-z][a-z0-9]*)\b[^>]*>").unwrap());
+    let attr_re = ATTR_RE.get_or_init(|| {
+        Regex::new(r#"(?is)([a-z][a-z0-9:_-]*)\s*=\s*(?:"([^"]*)"|'([^']*)'|([^\s"'>]+))"#).unwrap()
+    });
+
+    tag_re
+        .captures_iter(html)
+        .filter(|tag| tag[1].eq_ignore_ascii_case(name))
+        .map(|tag| {
+            // Skip the tag name, so it isn't mistaken for an attribute.
+            let tag = &tag[0][1 + tag[1].len()..];
+            attr_re
+                .captures_iter(tag)
+                .map(|c| {
+                    let value = c
+                        .get(2)
+                        .or_else(|| c.get(3))
+                        .or_else(|| c.get(4))
+                        .map_or("", |m| m.as_str());
+                    (c[1].to_lowercase(), decode_entities(value))
+                })
+                .collect()
+        })
+        .collect()
+}
+
+fn attribute<'a>(attributes: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    attributes
+        .iter()
+        .find(|(n, _)| n == name)
+        .map(|(_, v)| v.as_str())
+}
+
+/// Decode the handful of HTML entities that turn up in URLs.
+fn decode_entities(value: &str) -> String {
+    value
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&#x2F;", "/")
+        .replace("&#47;", "/")
+        .replace("&amp;", "&")
+        .replace("&#38;", "&")
+}
+
+/// Find the target of a `<meta http-equiv="refresh" content="0; url=…">` tag.
+fn find_meta_refresh(html: &str) -> Option<String> {
+    html_tags(html, "meta").into_iter().find_map(|attributes| {
+        let http_equiv = attribute(&attributes, "http-equiv")?;
+        if !http_equiv.eq_ignore_ascii_case("refresh") {
+            return None;
+        }
+
+        // The content is a delay, then optionally a URL, e.g. "0; url=/next".
+        // A refresh without a URL just reloads the page.
+        let content = attribute(&attributes, "content")?;
+        let target = content.split_once([';', ','])?.1.trim();
+        // The page is untrusted, so don't slice it on a byte index that
+        // might be in the middle of a character.
+        let target = if target
+            .get(..3)
+            .is_some_and(|p| p.eq_ignore_ascii_case("url"))
+        {
+            target.get(3..)?.trim_start().strip_prefix('=')?.trim()
+        } else {
+            target
+        };
+        let target = target.trim_matches(['"', '\'']);
+
+        if target.is_empty() {
+            None
+        } else {
+            Some(target.to_owned())
+        }
+    })
+}
+
+/// Find the target of a `<link rel="canonical" href="…">` tag.
+fn find_canonical(html: &str) -> Option<String> {
+    html_tags(html, "link").into_iter().find_map(|attributes| {
+        let rel = attribute(&attributes, "rel")?;
+        if !rel
+            .split_whitespace()
+            .any(|r| r.eq_ignore_ascii_case("canonical"))
+        {
+            return None;
+        }
+        match attribute(&attributes, "href") {
+            Some(href) if !href.trim().is_empty() => Some(href.trim().to_owned()),
+            _ => None,
+        }
+    })
+}
+
+/// Strip tracking junk and URL suffixes.
+///
+/// This applies the built-in rules; see `rules/default.json`.
+pub fn tidy_url(url: &str) -> String {
+    RuleSet::builtin_ref().tidy(url)
 }
 
 macro_rules! tidy_url_tests {
@@ -538,12 +638,12 @@ tidy_url_tests! {
 
   stack_overflow_question: (
     "http://stackoverflow.com/questions/1732348/regex-match-open-tags-except-xhtml-self-contained-tags",
-    "http://stackoverflow.com/q/1732348/1558022"
+    "http://stackoverflow.com/questions/1732348/regex-match-open-tags-except-xhtml-self-contained-tags"
   ),
 
   stack_overflow_answer: (
     "http://stackoverflow.com/questions/82831/how-do-i-check-whether-a-file-exists-using-python#82852",
-    "http://stackoverflow.com/a/82852/1558022"
+    "http://stackoverflow.com/questions/82831/how-do-i-check-whether-a-file-exists-using-python#82852"
   ),
 
   sff_se_non_question: (
@@ -553,12 +653,12 @@ tidy_url_tests! {
 
   sff_se_question: (
     "https://scifi.stackexchange.com/questions/58980/how-did-lupin-forget-there-was-a-full-moon",
-    "https://scifi.stackexchange.com/q/58980/3567"
+    "https://scifi.stackexchange.com/questions/58980/how-did-lupin-forget-there-was-a-full-moon"
   ),
 
   sff_se_answer: (
     "https://scifi.stackexchange.com/questions/39201/which-owls-did-fred-and-george-weasley-achieve/39218#39218",
-    "https://scifi.stackexchange.com/a/39218/3567"
+    "https://scifi.stackexchange.com/questions/39201/which-owls-did-fred-and-george-weasley-achieve/39218#39218"
   ),
 
   telegraph_bare: (
@@ -696,8 +796,611 @@ tidy_url_tests! {
     "https://imgix.com/pricing"
   ),
 
+  google_redirect: (
+    "https://www.google.com/url?sa=t&rct=j&q=&esrc=s&source=web&cd=1&url=https%3A%2F%2Fexample.org%2Fpage%3Futm_source%3Dgoogle&usg=AOvVaw0",
+    "https://example.org/page"
+  ),
+
+  google_redirect_with_q: (
+    "https://www.google.co.uk/url?q=https://example.org/page&sa=D",
+    "https://example.org/page"
+  ),
+
+  google_search_is_not_unwrapped: (
+    "https://www.google.com/search?q=https://example.org",
+    "https://www.google.com/search?q=https%3A%2F%2Fexample.org"
+  ),
+
+  facebook_redirect: (
+    "https://l.facebook.com/l.php?u=https%3A%2F%2Fexample.org%2F%3Ffbclid%3Dabc&h=AT0",
+    "https://example.org/?fbclid=abc"
+  ),
+
+  reddit_redirect: (
+    "https://out.reddit.com/t3_abc123?url=https%3A%2F%2Fexample.org%2Fstory&token=AQAA&app_name=reddit.com",
+    "https://example.org/story"
+  ),
+
+  slack_redirect: (
+    "https://slack-redir.net/link?url=https%3A%2F%2Fexample.org%2Fdocs",
+    "https://example.org/docs"
+  ),
+
+  outlook_safelinks: (
+    "https://eur02.safelinks.protection.outlook.com/?url=https%3A%2F%2Fexample.org%2Fnewsletter%3Futm_medium%3Demail&data=05%7C01&sdata=abc&reserved=0",
+    "https://example.org/newsletter"
+  ),
+
+  duckduckgo_redirect: (
+    "https://duckduckgo.com/l/?uddg=https%3A%2F%2Fexample.org%2Fresult&rut=abc",
+    "https://example.org/result"
+  ),
+
+  nested_redirects: (
+    "https://www.google.com/url?q=https%3A%2F%2Fout.reddit.com%2Ft3_abc%3Furl%3Dhttps%253A%252F%252Fmobile.twitter.com%252Fb0rk",
+    "https://twitter.com/b0rk"
+  ),
+
+  redirect_without_url_is_unchanged: (
+    "https://duckduckgo.com/l/?uddg=not-a-url",
+    "https://duckduckgo.com/l/?uddg=not-a-url"
+  ),
+
   tiktok_with_tracking_junk: (
     "https://www.tiktok.com/@example/video/1234567890?sec_user_id=ABCDEFGHIJ&u_code=dgfffl6mjl6be3&share_app_id=1233&timestamp=1631110682",
     "https://www.tiktok.com/@example/video/1234567890"
   ),
 }
+
+#[cfg(test)]
+pub mod resolve_tests {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+
+    pub type Route = (&'static str, u16, Option<&'static str>);
+    type Page = (&'static str, &'static str);
+
+    /// A tiny HTTP server for testing.  Each path maps to a status code and
+    /// an optional Location header; unknown paths get a 200.  If
+    /// `reject_head` is true, every HEAD request gets a 405.
+    pub fn serve(routes: Vec<Route>, reject_head: bool) -> String {
+        serve_pages(routes, vec![], reject_head)
+    }
+
+    /// Like `serve`, but paths in `pages` get a 200 with the given HTML.
+    fn serve_pages(routes: Vec<Route>, pages: Vec<Page>, reject_head: bool) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(s) => s,
+                    Err(_) => break,
+                };
+                handle(stream, &routes, &pages, reject_head);
+            }
+        });
+
+        format!("http://{}", addr)
+    }
+
+    fn handle(mut stream: TcpStream, routes: &[Route], pages: &[Page], reject_head: bool) {
+        let mut request_line = String::new();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        reader.read_line(&mut request_line).unwrap();
+
+        // Read (and ignore) the rest of the request headers
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).unwrap() == 0 || line == "\r\n" {
+                break;
+            }
+        }
+
+        let mut parts = request_line.split_whitespace();
+        let method = parts.next().unwrap_or("");
+        let path = parts.next().unwrap_or("");
+
+        let (status, location) = if reject_head && method == "HEAD" {
+            (405, None)
+        } else {
+            routes
+                .iter()
+                .find(|r| r.0 == path)
+                .map(|r| (r.1, r.2))
+                .unwrap_or((200, None))
+        };
+
+        let body = match pages.iter().find(|p| p.0 == path) {
+            Some(page) if status == 200 => page.1,
+            _ => "",
+        };
+
+        let mut response = format!("HTTP/1.1 {} Test\r\nConnection: close\r\n", status);
+        if let Some(location) = location {
+            response.push_str(&format!("Location: {}\r\n", location));
+        }
+        if !body.is_empty() {
+            response.push_str("Content-Type: text/html; charset=utf-8\r\n");
+        }
+        response.push_str(&format!("Content-Length: {}\r\n\r\n", body.len()));
+        if method != "HEAD" {
+            response.push_str(body);
+        }
+        let _ = stream.write_all(response.as_bytes());
+    }
+
+    fn resolve_with(url: &str, options: &ResolveOptions) -> Result<Resolution, ResolveError> {
+        let client = build_client(options).unwrap();
+        resolve(&client, url, options)
+    }
+
+    fn hop(url: String, method: &str, status: u16) -> Hop {
+        Hop {
+            url,
+            method: method.to_owned(),
+            status,
+            html_redirect: None,
+        }
+    }
+
+    fn html_options() -> ResolveOptions {
+        ResolveOptions {
+            follow_meta_refresh: true,
+            prefer_canonical: true,
+            ..ResolveOptions::default()
+        }
+    }
+
+    #[test]
+    fn test_no_redirect() {
+        let base = serve(vec![], false);
+        let resolution =
+            resolve_with(&format!("{}/page", base), &ResolveOptions::default()).unwrap();
+        assert_eq!(resolution.url, format!("{}/page", base));
+        assert_eq!(
+            resolution.chain,
+            vec![hop(format!("{}/page", base), "HEAD", 200)]
+        );
+    }
+
+    #[test]
+    fn test_redirect_chain_is_recorded() {
+        let base = serve(
+            vec![
+                ("/a", 301, Some("/b")),
+                ("/b", 302, Some("/c")),
+                ("/c", 307, Some("/final")),
+            ],
+            false,
+        );
+        let resolution = resolve_with(&format!("{}/a", base), &ResolveOptions::default()).unwrap();
+        assert_eq!(resolution.url, format!("{}/final", base));
+        assert_eq!(
+            resolution.chain,
+            vec![
+                hop(format!("{}/a", base), "HEAD", 301),
+                hop(format!("{}/b", base), "HEAD", 302),
+                hop(format!("{}/c", base), "HEAD", 307),
+                hop(format!("{}/final", base), "HEAD", 200),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_absolute_location() {
+        let base = serve(
+            vec![("/a", 301, Some("http://127.0.0.1:1/elsewhere"))],
+            false,
+        );
+        let options = ResolveOptions {
+            max_redirects: 0,
+            ..ResolveOptions::default()
+        };
+
+        // We don't want to actually fetch the other URL, so stop after the
+        // first redirect and check where it was going.
+        match resolve_with(&format!("{}/a", base), &options) {
+            Err(ResolveError::TooManyRedirects { chain }) => {
+                assert_eq!(chain, vec![hop(format!("{}/a", base), "HEAD", 301)])
+            }
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_too_many_redirects() {
+        let base = serve(vec![("/loop", 302, Some("/loop"))], false);
+        let options = ResolveOptions {
+            max_redirects: 3,
+            ..ResolveOptions::default()
+        };
+        match resolve_with(&format!("{}/loop", base), &options) {
+            Err(ResolveError::TooManyRedirects { chain }) => assert_eq!(chain.len(), 4),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_max_redirects_is_inclusive() {
+        let base = serve(
+            vec![("/a", 301, Some("/b")), ("/b", 301, Some("/c"))],
+            false,
+        );
+        let options = ResolveOptions {
+            max_redirects: 2,
+            ..ResolveOptions::default()
+        };
+        let resolution = resolve_with(&format!("{}/a", base), &options).unwrap();
+        assert_eq!(resolution.url, format!("{}/c", base));
+    }
+
+    #[test]
+    fn test_get_fallback_when_head_is_rejected() {
+        let base = serve(vec![("/a", 301, Some("/b"))], true);
+        let resolution = resolve_with(&format!("{}/a", base), &ResolveOptions::default()).unwrap();
+        assert_eq!(resolution.url, format!("{}/b", base));
+        assert_eq!(
+            resolution.chain,
+            vec![
+                hop(format!("{}/a", base), "GET", 301),
+                hop(format!("{}/b", base), "GET", 200),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_redirect_without_location_is_final() {
+        let base = serve(vec![("/a", 304, None)], false);
+        let resolution = resolve_with(&format!("{}/a", base), &ResolveOptions::default()).unwrap();
+        assert_eq!(resolution.url, format!("{}/a", base));
+    }
+
+    #[test]
+    fn test_bad_location_is_an_error() {
+        let base = serve(vec![("/a", 301, Some("http://[::1"))], false);
+        match resolve_with(&format!("{}/a", base), &ResolveOptions::default()) {
+            Err(ResolveError::BadRedirect { url, location }) => {
+                assert_eq!(url, format!("{}/a", base));
+                assert_eq!(location, "http://[::1");
+            }
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_utf8_location_is_followed() {
+        let base = serve(vec![("/a", 301, Some("/caf\u{e9}"))], false);
+        let resolution = resolve_with(&format!("{}/a", base), &ResolveOptions::default()).unwrap();
+        assert_eq!(resolution.url, format!("{}/caf%C3%A9", base));
+    }
+
+    #[test]
+    fn test_non_utf8_location_is_an_error() {
+        // "café" in Latin-1, which isn't valid UTF-8
+        const RESPONSE: &[u8] = b"HTTP/1.1 301 Test\r\n\
+            Location: /caf\xe9\r\n\
+            Content-Length: 0\r\n\
+            Connection: close\r\n\r\n";
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/a", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = match stream {
+                    Ok(s) => s,
+                    Err(_) => break,
+                };
+                let mut request_line = String::new();
+                let _ = BufReader::new(stream.try_clone().unwrap()).read_line(&mut request_line);
+                let _ = stream.write_all(RESPONSE);
+            }
+        });
+
+        match resolve_with(&url, &ResolveOptions::default()) {
+            Err(ResolveError::UnreadableLocation { url: u }) => assert_eq!(u, url),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_invalid_url_is_an_error() {
+        match resolve_with("not a url", &ResolveOptions::default()) {
+            Err(ResolveError::InvalidUrl { url, .. }) => assert_eq!(url, "not a url"),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_network_error_is_an_error() {
+        // Bind a port and immediately drop the listener, so nothing is
+        // listening on it.
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let url = format!("http://127.0.0.1:{}/", port);
+        let options = ResolveOptions {
+            timeout: Some(Duration::from_secs(5)),
+            ..ResolveOptions::default()
+        };
+        match resolve_with(&url, &options) {
+            Err(ResolveError::Network { url: u, reason }) => {
+                assert_eq!(u, url);
+                assert!(!reason.starts_with(&url));
+            }
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_meta_refresh_is_ignored_by_default() {
+        let base = serve_pages(
+            vec![],
+            vec![("/a", r#"<meta http-equiv="refresh" content="0; url=/b">"#)],
+            false,
+        );
+        let resolution = resolve_with(&format!("{}/a", base), &ResolveOptions::default()).unwrap();
+        assert_eq!(resolution.url, format!("{}/a", base));
+    }
+
+    #[test]
+    fn test_follows_meta_refresh() {
+        let base = serve_pages(
+            vec![("/b", 301, Some("/c"))],
+            vec![(
+                "/a",
+                r#"<html><head><META HTTP-EQUIV="Refresh" CONTENT="0;URL='/b'"></head></html>"#,
+            )],
+            false,
+        );
+        let resolution = resolve_with(&format!("{}/a", base), &html_options()).unwrap();
+        assert_eq!(resolution.url, format!("{}/c", base));
+        assert_eq!(
+            resolution.chain,
+            vec![
+                Hop {
+                    html_redirect: Some(HtmlRedirect::MetaRefresh),
+                    ..hop(format!("{}/a", base), "GET", 200)
+                },
+                hop(format!("{}/b", base), "GET", 301),
+                hop(format!("{}/c", base), "GET", 200),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_follows_canonical_link() {
+        let base = serve_pages(
+            vec![],
+            vec![
+                (
+                    "/amp/story",
+                    r#"<link href="/story" rel="amphtml canonical">"#,
+                ),
+                ("/story", r#"<link rel="canonical" href="/story">"#),
+            ],
+            false,
+        );
+        let resolution = resolve_with(&format!("{}/amp/story", base), &html_options()).unwrap();
+        assert_eq!(resolution.url, format!("{}/story", base));
+        assert_eq!(resolution.chain.len(), 2);
+        assert_eq!(
+            resolution.chain[0].html_redirect,
+            Some(HtmlRedirect::Canonical)
+        );
+    }
+
+    #[test]
+    fn test_canonical_is_only_followed_if_asked() {
+        let base = serve_pages(
+            vec![],
+            vec![("/a", r#"<link rel="canonical" href="/b">"#)],
+            false,
+        );
+        let options = ResolveOptions {
+            follow_meta_refresh: true,
+            ..ResolveOptions::default()
+        };
+        let resolution = resolve_with(&format!("{}/a", base), &options).unwrap();
+        assert_eq!(resolution.url, format!("{}/a", base));
+    }
+
+    #[test]
+    fn test_meta_refresh_loops_hit_the_redirect_limit() {
+        let base = serve_pages(
+            vec![],
+            vec![
+                ("/a", r#"<meta http-equiv="refresh" content="0; url=/b">"#),
+                ("/b", r#"<meta http-equiv="refresh" content="0; url=/a">"#),
+            ],
+            false,
+        );
+        match resolve_with(&format!("{}/a", base), &html_options()) {
+            Err(ResolveError::TooManyRedirects { chain }) => assert_eq!(chain.len(), 11),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_body_is_bounded() {
+        let base = serve_pages(
+            vec![],
+            vec![(
+                "/a",
+                concat!(
+                    "<!-- padding padding padding padding padding -->",
+                    r#"<meta http-equiv="refresh" content="0; url=/b">"#
+                ),
+            )],
+            false,
+        );
+        let options = ResolveOptions {
+            max_body_bytes: 20,
+            ..html_options()
+        };
+        let resolution = resolve_with(&format!("{}/a", base), &options).unwrap();
+        assert_eq!(resolution.url, format!("{}/a", base));
+    }
+
+    #[test]
+    fn test_final_url_is_tidied() {
+        let base = serve_pages(
+            vec![],
+            vec![(
+                "/newsletter",
+                r#"<meta http-equiv="refresh" content="0; url=/page?utm_source=newsletter">"#,
+            )],
+            false,
+        );
+        let options = ResolveOptions {
+            tidy: Some(RuleSet::builtin()),
+            ..html_options()
+        };
+        let resolution = resolve_with(&format!("{}/newsletter", base), &options).unwrap();
+        assert_eq!(resolution.url, format!("{}/page", base));
+        assert_eq!(
+            resolution.chain.last().unwrap().url,
+            format!("{}/page?utm_source=newsletter", base)
+        );
+    }
+}
+
+#[cfg(test)]
+mod resolve_all_tests {
+    use super::resolve_tests::serve;
+    use super::*;
+
+    #[test]
+    fn test_results_are_in_input_order() {
+        let base = serve(
+            vec![
+                ("/1", 301, Some("/one")),
+                ("/2", 301, Some("/two")),
+                ("/3", 301, Some("/three")),
+                ("/4", 301, Some("/four")),
+                ("/5", 301, Some("/five")),
+            ],
+            false,
+        );
+        let urls: Vec<String> = (1..6).map(|i| format!("{}/{}", base, i)).collect();
+        let options = ResolveOptions::default();
+        let client = build_client(&options).unwrap();
+
+        let results = resolve_all(&client, &urls, &options, 3);
+        let resolved: Vec<String> = results.into_iter().map(|r| r.unwrap().url).collect();
+        assert_eq!(
+            resolved,
+            vec!["one", "two", "three", "four", "five"]
+                .into_iter()
+                .map(|p| format!("{}/{}", base, p))
+                .collect::<Vec<String>>()
+        );
+    }
+
+    #[test]
+    fn test_errors_dont_stop_the_batch() {
+        let base = serve(vec![("/a", 301, Some("/b"))], false);
+        let urls = vec![
+            format!("{}/a", base),
+            "not a url".to_owned(),
+            format!("{}/c", base),
+        ];
+        let options = ResolveOptions::default();
+        let client = build_client(&options).unwrap();
+
+        let results = resolve_all(&client, &urls, &options, 2);
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].as_ref().unwrap().url, format!("{}/b", base));
+        match results[1] {
+            Err(ResolveError::InvalidUrl { ref url, .. }) => assert_eq!(url, "not a url"),
+            ref other => panic!("Unexpected result: {:?}", other),
+        }
+        assert_eq!(results[2].as_ref().unwrap().url, format!("{}/c", base));
+    }
+
+    #[test]
+    fn test_no_urls() {
+        let options = ResolveOptions::default();
+        let client = build_client(&options).unwrap();
+        assert!(resolve_all(&client, &[], &options, 4).is_empty());
+    }
+
+    #[test]
+    fn test_zero_jobs_still_runs() {
+        let base = serve(vec![], false);
+        let options = ResolveOptions::default();
+        let client = build_client(&options).unwrap();
+        let results = resolve_all(&client, &[format!("{}/a", base)], &options, 0);
+        assert_eq!(results.len(), 1);
+        assert!(results[0].is_ok());
+    }
+}
+
+#[cfg(test)]
+mod html_tests {
+    use super::*;
+
+    #[test]
+    fn test_find_meta_refresh() {
+        let cases = vec![
+            (
+                r#"<meta http-equiv="refresh" content="0; url=https://example.org/">"#,
+                Some("https://example.org/"),
+            ),
+            (
+                r#"<meta content="5;URL=/next" http-equiv="REFRESH" />"#,
+                Some("/next"),
+            ),
+            (
+                r#"<meta http-equiv=refresh content='0; url="/quoted"'>"#,
+                Some("/quoted"),
+            ),
+            (
+                r#"<meta http-equiv="refresh" content="0, /comma">"#,
+                Some("/comma"),
+            ),
+            (
+                r#"<meta http-equiv="refresh" content="0; url=/a?b=1&amp;c=2">"#,
+                Some("/a?b=1&c=2"),
+            ),
+            (r#"<meta http-equiv="refresh" content="0; éé">"#, Some("éé")),
+            (
+                r#"<meta http-equiv="refresh" content="0; url=/café">"#,
+                Some("/café"),
+            ),
+            (r#"<meta http-equiv="refresh" content="30">"#, None),
+            (r#"<meta name="description" content="0; url=/nope">"#, None),
+            ("<p>No redirects here</p>", None),
+        ];
+        for (html, expected) in cases {
+            assert_eq!(find_meta_refresh(html).as_deref(), expected, "{}", html);
+        }
+    }
+
+    #[test]
+    fn test_find_canonical() {
+        let cases = vec![
+            (
+                r#"<link rel="canonical" href="https://example.org/">"#,
+                Some("https://example.org/"),
+            ),
+            (r#"<LINK HREF="/story" REL="Canonical">"#, Some("/story")),
+            (
+                r#"<link rel="stylesheet" href="/style.css"><link rel="canonical" href="/page">"#,
+                Some("/page"),
+            ),
+            (r#"<link rel="canonical" href="">"#, None),
+            (r#"<link rel="alternate" href="/feed">"#, None),
+        ];
+        for (html, expected) in cases {
+            assert_eq!(find_canonical(html).as_deref(), expected, "{}", html);
+        }
+    }
+}
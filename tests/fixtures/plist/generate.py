@@ -0,0 +1,113 @@
+#!/usr/bin/env python3
+"""
+Generate the binary and malformed plist fixtures from the XML fixtures.
+
+Safari writes its plists in the binary format, but binary files are hard to
+review, so the XML fixtures are the source of truth.  This script writes a
+binary copy of each one, plus variants that are broken in the ways we've
+seen (or can imagine) Safari's files being broken.
+
+Run it from anywhere:
+
+    python3 tests/fixtures/plist/generate.py
+"""
+
+import copy
+import os
+import plistlib
+
+HERE = os.path.dirname(os.path.abspath(__file__))
+
+
+def load(name):
+    with open(os.path.join(HERE, name), "rb") as f:
+        return plistlib.load(f)
+
+
+def write(name, value):
+    fmt = plistlib.FMT_BINARY if name.endswith(".bplist") else plistlib.FMT_XML
+    with open(os.path.join(HERE, name), "wb") as f:
+        plistlib.dump(value, f, fmt=fmt, sort_keys=True)
+
+
+def write_bytes(name, data):
+    with open(os.path.join(HERE, name), "wb") as f:
+        f.write(data)
+
+
+def reading_list_folder(bookmarks):
+    return next(c for c in bookmarks["Children"] if c["Title"] == "com.apple.ReadingList")
+
+
+def first_device(icloud_tabs):
+    return icloud_tabs["values"]["0C5A8C53-2A3B-4C1C-9A4E-3B1B2F1E7D10"]["value"]
+
+
+bookmarks = load("bookmarks.plist")
+reading_list = load("reading-list.plist")
+icloud_tabs = load("icloud-tabs.plist")
+
+# Binary copies of the well-formed fixtures
+write("bookmarks.bplist", bookmarks)
+write("reading-list.bplist", reading_list)
+write("icloud-tabs.bplist", icloud_tabs)
+
+# An empty Reading List doesn't have a Children key
+value = copy.deepcopy(reading_list)
+del reading_list_folder(value)["Children"]
+write("reading-list-empty.bplist", value)
+
+# A Bookmarks.plist without any top-level folders
+value = copy.deepcopy(reading_list)
+del value["Children"]
+write("missing-children.plist", value)
+
+# Two Reading List folders
+value = copy.deepcopy(reading_list)
+value["Children"].append(copy.deepcopy(reading_list_folder(value)))
+write("reading-list-duplicate.bplist", value)
+
+# A Reading List item whose URL is a number
+value = copy.deepcopy(reading_list)
+reading_list_folder(value)["Children"][1]["URLString"] = 42
+write("reading-list-non-string-url.bplist", value)
+
+# A folder whose Children is a string, rather than an array
+value = copy.deepcopy(bookmarks)
+value["Children"][1]["Children"] = "GitHub"
+write("bookmarks-children-not-array.plist", value)
+
+# A bookmark whose URL is a dictionary
+value = copy.deepcopy(bookmarks)
+value["Children"][1]["Children"][0]["URLString"] = {"url": "https://github.com/"}
+write("bookmarks-non-string-url.bplist", value)
+
+# Devices which are signed in to iCloud, but don't have any tabs open
+value = copy.deepcopy(icloud_tabs)
+for device in value["values"].values():
+    device["value"].pop("Tabs", None)
+write("icloud-tabs-no-tabs.bplist", value)
+
+# An iCloud tab whose URL is a boolean
+value = copy.deepcopy(icloud_tabs)
+first_device(value)["Tabs"][1]["URL"] = True
+write("icloud-tabs-non-string-url.bplist", value)
+
+# A device whose Tabs is a dictionary, rather than an array
+value = copy.deepcopy(icloud_tabs)
+first_device(value)["Tabs"] = {"Title": "Example Domain"}
+write("icloud-tabs-tabs-not-array.plist", value)
+
+# A device without a name
+value = copy.deepcopy(icloud_tabs)
+del first_device(value)["DeviceName"]
+write("icloud-tabs-missing-device-name.plist", value)
+
+# A synced preferences file without any iCloud Tabs
+write("icloud-tabs-missing-values.plist", {"LastSync": 0})
+
+# Files that aren't the right shape, or aren't plists at all
+write("array-root.bplist", [reading_list])
+write_bytes("not-a-plist.plist", b"This is not a plist.\n")
+with open(os.path.join(HERE, "reading-list.bplist"), "rb") as f:
+    write_bytes("truncated.bplist", f.read()[:100])